@@ -0,0 +1,112 @@
+//! Compares a program compiled by `umix compile` with the interpreter and the
+//! JIT of `umix run`.
+//!
+//! The program runs many loops one after another, each for a few thousand
+//! iterations. The JIT interprets every loop until it gets hot and then
+//! compiles it, which costs more than the loop saves, whereas the compiled
+//! executable runs them all as native code from the start.
+//!
+//! Run with `cargo bench --bench aot`.
+
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+const LOOPS: u32 = 1000;
+const ITERATIONS: u32 = 5000;
+const RUNS: usize = 3;
+
+fn op(opcode: u32, a: u32, b: u32, c: u32) -> u32 {
+    opcode << 28 | a << 6 | b << 3 | c
+}
+
+fn imm(a: u32, value: u32) -> u32 {
+    13 << 28 | a << 25 | value
+}
+
+/// Returns a program that runs `LOOPS` loops of `ITERATIONS` iterations
+/// mixing r5, and prints the low byte of r5.
+fn program() -> Vec<u32> {
+    let mut code = vec![
+        op(6, 2, 0, 0), // r2 = -1
+        imm(6, 3),
+        imm(5, 1),
+    ];
+    for _ in 0..LOOPS {
+        code.push(imm(4, ITERATIONS));
+        let head = code.len() as u32;
+        let next = head + 9;
+        code.extend([
+            op(4, 5, 5, 6), // r5 *= 3
+            op(3, 5, 5, 4), // r5 += r4
+            op(6, 7, 5, 4),
+            op(3, 5, 5, 7), // r5 += !(r5 & r4)
+            op(3, 4, 4, 2), // r4 -= 1
+            imm(3, next),
+            imm(1, head),
+            op(0, 3, 1, 4), // loop while r4 != 0
+            op(12, 0, 0, 3),
+        ]);
+    }
+    code.extend([
+        imm(1, 255),
+        op(6, 5, 5, 1),
+        op(6, 5, 5, 5), // r5 &= 255
+        op(10, 0, 0, 5),
+        op(7, 0, 0, 0),
+    ]);
+    code
+}
+
+/// Runs a command `RUNS` times and returns its output and the fastest time.
+fn time(command: &mut Command) -> (Vec<u8>, Duration) {
+    let mut best = Duration::MAX;
+    let mut output = Vec::new();
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let result = command.stdin(Stdio::null()).output().unwrap();
+        best = best.min(start.elapsed());
+        assert!(result.status.success(), "{command:?} failed");
+        output = result.stdout;
+    }
+    (output, best)
+}
+
+fn main() {
+    let umix = Path::new(env!("CARGO_BIN_EXE_umix"));
+    let dir = std::env::temp_dir().join(format!("umix-bench-aot-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let codex = dir.join("loops.um");
+    let exe = dir.join("loops");
+    let data: Vec<u8> = program().iter().flat_map(|c| c.to_be_bytes()).collect();
+    std::fs::write(&codex, data).unwrap();
+
+    let status = Command::new(umix)
+        .arg("compile")
+        .arg("-o")
+        .arg(&exe)
+        .arg(&codex)
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success(), "umix compile failed");
+
+    let (expected, interpreter) = time(
+        Command::new(umix)
+            .args(["run", "--mode", "interpreter"])
+            .arg(&codex),
+    );
+    let (jit_output, jit) = time(Command::new(umix).arg("run").arg(&codex));
+    let (aot_output, aot) = time(&mut Command::new(&exe));
+    assert_eq!(jit_output, expected);
+    assert_eq!(aot_output, expected);
+
+    println!("{LOOPS} loops of {ITERATIONS} iterations, best of {RUNS} runs:");
+    println!("  interpreter  {:>8.1} ms", interpreter.as_secs_f64() * 1e3);
+    println!("  jit          {:>8.1} ms", jit.as_secs_f64() * 1e3);
+    println!("  compiled     {:>8.1} ms", aot.as_secs_f64() * 1e3);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
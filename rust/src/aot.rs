@@ -0,0 +1,450 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use anyhow::{bail, ensure, Context as _, Result};
+use cranelift::codegen::binemit::Reloc;
+
+use crate::{
+    codegen::{
+        cranelift::{CraneliftCodeGen, NativeReloc, NativeTrace},
+        CodeGen, CodeGenContext,
+    },
+    console::Console,
+    instruction::Instruction,
+    interpreter::Exit,
+    jit::{self, PrecompiledTrace},
+    memory::Memory,
+};
+
+// `umix compile` does not link an object file against a separate runtime.
+// Executables it makes are a copy of umix, which serves as the runtime,
+// followed by a payload with the program and the machine code of traces
+// compiled by the cranelift backend. At startup the machine code is relocated
+// and loaded like that of the JIT, which also runs any code the traces do not
+// cover.
+//
+// Payload layout. All values are little-endian u32s unless noted.
+//
+//   magic "UMXA", version
+//   length and bytes of the target the machine code was generated for
+//   length and platters of the program
+//   number of traces, then for each trace:
+//     entry pc
+//     number of instructions, then the pc and platter of each
+//     alignment as a u64, then length and bytes of the machine code
+//     number of relocations, then for each:
+//       offset, kind, addend as an i64, length and bytes of the symbol
+//
+// The file ends with the offset of the payload as a u64 and the magic again.
+const MAGIC: &[u8; 4] = b"UMXA";
+const VERSION: u32 = 1;
+const TRAILER_LEN: u64 = 12;
+
+/// Relocation kinds by their code in the payload.
+const RELOC_KINDS: [Reloc; 10] = [
+    Reloc::Abs4,
+    Reloc::Abs8,
+    Reloc::X86PCRel4,
+    Reloc::X86CallPCRel4,
+    Reloc::X86CallPLTRel4,
+    Reloc::X86GOTPCRel4,
+    Reloc::Arm64Call,
+    Reloc::Aarch64AdrGotPage21,
+    Reloc::Aarch64Ld64GotLo12Nc,
+    Reloc::RiscvCallPlt,
+];
+
+const AOT_MAX_INSTRUCTIONS: usize = 1000;
+// Beyond this many possible values, a register is considered unknown.
+const AOT_MAX_VALUES: usize = 8;
+
+/// A trace compiled ahead of time.
+struct AotTrace {
+    entry_pc: usize,
+    code: Vec<(usize, u32)>,
+    native: NativeTrace,
+}
+
+/// A program compiled ahead of time.
+pub struct Image {
+    target: String,
+    program: Vec<u32>,
+    traces: Vec<AotTrace>,
+}
+
+impl Image {
+    /// Compiles traces starting at pc 0 and at every pc the program is found
+    /// to jump to. Jump targets are found by following immediate values
+    /// through registers. Jumps to other pcs are left to the JIT at run time.
+    pub fn compile(program: Vec<u32>) -> Self {
+        let mut codegen = CraneliftCodeGen::new();
+        codegen.export();
+
+        let mut leaders: HashSet<usize> = HashSet::from([0]);
+        let mut queue: VecDeque<usize> = VecDeque::from([0]);
+        let mut compiled = Vec::new();
+        while let Some(start_pc) = queue.pop_front() {
+            let compilable = program.get(start_pc).is_some_and(|&code| {
+                !matches!(Instruction::from_u32(code).opcode(), 7 | 11 | 14 | 15)
+            });
+            if !compilable {
+                continue;
+            }
+            let mut ctx = codegen.start_function();
+            let mut targets = Vec::new();
+            let (code, end_pc) = trace_static(&program, start_pc, &leaders, &mut ctx, &mut targets);
+            ctx.finalize(end_pc);
+            compiled.push((start_pc, code));
+            for pc in targets {
+                if pc < program.len() && leaders.insert(pc) {
+                    queue.push_back(pc);
+                }
+            }
+        }
+
+        let traces = compiled
+            .into_iter()
+            .zip(codegen.take_exported())
+            .map(|((entry_pc, code), native)| AotTrace {
+                entry_pc,
+                code,
+                native,
+            })
+            .collect();
+        Self {
+            target: codegen.target(),
+            program,
+            traces,
+        }
+    }
+
+    /// Returns the number of compiled traces and instructions in them.
+    pub fn size(&self) -> (usize, usize) {
+        let insts = self.traces.iter().map(|trace| trace.code.len()).sum();
+        (self.traces.len(), insts)
+    }
+
+    /// Writes an executable running the program, made of the running umix
+    /// executable and the image.
+    pub fn save_executable(&self, path: &Path) -> Result<()> {
+        let exe = std::env::current_exe().context("locating the umix executable")?;
+        let mut runtime =
+            std::fs::read(&exe).with_context(|| format!("reading {}", exe.display()))?;
+        // Do not nest images when umix compile is run by a compiled program.
+        if let Some(offset) = payload_offset(&mut std::io::Cursor::new(&runtime))? {
+            runtime.truncate(offset as usize);
+        }
+
+        let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&runtime)?;
+        self.write(&mut writer)?;
+        writer.write_all(&(runtime.len() as u64).to_le_bytes())?;
+        writer.write_all(MAGIC)?;
+        writer.flush()?;
+        drop(writer);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_all(MAGIC)?;
+        write_u32(w, VERSION)?;
+        write_bytes(w, self.target.as_bytes())?;
+        write_u32(w, self.program.len() as u32)?;
+        for &platter in self.program.iter() {
+            write_u32(w, platter)?;
+        }
+        write_u32(w, self.traces.len() as u32)?;
+        for trace in self.traces.iter() {
+            write_u32(w, trace.entry_pc as u32)?;
+            write_u32(w, trace.code.len() as u32)?;
+            for &(pc, code) in trace.code.iter() {
+                write_u32(w, pc as u32)?;
+                write_u32(w, code)?;
+            }
+            w.write_all(&trace.native.alignment.to_le_bytes())?;
+            write_bytes(w, &trace.native.code)?;
+            write_u32(w, trace.native.relocs.len() as u32)?;
+            for reloc in trace.native.relocs.iter() {
+                let Some(kind) = RELOC_KINDS.iter().position(|&kind| kind == reloc.kind) else {
+                    bail!("unsupported relocation {:?}", reloc.kind);
+                };
+                write_u32(w, reloc.offset)?;
+                write_u32(w, kind as u32)?;
+                w.write_all(&reloc.addend.to_le_bytes())?;
+                write_bytes(w, reloc.symbol.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the image appended to the running executable, if any.
+    pub fn embedded() -> Result<Option<Self>> {
+        let exe = std::env::current_exe().context("locating the executable")?;
+        let mut reader = BufReader::new(File::open(&exe)?);
+        let Some(offset) = payload_offset(&mut reader)? else {
+            return Ok(None);
+        };
+        reader.seek(SeekFrom::Start(offset))?;
+        let image =
+            Self::read(&mut reader).with_context(|| format!("loading {}", exe.display()))?;
+        Ok(Some(image))
+    }
+
+    fn read(r: &mut impl Read) -> Result<Self> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        ensure!(&magic == MAGIC, "not a compiled program");
+        let version = read_u32(r)?;
+        ensure!(version == VERSION, "unsupported image version {version}");
+        let target = String::from_utf8(read_bytes(r)?)?;
+        let len = read_u32(r)?;
+        let program = (0..len).map(|_| read_u32(r)).collect::<Result<_>>()?;
+        let num_traces = read_u32(r)?;
+        let mut traces = Vec::new();
+        for _ in 0..num_traces {
+            let entry_pc = read_u32(r)? as usize;
+            let len = read_u32(r)?;
+            let mut code = Vec::new();
+            for _ in 0..len {
+                code.push((read_u32(r)? as usize, read_u32(r)?));
+            }
+            let alignment = read_u64(r)?;
+            let machine_code = read_bytes(r)?;
+            let num_relocs = read_u32(r)?;
+            let mut relocs = Vec::new();
+            for _ in 0..num_relocs {
+                let offset = read_u32(r)?;
+                let kind = read_u32(r)? as usize;
+                let Some(&kind) = RELOC_KINDS.get(kind) else {
+                    bail!("unknown relocation kind {kind}");
+                };
+                let addend = read_u64(r)? as i64;
+                let symbol = String::from_utf8(read_bytes(r)?)?;
+                relocs.push(NativeReloc {
+                    offset,
+                    kind,
+                    symbol,
+                    addend,
+                });
+            }
+            traces.push(AotTrace {
+                entry_pc,
+                code,
+                native: NativeTrace {
+                    code: machine_code,
+                    alignment,
+                    relocs,
+                },
+            });
+        }
+        Ok(Self {
+            target,
+            program,
+            traces,
+        })
+    }
+
+    /// Returns the machine with the program loaded into array 0.
+    pub fn boot(&self) -> Memory {
+        Memory::new(self.program.clone())
+    }
+
+    /// Runs `memory` booted with the program, starting with the compiled
+    /// traces. Code not compiled ahead of time, or modified by the program,
+    /// is run by the interpreter and the JIT as usual.
+    pub fn run(self, memory: &mut Memory, console: &mut Console) -> Result<Exit> {
+        let mut codegen = CraneliftCodeGen::new();
+        let mut traces = Vec::new();
+        if self.target == codegen.target() {
+            for trace in self.traces {
+                traces.push(PrecompiledTrace {
+                    entry_pc: trace.entry_pc,
+                    func: codegen.load(&trace.native)?,
+                    code: trace.code,
+                });
+            }
+        } else {
+            eprintln!("umix: the program was compiled for another machine; running in the JIT");
+        }
+
+        Ok(jit::run_precompiled(memory, 0, console, codegen, traces)?)
+    }
+}
+
+/// Generates a trace from `start_pc` by following the program statically,
+/// and returns the compiled instructions and the pc to continue at. Pcs
+/// the trace may jump to are added to `targets`.
+///
+/// The trace ends at instructions left to the interpreter, at `leaders`
+/// which have their own traces, and at jumps to unknown or several pcs.
+fn trace_static(
+    program: &[u32],
+    start_pc: usize,
+    leaders: &HashSet<usize>,
+    ctx: &mut impl CodeGenContext,
+    targets: &mut Vec<usize>,
+) -> (Vec<(usize, u32)>, usize) {
+    // Values registers may hold, or None if unknown.
+    let mut values: [Option<Vec<u32>>; 8] = Default::default();
+    let mut code = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pc = start_pc;
+    loop {
+        if code.len() >= AOT_MAX_INSTRUCTIONS
+            || (pc != start_pc && leaders.contains(&pc))
+            || !visited.insert(pc)
+        {
+            targets.push(pc);
+            break;
+        }
+        let Some(&platter) = program.get(pc) else {
+            break;
+        };
+        let inst = Instruction::from_u32(platter);
+        let (a, b, c) = (inst.a(), inst.b(), inst.c());
+        let mut next_pc = pc + 1;
+        match inst.opcode() {
+            0 => {
+                ctx.conditional_move(a, b, c);
+                values[a] = match &values[c] {
+                    Some(cond) if cond.iter().all(|&v| v == 0) => values[a].take(),
+                    Some(cond) if cond.iter().all(|&v| v != 0) => values[b].clone(),
+                    _ => union(&values[a], &values[b]),
+                };
+            }
+            1 => {
+                ctx.load(a, b, c, pc);
+                values[a] = None;
+            }
+            2 => ctx.store(a, b, c, pc),
+            3 => {
+                ctx.add(a, b, c);
+                values[a] = fold(&values[b], &values[c], |x, y| Some(x.wrapping_add(y)));
+            }
+            4 => {
+                ctx.mul(a, b, c);
+                values[a] = fold(&values[b], &values[c], |x, y| Some(x.wrapping_mul(y)));
+            }
+            5 => {
+                ctx.div(a, b, c, pc);
+                values[a] = fold(&values[b], &values[c], u32::checked_div);
+            }
+            6 => {
+                ctx.nand(a, b, c);
+                values[a] = fold(&values[b], &values[c], |x, y| Some(!(x & y)));
+            }
+            8 => {
+                ctx.alloc_array(b, c);
+                values[b] = None;
+            }
+            9 => ctx.free_array(c, pc),
+            10 => ctx.putc(c),
+            11 => {
+                // The interpreter takes input and continues at the next pc.
+                targets.push(pc + 1);
+                break;
+            }
+            12 => {
+                let candidates: Vec<usize> = values[c]
+                    .iter()
+                    .flatten()
+                    .map(|&v| v as usize)
+                    .filter(|&v| v < program.len())
+                    .collect();
+                targets.extend(&candidates);
+                next_pc = candidates.first().copied().unwrap_or(pc + 1);
+                ctx.jump(b, c, next_pc, pc);
+                code.push((pc, platter));
+                pc = next_pc;
+                if candidates.len() == 1 {
+                    continue;
+                }
+                // Other pcs are reached through the chain table.
+                targets.push(pc);
+                break;
+            }
+            13 => {
+                ctx.immediate(inst.imm_a(), inst.imm_value());
+                values[inst.imm_a()] = Some(vec![inst.imm_value()]);
+            }
+            // Halts and invalid instructions are left to the interpreter.
+            _ => break,
+        }
+        code.push((pc, platter));
+        pc = next_pc;
+    }
+    (code, pc)
+}
+
+fn union(x: &Option<Vec<u32>>, y: &Option<Vec<u32>>) -> Option<Vec<u32>> {
+    let mut values = x.clone()?;
+    for &v in y.as_ref()? {
+        if !values.contains(&v) {
+            values.push(v);
+        }
+    }
+    (values.len() <= AOT_MAX_VALUES).then_some(values)
+}
+
+fn fold(
+    x: &Option<Vec<u32>>,
+    y: &Option<Vec<u32>>,
+    f: impl Fn(u32, u32) -> Option<u32>,
+) -> Option<Vec<u32>> {
+    match (x.as_deref()?, y.as_deref()?) {
+        (&[x], &[y]) => Some(vec![f(x, y)?]),
+        _ => None,
+    }
+}
+
+/// Returns the offset of the payload if the file ends with one.
+fn payload_offset(r: &mut (impl Read + Seek)) -> Result<Option<u64>> {
+    let len = r.seek(SeekFrom::End(0))?;
+    if len < TRAILER_LEN {
+        return Ok(None);
+    }
+    r.seek(SeekFrom::Start(len - TRAILER_LEN))?;
+    let offset = read_u64(r)?;
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    Ok((&magic == MAGIC && offset < len).then_some(offset))
+}
+
+fn write_u32(w: &mut impl Write, value: u32) -> std::io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_bytes(w: &mut impl Write, bytes: &[u8]) -> std::io::Result<()> {
+    write_u32(w, bytes.len() as u32)?;
+    w.write_all(bytes)
+}
+
+fn read_u32(r: &mut impl Read) -> Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_bytes(r: &mut impl Read) -> Result<Vec<u8>> {
+    let len = read_u32(r)? as usize;
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
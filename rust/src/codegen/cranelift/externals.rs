@@ -1,6 +1,4 @@
-use std::io::{Read as _, Write as _};
-
-use codegen::ir::{FuncRef, Function};
+use codegen::ir::{FuncRef, Function, GlobalValue};
 use cranelift::{
     prelude::*,
     prelude::{AbiParam, Type},
@@ -8,53 +6,26 @@ use cranelift::{
 use cranelift_jit::{JITBuilder, JITModule};
 use cranelift_module::{Linkage, Module};
 
-use crate::memory::Arrays;
-
-fn alloc_array_impl(arrays_real: *mut Arrays, size: u32) -> u32 {
-    let arrays: &mut Arrays = unsafe { &mut *arrays_real };
-    arrays.insert(vec![0; size as usize]) as u32
-}
-
-fn free_array_impl(arrays_real: *mut Arrays, id: u32) {
-    let arrays = unsafe { &mut *arrays_real };
-    arrays.remove(id as usize);
-}
-
-fn get_arrays_ptr_impl(arrays_real: *mut Arrays) -> *mut *mut u32 {
-    let arrays = unsafe { &mut *arrays_real };
-    arrays.as_mut_ptr()
-}
-
-fn getc_impl() -> u32 {
-    let mut buf = [0];
-    let size = std::io::stdin().read(&mut buf).expect("read error");
-    if size == 0 {
-        !0
-    } else {
-        buf[0] as u32
-    }
-}
-
-fn putc_impl(value: u32) {
-    std::io::stdout()
-        .write_all(&[value as u8])
-        .expect("write error");
-}
+use crate::codegen::runtime;
 
 pub fn register_externals(builder: &mut JITBuilder) {
-    builder.symbol("alloc_array", alloc_array_impl as _);
-    builder.symbol("free_array", free_array_impl as _);
-    builder.symbol("get_arrays_ptr", get_arrays_ptr_impl as _);
-    builder.symbol("getc", getc_impl as _);
-    builder.symbol("putc", putc_impl as _);
+    builder.symbol("alloc_array", runtime::alloc_array as _);
+    builder.symbol("free_array", runtime::free_array as _);
+    builder.symbol("store", runtime::store as _);
+    builder.symbol("get_arrays_ptr", runtime::get_arrays_ptr as _);
+    builder.symbol("get_num_slots", runtime::get_num_slots as _);
+    builder.symbol("putc", runtime::putc as _);
+    builder.symbol("UNPROTECTED", &runtime::UNPROTECTED as *const u8);
 }
 
 pub struct ExternalRefs {
     pub alloc_array: FuncRef,
     pub free_array: FuncRef,
+    pub store: FuncRef,
     pub get_arrays_ptr: FuncRef,
-    pub getc: FuncRef,
+    pub get_num_slots: FuncRef,
     pub putc: FuncRef,
+    pub unprotected: GlobalValue,
 }
 
 pub fn declare_externals(module: &mut JITModule, func: &mut Function) -> ExternalRefs {
@@ -73,11 +44,22 @@ pub fn declare_externals(module: &mut JITModule, func: &mut Function) -> Externa
     let mut free_array_signature = module.make_signature();
     free_array_signature.params.push(AbiParam::new(pointer));
     free_array_signature.params.push(AbiParam::new(platter));
+    free_array_signature.returns.push(AbiParam::new(platter));
     let free_array_id = module
         .declare_function("free_array", Linkage::Import, &free_array_signature)
         .unwrap();
     let free_array_ref = module.declare_func_in_func(free_array_id, func);
 
+    let mut store_signature = module.make_signature();
+    store_signature.params.push(AbiParam::new(pointer));
+    store_signature.params.push(AbiParam::new(platter));
+    store_signature.params.push(AbiParam::new(platter));
+    store_signature.params.push(AbiParam::new(platter));
+    let store_id = module
+        .declare_function("store", Linkage::Import, &store_signature)
+        .unwrap();
+    let store_ref = module.declare_func_in_func(store_id, func);
+
     let mut get_arrays_ptr_signature = module.make_signature();
     get_arrays_ptr_signature.params.push(AbiParam::new(pointer));
     get_arrays_ptr_signature
@@ -88,25 +70,34 @@ pub fn declare_externals(module: &mut JITModule, func: &mut Function) -> Externa
         .unwrap();
     let get_arrays_ptr_ref = module.declare_func_in_func(get_arrays_ptr_id, func);
 
-    let mut getc_signature = module.make_signature();
-    getc_signature.returns.push(AbiParam::new(platter));
-    let getc_id = module
-        .declare_function("getc", Linkage::Import, &getc_signature)
+    let mut get_num_slots_signature = module.make_signature();
+    get_num_slots_signature.params.push(AbiParam::new(pointer));
+    get_num_slots_signature.returns.push(AbiParam::new(platter));
+    let get_num_slots_id = module
+        .declare_function("get_num_slots", Linkage::Import, &get_num_slots_signature)
         .unwrap();
-    let getc_ref = module.declare_func_in_func(getc_id, func);
+    let get_num_slots_ref = module.declare_func_in_func(get_num_slots_id, func);
 
     let mut putc_signature = module.make_signature();
+    putc_signature.params.push(AbiParam::new(pointer));
     putc_signature.params.push(AbiParam::new(platter));
     let putc_id = module
         .declare_function("putc", Linkage::Import, &putc_signature)
         .unwrap();
     let putc_ref = module.declare_func_in_func(putc_id, func);
 
+    let unprotected_id = module
+        .declare_data("UNPROTECTED", Linkage::Import, false, false)
+        .unwrap();
+    let unprotected_value = module.declare_data_in_func(unprotected_id, func);
+
     ExternalRefs {
         alloc_array: alloc_array_ref,
         free_array: free_array_ref,
+        store: store_ref,
         get_arrays_ptr: get_arrays_ptr_ref,
-        getc: getc_ref,
+        get_num_slots: get_num_slots_ref,
         putc: putc_ref,
+        unprotected: unprotected_value,
     }
 }
@@ -1,44 +1,95 @@
-use codegen::Context;
+use std::mem::offset_of;
+
+use anyhow::{bail, Result};
+use codegen::{
+    binemit::Reloc,
+    ir::{ExternalName, Function, SigRef, UserExternalName, UserFuncName},
+    Context, FinalizedMachReloc, FinalizedRelocTarget,
+};
 use cranelift::{
     frontend::{FunctionBuilder, FunctionBuilderContext},
     prelude::AbiParam,
     prelude::*,
 };
 use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::{default_libcall_names, Module as _};
+use cranelift_module::{default_libcall_names, DataId, FuncId, FuncOrDataId, Module as _};
 use externals::declare_externals;
 
 use crate::{
     codegen::cranelift::externals::{register_externals, ExternalRefs},
-    memory::{Arrays, Memory},
+    memory::RawArray,
 };
 
-use super::{CompiledFunc, CompiledFuncResult, RESULT_JUMP, RESULT_OK};
+use super::{
+    CodeGen, CodeGenContext, CompiledFunc, TraceContext, RESULT_FAULT, RESULT_JUMP, RESULT_OK,
+};
 
 mod externals;
 
 struct FunctionParams {
+    pub ctx: Value,
+    // Not parameters, but loaded once from the context in the entry block.
     pub arrays: Value,
+    pub io: Value,
+    pub protected_code: Value,
 }
 
 struct FunctionVars {
     pub regs: Vec<Variable>,
     pub arrays_ptr: Variable,
+    pub num_slots: Variable,
 }
 
 struct FunctionBlocks {
     pub return_: Block,
 }
 
+/// Returns the signature of traces, which use the tail calling convention to
+/// jump from one to another.
+fn trace_signature(module: &JITModule) -> Signature {
+    let pointer = module.target_config().pointer_type();
+    let mut signature = Signature::new(isa::CallConv::Tail);
+    signature.params.push(AbiParam::new(pointer)); // ctx
+    for _ in 0..4 {
+        signature.params.push(AbiParam::new(types::I64)); // packed registers
+    }
+    signature
+}
+
+/// Signature of the trampoline calling a trace from Rust, which passes the
+/// trace as the last argument.
+type Trampoline = extern "C" fn(&mut TraceContext, u64, u64, u64, u64, *const u8);
+
+/// Machine code of a compiled trace, with the relocations to apply when it
+/// is loaded into another process.
+pub struct NativeTrace {
+    pub code: Vec<u8>,
+    pub alignment: u64,
+    pub relocs: Vec<NativeReloc>,
+}
+
+pub struct NativeReloc {
+    pub offset: u32,
+    pub kind: Reloc,
+    /// Name of the runtime function or data referred to.
+    pub symbol: String,
+    pub addend: i64,
+}
+
 pub struct CraneliftCodeGen {
     builder_ctx: FunctionBuilderContext,
     module: JITModule,
+    trampoline: Trampoline,
+    // Machine code of compiled traces, kept if exporting.
+    exported: Option<Vec<NativeTrace>>,
 }
 
 impl CraneliftCodeGen {
     pub fn new() -> Self {
         let mut flag_builder = settings::builder();
         flag_builder.set("opt_level", "speed").unwrap();
+        // Tail calls between traces rely on frame pointers.
+        flag_builder.set("preserve_frame_pointers", "true").unwrap();
         let isa_builder = cranelift_native::builder().unwrap();
         let isa = isa_builder
             .finish(settings::Flags::new(flag_builder))
@@ -46,15 +97,163 @@ impl CraneliftCodeGen {
 
         let mut jit_builder = JITBuilder::with_isa(isa, default_libcall_names());
         register_externals(&mut jit_builder);
-        let module = JITModule::new(jit_builder);
+        let mut module = JITModule::new(jit_builder);
+        let mut builder_ctx = FunctionBuilderContext::new();
+        let trampoline = define_trampoline(&mut module, &mut builder_ctx);
 
         Self {
-            builder_ctx: FunctionBuilderContext::new(),
+            builder_ctx,
             module,
+            trampoline,
+            exported: None,
         }
     }
 
-    pub fn start_function(&mut self) -> CraneliftCodeGenContext {
+    /// Keeps the machine code of traces compiled from now on, to be taken by
+    /// `take_exported`.
+    pub fn export(&mut self) {
+        self.exported = Some(Vec::new());
+    }
+
+    pub fn take_exported(&mut self) -> Vec<NativeTrace> {
+        self.exported.take().unwrap_or_default()
+    }
+
+    /// Describes the target machine code is generated for. Exported code can
+    /// be loaded only where it is the same.
+    pub fn target(&self) -> String {
+        let isa = self.module.isa();
+        let flags: Vec<String> = isa
+            .isa_flags()
+            .iter()
+            .map(|flag| flag.to_string())
+            .collect();
+        format!("{} {}", isa.triple(), flags.join(","))
+    }
+
+    /// Loads the machine code of a trace exported by another process.
+    pub fn load(&mut self, trace: &NativeTrace) -> Result<CompiledFunc> {
+        let signature = trace_signature(&self.module);
+        let mut func = Function::with_name_signature(UserFuncName::default(), signature.clone());
+        // Make sure the runtime is declared in the module.
+        declare_externals(&mut self.module, &mut func);
+
+        let mut relocs = Vec::new();
+        for reloc in trace.relocs.iter() {
+            let name = match self.module.get_name(&reloc.symbol) {
+                Some(FuncOrDataId::Func(id)) => UserExternalName::new(0, id.as_u32()),
+                Some(FuncOrDataId::Data(id)) => UserExternalName::new(1, id.as_u32()),
+                None => bail!("unknown symbol {}", reloc.symbol),
+            };
+            let name_ref = func.declare_imported_user_function(name);
+            relocs.push(FinalizedMachReloc {
+                offset: reloc.offset,
+                kind: reloc.kind,
+                target: FinalizedRelocTarget::ExternalName(ExternalName::User(name_ref)),
+                addend: reloc.addend,
+            });
+        }
+
+        let func_id = self.module.declare_anonymous_function(&signature)?;
+        self.module
+            .define_function_bytes(func_id, &func, trace.alignment, &trace.code, &relocs)?;
+        self.module.finalize_definitions()?;
+        let entry = self.module.get_finalized_function(func_id);
+        Ok(compiled_func(self.trampoline, entry))
+    }
+}
+
+/// Returns the machine code of the function just defined, with relocations
+/// referring to the runtime by name.
+fn native_trace(ctx: &Context, module: &JITModule) -> NativeTrace {
+    let compiled = ctx.compiled_code().unwrap();
+    let declarations = module.declarations();
+    let relocs = compiled
+        .buffer
+        .relocs()
+        .iter()
+        .map(|reloc| {
+            let FinalizedRelocTarget::ExternalName(ExternalName::User(name_ref)) = reloc.target
+            else {
+                panic!("unexpected relocation target {:?}", reloc.target);
+            };
+            let name = &ctx.func.params.user_named_funcs()[name_ref];
+            let symbol = match name.namespace {
+                0 => {
+                    let id = FuncId::from_u32(name.index);
+                    declarations.get_function_decl(id).linkage_name(id)
+                }
+                _ => {
+                    let id = DataId::from_u32(name.index);
+                    declarations.get_data_decl(id).linkage_name(id)
+                }
+            };
+            NativeReloc {
+                offset: reloc.offset,
+                kind: reloc.kind,
+                symbol: symbol.into_owned(),
+                addend: reloc.addend,
+            }
+        })
+        .collect();
+    NativeTrace {
+        code: compiled.code_buffer().to_vec(),
+        alignment: compiled.buffer.alignment as u64,
+        relocs,
+    }
+}
+
+/// Creates a Rust function convenient for calling a generated function.
+fn compiled_func(trampoline: Trampoline, entry: *const u8) -> CompiledFunc {
+    CompiledFunc::new(
+        entry,
+        Box::new(move |ctx: &mut TraceContext, regs: [u64; 4]| {
+            trampoline(ctx, regs[0], regs[1], regs[2], regs[3], entry)
+        }),
+    )
+}
+
+/// Defines a function in the C calling convention that calls a trace.
+fn define_trampoline(
+    module: &mut JITModule,
+    builder_ctx: &mut FunctionBuilderContext,
+) -> Trampoline {
+    let pointer = module.target_config().pointer_type();
+
+    let mut ctx = module.make_context();
+    let mut builder = FunctionBuilder::new(&mut ctx.func, builder_ctx);
+    let signature = &mut builder.func.signature;
+    signature.params.push(AbiParam::new(pointer)); // ctx
+    for _ in 0..4 {
+        signature.params.push(AbiParam::new(types::I64)); // packed registers
+    }
+    signature.params.push(AbiParam::new(pointer)); // trace
+
+    let block = builder.create_block();
+    builder.append_block_params_for_function_params(block);
+    builder.switch_to_block(block);
+    builder.seal_block(block);
+    let params = builder.block_params(block).to_vec();
+    let sig_ref = builder.import_signature(trace_signature(module));
+    builder
+        .ins()
+        .call_indirect(sig_ref, params[5], &params[..5]);
+    builder.ins().return_(&[]);
+    builder.finalize();
+
+    let func_id = module
+        .declare_anonymous_function(&ctx.func.signature)
+        .unwrap();
+    module.define_function(func_id, &mut ctx).unwrap();
+    module.finalize_definitions().unwrap();
+    let ptr = module.get_finalized_function(func_id);
+    unsafe { std::mem::transmute::<*const u8, Trampoline>(ptr) }
+}
+
+impl CodeGen for CraneliftCodeGen {
+    type Context<'codegen> = CraneliftCodeGenContext<'codegen>;
+
+    fn start_function(&mut self) -> CraneliftCodeGenContext<'_> {
         let platter = Type::int(32).unwrap();
         let pointer = self.module.target_config().pointer_type();
 
@@ -62,16 +261,13 @@ impl CraneliftCodeGen {
         // ctx.set_disasm(true);
         let mut builder = FunctionBuilder::new(
             // SAFETY: ctx is essentially pinned.
-            unsafe { std::mem::transmute(&mut ctx.func) },
+            unsafe { std::mem::transmute::<&mut Function, &mut Function>(&mut ctx.func) },
             &mut self.builder_ctx,
         );
         let refs = declare_externals(&mut self.module, builder.func);
 
-        builder.func.signature.params = vec![
-            AbiParam::new(pointer), // regs
-            AbiParam::new(pointer), // arrays
-            AbiParam::new(pointer), // result
-        ];
+        builder.func.signature = trace_signature(&self.module);
+        let trace_sig = builder.import_signature(trace_signature(&self.module));
 
         let entry_block = builder.create_block();
         let return_block = builder.create_block();
@@ -81,30 +277,47 @@ impl CraneliftCodeGen {
         builder.seal_block(entry_block);
         builder.switch_to_block(entry_block);
         builder.append_block_params_for_function_params(entry_block);
-        let regs_value = builder.block_params(entry_block)[0];
-        let arrays_value = builder.block_params(entry_block)[1];
-        let result_value = builder.block_params(entry_block)[2];
+        let ctx_value = builder.block_params(entry_block)[0];
+        let packed_regs = builder.block_params(entry_block)[1..5].to_vec();
+        let load_field = |builder: &mut FunctionBuilder, ty: Type, offset: usize| {
+            builder
+                .ins()
+                .load(ty, MemFlags::trusted(), ctx_value, offset as i32)
+        };
 
         let regs: Vec<Variable> = (0..8)
             .map(|i| {
                 let var = Variable::new(i);
                 builder.declare_var(var, platter);
-                let value =
-                    builder
-                        .ins()
-                        .load(platter, MemFlags::trusted(), regs_value, (i * 4) as i32);
+                let packed = packed_regs[i / 2];
+                let value = if i % 2 == 0 {
+                    packed
+                } else {
+                    builder.ins().ushr_imm(packed, 32)
+                };
+                let value = builder.ins().ireduce(platter, value);
                 builder.def_var(var, value);
                 var
             })
             .collect();
 
+        let arrays_value = load_field(&mut builder, pointer, offset_of!(TraceContext, arrays));
+        let io_value = load_field(&mut builder, pointer, offset_of!(TraceContext, io));
         let arrays_ptr = Variable::new(8);
         builder.declare_var(arrays_ptr, pointer);
-        {
-            let inst = builder.ins().call(refs.get_arrays_ptr, &[arrays_value]);
-            let arrays_ptr_value = builder.inst_results(inst)[0];
-            builder.def_var(arrays_ptr, arrays_ptr_value);
-        }
+        let arrays_ptr_value =
+            load_field(&mut builder, pointer, offset_of!(TraceContext, arrays_ptr));
+        builder.def_var(arrays_ptr, arrays_ptr_value);
+        let num_slots = Variable::new(9);
+        builder.declare_var(num_slots, platter);
+        let num_slots_value =
+            load_field(&mut builder, platter, offset_of!(TraceContext, num_slots));
+        builder.def_var(num_slots, num_slots_value);
+        let protected_code_value = load_field(
+            &mut builder,
+            pointer,
+            offset_of!(TraceContext, protected_code),
+        );
         builder.ins().jump(main_block, &[]);
         builder.seal_block(main_block);
 
@@ -114,11 +327,14 @@ impl CraneliftCodeGen {
             builder.append_block_param(return_block, platter); // code
             builder.append_block_param(return_block, platter); // arg1
             builder.append_block_param(return_block, platter); // arg2
+            builder.append_block_param(return_block, types::I64); // executed instructions
             let code = builder.block_params(return_block)[0];
             let arg1 = builder.block_params(return_block)[1];
             let arg2 = builder.block_params(return_block)[2];
+            let executed = builder.block_params(return_block)[3];
 
             // Save registers.
+            let regs_value = load_field(&mut builder, pointer, offset_of!(TraceContext, regs));
             for (i, reg_var) in regs.iter().enumerate() {
                 let value = builder.use_var(*reg_var);
                 builder.ins().store(
@@ -129,7 +345,18 @@ impl CraneliftCodeGen {
                 );
             }
 
+            // Count executed instructions.
+            let insts_value = load_field(&mut builder, pointer, offset_of!(TraceContext, insts));
+            let insts = builder
+                .ins()
+                .load(types::I64, MemFlags::trusted(), insts_value, 0);
+            let insts = builder.ins().iadd(insts, executed);
+            builder
+                .ins()
+                .store(MemFlags::trusted(), insts, insts_value, 0);
+
             // Save results.
+            let result_value = load_field(&mut builder, pointer, offset_of!(TraceContext, result));
             builder
                 .ins()
                 .store(MemFlags::trusted(), code, result_value, 0);
@@ -157,13 +384,24 @@ impl CraneliftCodeGen {
             ctx,
             builder,
             params: FunctionParams {
+                ctx: ctx_value,
                 arrays: arrays_value,
+                io: io_value,
+                protected_code: protected_code_value,
+            },
+            vars: FunctionVars {
+                regs,
+                arrays_ptr,
+                num_slots,
             },
-            vars: FunctionVars { regs, arrays_ptr },
             blocks: FunctionBlocks {
                 return_: return_block,
             },
             refs,
+            trace_sig,
+            trampoline: self.trampoline,
+            exported: self.exported.as_mut(),
+            insts: 0,
         }
     }
 }
@@ -176,10 +414,191 @@ pub struct CraneliftCodeGenContext<'codegen> {
     vars: FunctionVars,
     blocks: FunctionBlocks,
     refs: ExternalRefs,
+    trace_sig: SigRef,
+    trampoline: Trampoline,
+    exported: Option<&'codegen mut Vec<NativeTrace>>,
+    // Number of instructions emitted so far.
+    insts: u64,
 }
 
 impl CraneliftCodeGenContext<'_> {
-    pub fn conditional_move(&mut self, a: usize, b: usize, c: usize) {
+    /// Exits the function, having executed `insts` instructions of the trace.
+    fn exit(&mut self, code: u32, arg1: Value, arg2: Value, insts: u64) {
+        let platter = Type::int(32).unwrap();
+
+        let code = self.builder.ins().iconst(platter, code as i64);
+        let insts = self.builder.ins().iconst(types::I64, insts as i64);
+        self.builder
+            .ins()
+            .jump(self.blocks.return_, &[code, arg1, arg2, insts]);
+    }
+
+    /// Continues at `pc` having executed `insts` instructions of the trace,
+    /// jumping into the trace compiled there if any, or else exiting the
+    /// function.
+    fn chain(&mut self, pc: Value, insts: u64) {
+        let platter = Type::int(32).unwrap();
+        let pointer = self.module.target_config().pointer_type();
+        let ctx = self.params.ctx;
+
+        let lookup_block = self.builder.create_block();
+        let call_block = self.builder.create_block();
+        let exit_block = self.builder.create_block();
+
+        let chain_len = self.builder.ins().load(
+            platter,
+            MemFlags::trusted(),
+            ctx,
+            offset_of!(TraceContext, chain_len) as i32,
+        );
+        let in_range = self
+            .builder
+            .ins()
+            .icmp(IntCC::UnsignedLessThan, pc, chain_len);
+        self.builder
+            .ins()
+            .brif(in_range, lookup_block, &[], exit_block, &[]);
+        self.builder.seal_block(lookup_block);
+
+        self.builder.switch_to_block(lookup_block);
+        let chain = self.builder.ins().load(
+            pointer,
+            MemFlags::trusted(),
+            ctx,
+            offset_of!(TraceContext, chain) as i32,
+        );
+        let pc64 = self.builder.ins().uextend(pointer, pc);
+        let entry_dist = self.builder.ins().imul_imm(pc64, pointer.bytes() as i64);
+        let entry_ptr = self.builder.ins().iadd(chain, entry_dist);
+        let entry = self
+            .builder
+            .ins()
+            .load(pointer, MemFlags::trusted(), entry_ptr, 0);
+        self.builder
+            .ins()
+            .brif(entry, call_block, &[], exit_block, &[]);
+        self.builder.seal_block(call_block);
+        self.builder.seal_block(exit_block);
+
+        // Count the instructions and the jump, and pass the registers on.
+        self.builder.switch_to_block(call_block);
+        let insts_ptr = self.builder.ins().load(
+            pointer,
+            MemFlags::trusted(),
+            ctx,
+            offset_of!(TraceContext, insts) as i32,
+        );
+        let total = self
+            .builder
+            .ins()
+            .load(types::I64, MemFlags::trusted(), insts_ptr, 0);
+        let total = self.builder.ins().iadd_imm(total, insts as i64);
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), total, insts_ptr, 0);
+        let chains_offset = offset_of!(TraceContext, chains) as i32;
+        let chains = self
+            .builder
+            .ins()
+            .load(types::I64, MemFlags::trusted(), ctx, chains_offset);
+        let chains = self.builder.ins().iadd_imm(chains, 1);
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), chains, ctx, chains_offset);
+        let mut args = vec![ctx];
+        for i in (0..8).step_by(2) {
+            let low = self.builder.use_var(self.vars.regs[i]);
+            let high = self.builder.use_var(self.vars.regs[i + 1]);
+            let low = self.builder.ins().uextend(types::I64, low);
+            let high = self.builder.ins().uextend(types::I64, high);
+            let high = self.builder.ins().ishl_imm(high, 32);
+            args.push(self.builder.ins().bor(low, high));
+        }
+        self.builder
+            .ins()
+            .return_call_indirect(self.trace_sig, entry, &args);
+
+        self.builder.switch_to_block(exit_block);
+        let zero = self.builder.ins().iconst(platter, 0);
+        self.exit(RESULT_OK, pc, zero, insts);
+    }
+
+    /// Continues if `cond` is non-zero, and exits with a fault at `pc`
+    /// otherwise.
+    fn check(&mut self, cond: Value, pc: usize) {
+        let platter = Type::int(32).unwrap();
+
+        let ok_block = self.builder.create_block();
+        let fault_block = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(cond, ok_block, &[], fault_block, &[]);
+        self.builder.seal_block(ok_block);
+        self.builder.seal_block(fault_block);
+        self.builder.set_cold_block(fault_block);
+
+        self.builder.switch_to_block(fault_block);
+        let pc_value = self.builder.ins().iconst(platter, pc as i64);
+        let zero = self.builder.ins().iconst(platter, 0);
+        self.exit(RESULT_FAULT, pc_value, zero, self.insts);
+
+        self.builder.switch_to_block(ok_block);
+    }
+
+    /// Returns the address of the `RawArray` for `id`. Identifiers out of
+    /// range get the inactive entry past the last slot, so that the address
+    /// is always safe to read.
+    fn raw_array_ptr(&mut self, id: Value) -> Value {
+        let pointer = self.module.target_config().pointer_type();
+
+        let num_slots = self.builder.use_var(self.vars.num_slots);
+        let id = self.builder.ins().umin(id, num_slots);
+
+        let id64 = self.builder.ins().uextend(pointer, id);
+        let arrays_ptr = self.builder.use_var(self.vars.arrays_ptr);
+        let array_dist = self
+            .builder
+            .ins()
+            .imul_imm(id64, std::mem::size_of::<RawArray>() as i64);
+        self.builder.ins().iadd(arrays_ptr, array_dist)
+    }
+
+    /// Returns the address of an element of the array at `array_ptr` after
+    /// checking its bounds.
+    fn element_ptr(&mut self, array_ptr: Value, offset: Value, pc: usize) -> Value {
+        let platter = Type::int(32).unwrap();
+        let pointer = self.module.target_config().pointer_type();
+
+        let offset64 = self.builder.ins().uextend(pointer, offset);
+        let len = self.builder.ins().load(
+            platter,
+            MemFlags::trusted(),
+            array_ptr,
+            std::mem::offset_of!(RawArray, len) as i32,
+        );
+        // Inactive arrays, and identifiers out of range, have zero length.
+        let valid = self
+            .builder
+            .ins()
+            .icmp(IntCC::UnsignedLessThan, offset, len);
+        self.check(valid, pc);
+
+        let array = self.builder.ins().load(
+            pointer,
+            MemFlags::trusted(),
+            array_ptr,
+            std::mem::offset_of!(RawArray, ptr) as i32,
+        );
+        let value_dist = self
+            .builder
+            .ins()
+            .imul_imm(offset64, platter.bytes() as i64);
+        self.builder.ins().iadd(array, value_dist)
+    }
+}
+
+impl CodeGenContext for CraneliftCodeGenContext<'_> {
+    fn conditional_move(&mut self, a: usize, b: usize, c: usize) {
         let cond = self.builder.use_var(self.vars.regs[c]);
         let then_block = self.builder.create_block();
         let next_block = self.builder.create_block();
@@ -195,91 +614,122 @@ impl CraneliftCodeGenContext<'_> {
         self.builder.seal_block(next_block);
 
         self.builder.switch_to_block(next_block);
+        self.insts += 1;
     }
 
-    pub fn load(&mut self, a: usize, b: usize, c: usize) {
+    fn load(&mut self, a: usize, b: usize, c: usize, pc: usize) {
         let platter = Type::int(32).unwrap();
-        let pointer = self.module.target_config().pointer_type();
 
         let id = self.builder.use_var(self.vars.regs[b]);
         let offset = self.builder.use_var(self.vars.regs[c]);
-        let id64 = self.builder.ins().uextend(pointer, id);
-        let offset64 = self.builder.ins().uextend(pointer, offset);
-        let arrays_ptr = self.builder.use_var(self.vars.arrays_ptr);
-        let array_dist = self.builder.ins().imul_imm(id64, pointer.bytes() as i64);
-        let array_ptr = self.builder.ins().iadd(arrays_ptr, array_dist);
-        let array = self
-            .builder
-            .ins()
-            .load(pointer, MemFlags::trusted(), array_ptr, 0);
-        let value_dist = self
-            .builder
-            .ins()
-            .imul_imm(offset64, platter.bytes() as i64);
-        let value_ptr = self.builder.ins().iadd(array, value_dist);
+        let array_ptr = self.raw_array_ptr(id);
+        let value_ptr = self.element_ptr(array_ptr, offset, pc);
         let value = self
             .builder
             .ins()
             .load(platter, MemFlags::trusted(), value_ptr, 0);
         self.builder.def_var(self.vars.regs[a], value);
+        self.insts += 1;
     }
 
-    pub fn store(&mut self, a: usize, b: usize, c: usize) {
+    fn store(&mut self, a: usize, b: usize, c: usize, pc: usize) {
         let platter = Type::int(32).unwrap();
         let pointer = self.module.target_config().pointer_type();
 
         let id = self.builder.use_var(self.vars.regs[a]);
         let offset = self.builder.use_var(self.vars.regs[b]);
         let value = self.builder.use_var(self.vars.regs[c]);
-        let id64 = self.builder.ins().uextend(pointer, id);
+        let array_ptr = self.raw_array_ptr(id);
+        let value_ptr = self.element_ptr(array_ptr, offset, pc);
         let offset64 = self.builder.ins().uextend(pointer, offset);
-        let arrays_ptr = self.builder.use_var(self.vars.arrays_ptr);
-        let array_dist = self.builder.ins().imul_imm(id64, pointer.bytes() as i64);
-        let array_ptr = self.builder.ins().iadd(arrays_ptr, array_dist);
-        let array = self
+
+        // Stores to array 0 may overwrite compiled code, which is recorded in
+        // the protection table. Other stores look at a dummy entry so that
+        // the fast path needs only one branch besides the check for arrays
+        // shared copy-on-write.
+        let code_block = self.builder.create_block();
+        let data_block = self.builder.create_block();
+        let protected_ptr = self
             .builder
             .ins()
-            .load(pointer, MemFlags::trusted(), array_ptr, 0);
-        let value_dist = self
+            .iadd(self.params.protected_code, offset64);
+        let unprotected_ptr = self
             .builder
             .ins()
-            .imul_imm(offset64, platter.bytes() as i64);
-        let value_ptr = self.builder.ins().iadd(array, value_dist);
+            .symbol_value(pointer, self.refs.unprotected);
+        let flag_ptr = self
+            .builder
+            .ins()
+            .select(id, unprotected_ptr, protected_ptr);
+        let flag = self
+            .builder
+            .ins()
+            .uload8(platter, MemFlags::trusted(), flag_ptr, 0);
+        let shared = self.builder.ins().load(
+            platter,
+            MemFlags::trusted(),
+            array_ptr,
+            std::mem::offset_of!(RawArray, shared) as i32,
+        );
+        let slow = self.builder.ins().bor(flag, shared);
+        self.builder
+            .ins()
+            .brif(slow, code_block, &[], data_block, &[]);
+        self.builder.seal_block(code_block);
+        self.builder.seal_block(data_block);
+
+        // Let the runtime do the store and exit, as the function itself may
+        // have been overwritten.
+        self.builder.switch_to_block(code_block);
+        self.builder
+            .ins()
+            .call(self.refs.store, &[self.params.arrays, id, offset, value]);
+        let next_pc = self.builder.ins().iconst(platter, (pc + 1) as i64);
+        let zero = self.builder.ins().iconst(platter, 0);
+        self.exit(RESULT_OK, next_pc, zero, self.insts + 1);
+
+        self.builder.switch_to_block(data_block);
         self.builder
             .ins()
             .store(MemFlags::trusted(), value, value_ptr, 0);
+        self.insts += 1;
     }
 
-    pub fn add(&mut self, a: usize, b: usize, c: usize) {
+    fn add(&mut self, a: usize, b: usize, c: usize) {
         let lhs = self.builder.use_var(self.vars.regs[b]);
         let rhs = self.builder.use_var(self.vars.regs[c]);
         let value = self.builder.ins().iadd(lhs, rhs);
         self.builder.def_var(self.vars.regs[a], value);
+        self.insts += 1;
     }
 
-    pub fn mul(&mut self, a: usize, b: usize, c: usize) {
+    fn mul(&mut self, a: usize, b: usize, c: usize) {
         let lhs = self.builder.use_var(self.vars.regs[b]);
         let rhs = self.builder.use_var(self.vars.regs[c]);
         let value = self.builder.ins().imul(lhs, rhs);
         self.builder.def_var(self.vars.regs[a], value);
+        self.insts += 1;
     }
 
-    pub fn div(&mut self, a: usize, b: usize, c: usize) {
+    fn div(&mut self, a: usize, b: usize, c: usize, pc: usize) {
         let lhs = self.builder.use_var(self.vars.regs[b]);
         let rhs = self.builder.use_var(self.vars.regs[c]);
+        self.check(rhs, pc);
         let value = self.builder.ins().udiv(lhs, rhs);
         self.builder.def_var(self.vars.regs[a], value);
+        self.insts += 1;
     }
 
-    pub fn nand(&mut self, a: usize, b: usize, c: usize) {
+    fn nand(&mut self, a: usize, b: usize, c: usize) {
         let lhs = self.builder.use_var(self.vars.regs[b]);
         let rhs = self.builder.use_var(self.vars.regs[c]);
         let and_value = self.builder.ins().band(lhs, rhs);
         let nand_value = self.builder.ins().bnot(and_value);
         self.builder.def_var(self.vars.regs[a], nand_value);
+        self.insts += 1;
     }
 
-    pub fn alloc_array(&mut self, b: usize, c: usize) {
+    fn alloc_array(&mut self, b: usize, c: usize) {
         let size = self.builder.use_var(self.vars.regs[c]);
         let call = self
             .builder
@@ -291,31 +741,54 @@ impl CraneliftCodeGenContext<'_> {
             .ins()
             .call(self.refs.get_arrays_ptr, &[self.params.arrays]);
         let new_arrays_ptr_value = self.builder.inst_results(call)[0];
+        let call = self
+            .builder
+            .ins()
+            .call(self.refs.get_num_slots, &[self.params.arrays]);
+        let new_num_slots_value = self.builder.inst_results(call)[0];
         self.builder.def_var(self.vars.regs[b], id);
         self.builder
             .def_var(self.vars.arrays_ptr, new_arrays_ptr_value);
+        self.builder
+            .def_var(self.vars.num_slots, new_num_slots_value);
+        // Let the traces jumped to from here see the new arrays.
+        self.builder.ins().store(
+            MemFlags::trusted(),
+            new_arrays_ptr_value,
+            self.params.ctx,
+            offset_of!(TraceContext, arrays_ptr) as i32,
+        );
+        self.builder.ins().store(
+            MemFlags::trusted(),
+            new_num_slots_value,
+            self.params.ctx,
+            offset_of!(TraceContext, num_slots) as i32,
+        );
+        self.insts += 1;
     }
 
-    pub fn free_array(&mut self, c: usize) {
+    fn free_array(&mut self, c: usize, pc: usize) {
         let id = self.builder.use_var(self.vars.regs[c]);
-        self.builder
+        let call = self
+            .builder
             .ins()
             .call(self.refs.free_array, &[self.params.arrays, id]);
+        let status = self.builder.inst_results(call)[0];
+        let ok = self.builder.ins().icmp_imm(IntCC::Equal, status, 0);
+        self.check(ok, pc);
+        self.insts += 1;
     }
 
-    pub fn putc(&mut self, c: usize) {
+    fn putc(&mut self, c: usize) {
         let value = self.builder.use_var(self.vars.regs[c]);
-        self.builder.ins().call(self.refs.putc, &[value]);
-    }
-
-    pub fn getc(&mut self, c: usize) {
-        let call = self.builder.ins().call(self.refs.getc, &[]);
-        let value = self.builder.inst_results(call)[0];
-        self.builder.def_var(self.vars.regs[c], value);
+        self.builder
+            .ins()
+            .call(self.refs.putc, &[self.params.io, value]);
+        self.insts += 1;
     }
 
-    pub fn jump(&mut self, b: usize, c: usize, expected_pc: usize) {
-        let platter = Type::int(32).unwrap();
+    fn jump(&mut self, b: usize, c: usize, expected_pc: usize, pc: usize) {
+        let pointer = self.module.target_config().pointer_type();
 
         let id = self.builder.use_var(self.vars.regs[b]);
         let new_pc = self.builder.use_var(self.vars.regs[c]);
@@ -329,11 +802,19 @@ impl CraneliftCodeGenContext<'_> {
         self.builder.seal_block(far_block);
         self.builder.seal_block(near_block);
 
+        // The program to load must be an active array.
         self.builder.switch_to_block(far_block);
-        let code = self.builder.ins().iconst(platter, RESULT_JUMP as i64);
-        self.builder
-            .ins()
-            .jump(self.blocks.return_, &[code, id, new_pc]);
+        let array_ptr = self.raw_array_ptr(id);
+        let array = self.builder.ins().load(
+            pointer,
+            MemFlags::trusted(),
+            array_ptr,
+            std::mem::offset_of!(RawArray, ptr) as i32,
+        );
+        // Inactive arrays, and identifiers out of range, have a null pointer.
+        let is_active = self.builder.ins().icmp_imm(IntCC::NotEqual, array, 0);
+        self.check(is_active, pc);
+        self.exit(RESULT_JUMP, id, new_pc, self.insts + 1);
 
         self.builder.switch_to_block(near_block);
         let cond = self
@@ -347,31 +828,25 @@ impl CraneliftCodeGenContext<'_> {
         self.builder.seal_block(next_block);
 
         self.builder.switch_to_block(miss_block);
-        let code = self.builder.ins().iconst(platter, RESULT_OK as i64);
-        let zero = self.builder.ins().iconst(platter, 0);
-        self.builder
-            .ins()
-            .jump(self.blocks.return_, &[code, new_pc, zero]);
+        self.chain(new_pc, self.insts + 1);
 
         self.builder.switch_to_block(next_block);
+        self.insts += 1;
     }
 
-    pub fn immediate(&mut self, a: usize, imm: u32) {
+    fn immediate(&mut self, a: usize, imm: u32) {
         let platter = Type::int(32).unwrap();
 
         let value = self.builder.ins().iconst(platter, imm as i64);
         self.builder.def_var(self.vars.regs[a], value);
+        self.insts += 1;
     }
 
-    pub fn finalize(mut self, pc: usize) -> CompiledFunc {
+    fn finalize(mut self, pc: usize) -> CompiledFunc {
         let platter = Type::int(32).unwrap();
 
-        let code = self.builder.ins().iconst(platter, RESULT_OK as i64);
         let pc_value = self.builder.ins().iconst(platter, pc as i64);
-        let zero = self.builder.ins().iconst(platter, 0);
-        self.builder
-            .ins()
-            .jump(self.blocks.return_, &[code, pc_value, zero]);
+        self.chain(pc_value, self.insts);
 
         // Finalize the function.
         self.builder.seal_all_blocks();
@@ -385,17 +860,12 @@ impl CraneliftCodeGenContext<'_> {
         if let Some(vcode) = self.ctx.compiled_code().unwrap().vcode.as_ref() {
             eprintln!("{}", vcode);
         }
+        if let Some(exported) = &mut self.exported {
+            exported.push(native_trace(&self.ctx, self.module));
+        }
         self.module.finalize_definitions().unwrap();
 
-        let jit_func_ptr = self.module.get_finalized_function(func_id);
-        let jit_func: extern "C" fn(&mut [u32; 8], &mut Arrays, &mut CompiledFuncResult) =
-            unsafe { std::mem::transmute(jit_func_ptr) };
-
-        // Create a Rust function convenient for calling the generated function.
-        Box::new(move |memory: &mut Memory| -> CompiledFuncResult {
-            let mut result = CompiledFuncResult::Halt;
-            jit_func(&mut memory.regs, &mut memory.arrays, &mut result);
-            result
-        })
+        let entry = self.module.get_finalized_function(func_id);
+        compiled_func(self.trampoline, entry)
     }
 }
@@ -0,0 +1,943 @@
+use std::{mem::offset_of, rc::Rc};
+
+use inkwell::{
+    basic_block::BasicBlock,
+    builder::Builder,
+    context::Context,
+    execution_engine::ExecutionEngine,
+    module::Module,
+    passes::PassManager,
+    targets::{InitializationConfig, Target},
+    types::{BasicType as _, BasicTypeEnum, FunctionType, IntType, PointerType, StructType},
+    values::{FunctionValue, IntValue, PhiValue, PointerValue},
+    AddressSpace, IntPredicate, OptimizationLevel,
+};
+
+use crate::codegen::runtime;
+
+use super::{
+    pack_regs, CodeGen, CodeGenContext, CompiledFunc, TraceContext, RESULT_FAULT, RESULT_JUMP,
+    RESULT_OK,
+};
+
+/// Signature of generated functions in the C calling convention.
+type TraceFunc = extern "C" fn(&mut TraceContext, u64, u64, u64, u64);
+
+struct FunctionParams<'ctx> {
+    pub ctx: PointerValue<'ctx>,
+    // Not parameters, but loaded once from the context in the entry block.
+    pub arrays: PointerValue<'ctx>,
+    pub io: PointerValue<'ctx>,
+    pub protected_code: PointerValue<'ctx>,
+}
+
+struct FunctionVars<'ctx> {
+    pub regs: Vec<PointerValue<'ctx>>,
+    pub arrays_ptr: PointerValue<'ctx>,
+    pub num_slots: PointerValue<'ctx>,
+}
+
+struct FunctionBlocks<'ctx> {
+    pub return_: BasicBlock<'ctx>,
+}
+
+struct ReturnPhis<'ctx> {
+    pub code: PhiValue<'ctx>,
+    pub arg1: PhiValue<'ctx>,
+    pub arg2: PhiValue<'ctx>,
+    pub insts: PhiValue<'ctx>,
+}
+
+struct ExternalFuncs<'ctx> {
+    pub alloc_array: FunctionValue<'ctx>,
+    pub free_array: FunctionValue<'ctx>,
+    pub store: FunctionValue<'ctx>,
+    pub get_arrays_ptr: FunctionValue<'ctx>,
+    pub get_num_slots: FunctionValue<'ctx>,
+    pub putc: FunctionValue<'ctx>,
+}
+
+impl<'ctx> ExternalFuncs<'ctx> {
+    fn declare(context: &'ctx Context, module: &Module<'ctx>) -> Self {
+        let void = context.void_type();
+        let platter = context.i32_type();
+        let opaque = context.i8_type().ptr_type(AddressSpace::default());
+        let arrays_ptr = raw_array_type(context).ptr_type(AddressSpace::default());
+
+        Self {
+            alloc_array: module.add_function(
+                "alloc_array",
+                platter.fn_type(&[opaque.into(), platter.into()], false),
+                None,
+            ),
+            free_array: module.add_function(
+                "free_array",
+                platter.fn_type(&[opaque.into(), platter.into()], false),
+                None,
+            ),
+            store: module.add_function(
+                "store",
+                void.fn_type(
+                    &[
+                        opaque.into(),
+                        platter.into(),
+                        platter.into(),
+                        platter.into(),
+                    ],
+                    false,
+                ),
+                None,
+            ),
+            get_arrays_ptr: module.add_function(
+                "get_arrays_ptr",
+                arrays_ptr.fn_type(&[opaque.into()], false),
+                None,
+            ),
+            get_num_slots: module.add_function(
+                "get_num_slots",
+                platter.fn_type(&[opaque.into()], false),
+                None,
+            ),
+            putc: module.add_function(
+                "putc",
+                void.fn_type(&[opaque.into(), platter.into()], false),
+                None,
+            ),
+        }
+    }
+
+    fn register(&self, engine: &ExecutionEngine<'ctx>) {
+        engine.add_global_mapping(
+            &self.alloc_array,
+            runtime::alloc_array as *const () as usize,
+        );
+        engine.add_global_mapping(&self.free_array, runtime::free_array as *const () as usize);
+        engine.add_global_mapping(&self.store, runtime::store as *const () as usize);
+        engine.add_global_mapping(
+            &self.get_arrays_ptr,
+            runtime::get_arrays_ptr as *const () as usize,
+        );
+        engine.add_global_mapping(
+            &self.get_num_slots,
+            runtime::get_num_slots as *const () as usize,
+        );
+        engine.add_global_mapping(&self.putc, runtime::putc as *const () as usize);
+    }
+}
+
+pub struct LlvmCodeGen {
+    // Compiled functions keep references to the context for their entire
+    // lifetime, so they share its ownership with the code generator. The
+    // context is freed once both are gone.
+    context: Rc<Context>,
+    next_id: usize,
+}
+
+impl LlvmCodeGen {
+    pub fn new() -> Self {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+        Self {
+            context: Rc::new(Context::create()),
+            next_id: 0,
+        }
+    }
+}
+
+impl CodeGen for LlvmCodeGen {
+    type Context<'codegen> = LlvmCodeGenContext;
+
+    fn start_function(&mut self) -> LlvmCodeGenContext {
+        // The context outlives everything built from it, as its owner is
+        // dropped last; see `LlvmCodeGenContext` and `Engine`.
+        let context: &'static Context = unsafe { &*Rc::as_ptr(&self.context) };
+        let name = format!("trace{}", self.next_id);
+        self.next_id += 1;
+
+        let platter = context.i32_type();
+        let platter_ptr = platter.ptr_type(AddressSpace::default());
+        let i64_type = context.i64_type();
+        let i64_ptr = i64_type.ptr_type(AddressSpace::default());
+        let pointer = raw_array_type(context).ptr_type(AddressSpace::default());
+        let opaque = context.i8_type().ptr_type(AddressSpace::default());
+
+        let module = context.create_module(&name);
+        let builder = context.create_builder();
+        let externals = ExternalFuncs::declare(context, &module);
+
+        let func = module.add_function(&name, trace_type(context), None);
+        let ctx_value = func.get_nth_param(0).unwrap().into_pointer_value();
+
+        let entry_block = context.append_basic_block(func, "entry");
+        let return_block = context.append_basic_block(func, "return");
+        let main_block = context.append_basic_block(func, "main");
+
+        // Create the entry block. Registers live in stack slots that are
+        // promoted to SSA values by mem2reg.
+        builder.position_at_end(entry_block);
+        let regs: Vec<PointerValue> = (0..8)
+            .map(|i| {
+                let var = builder.build_alloca(platter, &format!("r{i}")).unwrap();
+                let packed = func.get_nth_param(1 + i / 2).unwrap().into_int_value();
+                let shift = i64_type.const_int(32 * (i as u64 % 2), false);
+                let value = builder.build_right_shift(packed, shift, false, "").unwrap();
+                let value = builder.build_int_truncate(value, platter, "").unwrap();
+                builder.build_store(var, value).unwrap();
+                var
+            })
+            .collect();
+
+        let load_field = |offset: usize, ty: BasicTypeEnum<'static>| {
+            let ptr = ctx_field(context, &builder, ctx_value, offset, ty);
+            builder.build_load(ty, ptr, "").unwrap()
+        };
+        let arrays_value =
+            load_field(offset_of!(TraceContext, arrays), opaque.into()).into_pointer_value();
+        let io_value = load_field(offset_of!(TraceContext, io), opaque.into()).into_pointer_value();
+        let arrays_ptr = builder.build_alloca(pointer, "arrays_ptr").unwrap();
+        let arrays_ptr_value = load_field(offset_of!(TraceContext, arrays_ptr), pointer.into());
+        builder.build_store(arrays_ptr, arrays_ptr_value).unwrap();
+        let num_slots = builder.build_alloca(platter, "num_slots").unwrap();
+        let num_slots_value = load_field(offset_of!(TraceContext, num_slots), platter.into());
+        builder.build_store(num_slots, num_slots_value).unwrap();
+        let protected_code_value =
+            load_field(offset_of!(TraceContext, protected_code), opaque.into())
+                .into_pointer_value();
+        let regs_value =
+            load_field(offset_of!(TraceContext, regs), platter_ptr.into()).into_pointer_value();
+        let result_value =
+            load_field(offset_of!(TraceContext, result), platter_ptr.into()).into_pointer_value();
+        let insts_value =
+            load_field(offset_of!(TraceContext, insts), i64_ptr.into()).into_pointer_value();
+        builder.build_unconditional_branch(main_block).unwrap();
+
+        // Create the return block.
+        let phis = {
+            builder.position_at_end(return_block);
+            let code = builder.build_phi(platter, "code").unwrap();
+            let arg1 = builder.build_phi(platter, "arg1").unwrap();
+            let arg2 = builder.build_phi(platter, "arg2").unwrap();
+            let executed = builder.build_phi(i64_type, "insts").unwrap();
+
+            // Save registers.
+            for (i, reg_var) in regs.iter().enumerate() {
+                let value = builder.build_load(platter, *reg_var, "").unwrap();
+                let ptr = reg_ptr(&builder, platter, regs_value, i);
+                builder.build_store(ptr, value).unwrap();
+            }
+
+            // Count executed instructions.
+            let insts = builder
+                .build_load(i64_type, insts_value, "")
+                .unwrap()
+                .into_int_value();
+            let insts = builder
+                .build_int_add(insts, executed.as_basic_value().into_int_value(), "")
+                .unwrap();
+            builder.build_store(insts_value, insts).unwrap();
+
+            // Save results.
+            for (i, phi) in [code, arg1, arg2].iter().enumerate() {
+                let ptr = reg_ptr(&builder, platter, result_value, i);
+                builder.build_store(ptr, phi.as_basic_value()).unwrap();
+            }
+
+            builder.build_return(None).unwrap();
+
+            ReturnPhis {
+                code,
+                arg1,
+                arg2,
+                insts: executed,
+            }
+        };
+
+        // Start the main block.
+        builder.position_at_end(main_block);
+
+        LlvmCodeGenContext {
+            context,
+            module,
+            builder,
+            func,
+            name,
+            params: FunctionParams {
+                ctx: ctx_value,
+                arrays: arrays_value,
+                io: io_value,
+                protected_code: protected_code_value,
+            },
+            vars: FunctionVars {
+                regs,
+                arrays_ptr,
+                num_slots,
+            },
+            blocks: FunctionBlocks {
+                return_: return_block,
+            },
+            phis,
+            externals,
+            insts: 0,
+            owner: self.context.clone(),
+        }
+    }
+}
+
+/// Returns the type of traces, which take the context and the registers
+/// packed in pairs.
+fn trace_type(context: &Context) -> FunctionType<'_> {
+    let opaque = context.i8_type().ptr_type(AddressSpace::default());
+    let i64_type = context.i64_type();
+    context.void_type().fn_type(
+        &[
+            opaque.into(),   // ctx
+            i64_type.into(), // r0, r1
+            i64_type.into(), // r2, r3
+            i64_type.into(), // r4, r5
+            i64_type.into(), // r6, r7
+        ],
+        false,
+    )
+}
+
+/// Returns the address of the field of `TraceContext` at `offset`, which is
+/// of type `ty`.
+fn ctx_field<'ctx>(
+    context: &'ctx Context,
+    builder: &Builder<'ctx>,
+    ctx: PointerValue<'ctx>,
+    offset: usize,
+    ty: BasicTypeEnum<'ctx>,
+) -> PointerValue<'ctx> {
+    let byte = context.i8_type();
+    let offset = context.i64_type().const_int(offset as u64, false);
+    let ptr = unsafe { builder.build_in_bounds_gep(byte, ctx, &[offset], "") }.unwrap();
+    builder
+        .build_pointer_cast(ptr, ty.ptr_type(AddressSpace::default()), "")
+        .unwrap()
+}
+
+/// Returns the type corresponding to `RawArray`.
+fn raw_array_type(context: &Context) -> StructType<'_> {
+    let platter = context.i32_type();
+    let platter_ptr = platter.ptr_type(AddressSpace::default());
+    context.struct_type(&[platter_ptr.into(), platter.into(), platter.into()], false)
+}
+
+fn reg_ptr<'ctx>(
+    builder: &Builder<'ctx>,
+    platter: IntType<'ctx>,
+    base: PointerValue<'ctx>,
+    index: usize,
+) -> PointerValue<'ctx> {
+    let index = platter.const_int(index as u64, false);
+    unsafe { builder.build_in_bounds_gep(platter, base, &[index], "") }.unwrap()
+}
+
+pub struct LlvmCodeGenContext {
+    context: &'static Context,
+    module: Module<'static>,
+    builder: Builder<'static>,
+    func: FunctionValue<'static>,
+    name: String,
+    params: FunctionParams<'static>,
+    vars: FunctionVars<'static>,
+    blocks: FunctionBlocks<'static>,
+    phis: ReturnPhis<'static>,
+    externals: ExternalFuncs<'static>,
+    // Number of instructions emitted so far.
+    insts: u64,
+    // Keeps the context alive. Declared last to be dropped last.
+    owner: Rc<Context>,
+}
+
+/// An execution engine along with the context it was built in, which must be
+/// dropped after it.
+struct Engine {
+    engine: ExecutionEngine<'static>,
+    _owner: Rc<Context>,
+}
+
+impl LlvmCodeGenContext {
+    fn platter(&self) -> IntType<'static> {
+        self.context.i32_type()
+    }
+
+    fn pointer(&self) -> PointerType<'static> {
+        raw_array_type(self.context).ptr_type(AddressSpace::default())
+    }
+
+    fn use_reg(&self, i: usize) -> IntValue<'static> {
+        let platter = self.platter();
+        self.builder
+            .build_load(platter, self.vars.regs[i], "")
+            .unwrap()
+            .into_int_value()
+    }
+
+    fn def_reg(&self, i: usize, value: IntValue<'static>) {
+        self.builder.build_store(self.vars.regs[i], value).unwrap();
+    }
+
+    /// Exits the function, having executed `insts` instructions of the trace.
+    fn exit(&self, code: u32, arg1: IntValue<'static>, arg2: IntValue<'static>, insts: u64) {
+        let platter = self.platter();
+        let code = platter.const_int(code as u64, false);
+        let insts = self.context.i64_type().const_int(insts, false);
+        let block = self.builder.get_insert_block().unwrap();
+        self.phis.code.add_incoming(&[(&code, block)]);
+        self.phis.arg1.add_incoming(&[(&arg1, block)]);
+        self.phis.arg2.add_incoming(&[(&arg2, block)]);
+        self.phis.insts.add_incoming(&[(&insts, block)]);
+        self.builder
+            .build_unconditional_branch(self.blocks.return_)
+            .unwrap();
+    }
+
+    /// Continues at `pc` having executed `insts` instructions of the trace,
+    /// in the trace compiled there if any, or else exits the function.
+    fn chain(&self, pc: IntValue<'static>, insts: u64) {
+        let platter = self.platter();
+        let i64_type = self.context.i64_type();
+        let opaque = self.context.i8_type().ptr_type(AddressSpace::default());
+        let entries = opaque.ptr_type(AddressSpace::default());
+
+        let lookup_block = self.context.append_basic_block(self.func, "lookup");
+        let call_block = self.context.append_basic_block(self.func, "call");
+        let exit_block = self.context.append_basic_block(self.func, "exit");
+
+        let chain_len_ptr = self.ctx_field(offset_of!(TraceContext, chain_len), platter.into());
+        let chain_len = self
+            .builder
+            .build_load(platter, chain_len_ptr, "")
+            .unwrap()
+            .into_int_value();
+        let in_range = self
+            .builder
+            .build_int_compare(IntPredicate::ULT, pc, chain_len, "")
+            .unwrap();
+        self.builder
+            .build_conditional_branch(in_range, lookup_block, exit_block)
+            .unwrap();
+
+        self.builder.position_at_end(lookup_block);
+        let chain_ptr = self.ctx_field(offset_of!(TraceContext, chain), entries.into());
+        let chain = self
+            .builder
+            .build_load(entries, chain_ptr, "")
+            .unwrap()
+            .into_pointer_value();
+        let entry_ptr = unsafe {
+            self.builder
+                .build_in_bounds_gep(opaque, chain, &[self.zext(pc)], "")
+        }
+        .unwrap();
+        let entry = self
+            .builder
+            .build_load(opaque, entry_ptr, "")
+            .unwrap()
+            .into_pointer_value();
+        let is_compiled = self.builder.build_is_not_null(entry, "").unwrap();
+        self.builder
+            .build_conditional_branch(is_compiled, call_block, exit_block)
+            .unwrap();
+
+        // Count the jump, and return the entry to the trampoline in
+        // `CompiledFunc`, which calls it with the saved registers. Calling it
+        // here would grow the stack unless LLVM turned the call into a jump,
+        // which it only does reliably for musttail calls that the C API of
+        // LLVM 16 cannot build.
+        self.builder.position_at_end(call_block);
+        let chains_ptr = self.ctx_field(offset_of!(TraceContext, chains), i64_type.into());
+        let chains = self
+            .builder
+            .build_load(i64_type, chains_ptr, "")
+            .unwrap()
+            .into_int_value();
+        let chains = self
+            .builder
+            .build_int_add(chains, i64_type.const_int(1, false), "")
+            .unwrap();
+        self.builder.build_store(chains_ptr, chains).unwrap();
+        let next_ptr = self.ctx_field(offset_of!(TraceContext, next), opaque.into());
+        self.builder.build_store(next_ptr, entry).unwrap();
+        self.exit(RESULT_OK, pc, platter.const_zero(), insts);
+
+        self.builder.position_at_end(exit_block);
+        self.exit(RESULT_OK, pc, platter.const_zero(), insts);
+    }
+
+    fn ctx_field(&self, offset: usize, ty: BasicTypeEnum<'static>) -> PointerValue<'static> {
+        ctx_field(self.context, &self.builder, self.params.ctx, offset, ty)
+    }
+
+    /// Continues if `cond` is true, and exits with a fault at `pc` otherwise.
+    fn check(&self, cond: IntValue<'static>, pc: usize) {
+        let platter = self.platter();
+
+        let ok_block = self.context.append_basic_block(self.func, "ok");
+        let fault_block = self.context.append_basic_block(self.func, "fault");
+        let branch = self
+            .builder
+            .build_conditional_branch(cond, ok_block, fault_block)
+            .unwrap();
+        // Faults are rare, so keep them out of the way of the hot path.
+        let weights = self.context.metadata_node(&[
+            self.context.metadata_string("branch_weights").into(),
+            platter.const_int(1 << 20, false).into(),
+            platter.const_int(1, false).into(),
+        ]);
+        branch
+            .set_metadata(weights, self.context.get_kind_id("prof"))
+            .unwrap();
+
+        self.builder.position_at_end(fault_block);
+        let pc_value = platter.const_int(pc as u64, false);
+        self.exit(RESULT_FAULT, pc_value, platter.const_zero(), self.insts);
+
+        self.builder.position_at_end(ok_block);
+    }
+
+    /// Returns the address of the `RawArray` for `id`. Identifiers out of
+    /// range get the inactive entry past the last slot, so that the address
+    /// is always safe to read.
+    fn raw_array_ptr(&self, id: IntValue<'static>) -> PointerValue<'static> {
+        let platter = self.platter();
+        let pointer = self.pointer();
+        let raw_array = raw_array_type(self.context);
+
+        let num_slots = self
+            .builder
+            .build_load(platter, self.vars.num_slots, "")
+            .unwrap()
+            .into_int_value();
+        let valid_id = self
+            .builder
+            .build_int_compare(IntPredicate::ULT, id, num_slots, "")
+            .unwrap();
+        let id = self
+            .builder
+            .build_select(valid_id, id, num_slots, "")
+            .unwrap()
+            .into_int_value();
+
+        let arrays_ptr = self
+            .builder
+            .build_load(pointer, self.vars.arrays_ptr, "")
+            .unwrap()
+            .into_pointer_value();
+        let array_ptr = unsafe {
+            self.builder
+                .build_in_bounds_gep(raw_array, arrays_ptr, &[self.zext(id)], "")
+        }
+        .unwrap();
+        array_ptr
+    }
+
+    /// Returns the address of an element of the array at `array_ptr` after
+    /// checking its bounds.
+    fn element_ptr(
+        &self,
+        array_ptr: PointerValue<'static>,
+        offset: IntValue<'static>,
+        pc: usize,
+    ) -> PointerValue<'static> {
+        let platter = self.platter();
+        let platter_ptr = platter.ptr_type(AddressSpace::default());
+        let raw_array = raw_array_type(self.context);
+
+        let len_ptr = self
+            .builder
+            .build_struct_gep(raw_array, array_ptr, 1, "")
+            .unwrap();
+        let len = self
+            .builder
+            .build_load(platter, len_ptr, "")
+            .unwrap()
+            .into_int_value();
+        // Inactive arrays, and identifiers out of range, have zero length.
+        let valid = self
+            .builder
+            .build_int_compare(IntPredicate::ULT, offset, len, "")
+            .unwrap();
+        self.check(valid, pc);
+
+        let ptr_ptr = self
+            .builder
+            .build_struct_gep(raw_array, array_ptr, 0, "")
+            .unwrap();
+        let array = self
+            .builder
+            .build_load(platter_ptr, ptr_ptr, "")
+            .unwrap()
+            .into_pointer_value();
+        let offset = self.zext(offset);
+        unsafe {
+            self.builder
+                .build_in_bounds_gep(platter, array, &[offset], "")
+        }
+        .unwrap()
+    }
+
+    fn zext(&self, value: IntValue<'static>) -> IntValue<'static> {
+        let i64_type = self.context.i64_type();
+        self.builder
+            .build_int_z_extend(value, i64_type, "")
+            .unwrap()
+    }
+}
+
+impl CodeGenContext for LlvmCodeGenContext {
+    fn conditional_move(&mut self, a: usize, b: usize, c: usize) {
+        let cond = self.use_reg(c);
+        let then_value = self.use_reg(b);
+        let else_value = self.use_reg(a);
+        let zero = self.platter().const_zero();
+        let is_set = self
+            .builder
+            .build_int_compare(IntPredicate::NE, cond, zero, "")
+            .unwrap();
+        let value = self
+            .builder
+            .build_select(is_set, then_value, else_value, "")
+            .unwrap()
+            .into_int_value();
+        self.def_reg(a, value);
+        self.insts += 1;
+    }
+
+    fn load(&mut self, a: usize, b: usize, c: usize, pc: usize) {
+        let platter = self.platter();
+
+        let id = self.use_reg(b);
+        let offset = self.use_reg(c);
+        let array_ptr = self.raw_array_ptr(id);
+        let value_ptr = self.element_ptr(array_ptr, offset, pc);
+        let value = self
+            .builder
+            .build_load(platter, value_ptr, "")
+            .unwrap()
+            .into_int_value();
+        self.def_reg(a, value);
+        self.insts += 1;
+    }
+
+    fn store(&mut self, a: usize, b: usize, c: usize, pc: usize) {
+        let platter = self.platter();
+        let byte = self.context.i8_type();
+
+        let id = self.use_reg(a);
+        let offset = self.use_reg(b);
+        let value = self.use_reg(c);
+        let array_ptr = self.raw_array_ptr(id);
+        let value_ptr = self.element_ptr(array_ptr, offset, pc);
+
+        // Stores to array 0 may overwrite compiled code, which is recorded in
+        // the protection table. Other stores look at a dummy entry so that
+        // the fast path needs only one branch besides the check for arrays
+        // shared copy-on-write.
+        let code_block = self.context.append_basic_block(self.func, "code");
+        let data_block = self.context.append_basic_block(self.func, "data");
+        let protected_ptr = unsafe {
+            self.builder.build_in_bounds_gep(
+                byte,
+                self.params.protected_code,
+                &[self.zext(offset)],
+                "",
+            )
+        }
+        .unwrap();
+        let unprotected_ptr = self
+            .context
+            .i64_type()
+            .const_int(&runtime::UNPROTECTED as *const u8 as u64, false)
+            .const_to_pointer(byte.ptr_type(AddressSpace::default()));
+        let is_data = self
+            .builder
+            .build_int_compare(IntPredicate::NE, id, platter.const_zero(), "")
+            .unwrap();
+        let flag_ptr = self
+            .builder
+            .build_select(is_data, unprotected_ptr, protected_ptr, "")
+            .unwrap()
+            .into_pointer_value();
+        let flag = self
+            .builder
+            .build_load(byte, flag_ptr, "")
+            .unwrap()
+            .into_int_value();
+        let raw_array = raw_array_type(self.context);
+        let shared_ptr = self
+            .builder
+            .build_struct_gep(raw_array, array_ptr, 2, "")
+            .unwrap();
+        let shared = self
+            .builder
+            .build_load(platter, shared_ptr, "")
+            .unwrap()
+            .into_int_value();
+        let flag = self.builder.build_int_z_extend(flag, platter, "").unwrap();
+        let slow = self.builder.build_or(flag, shared, "").unwrap();
+        let is_slow = self
+            .builder
+            .build_int_compare(IntPredicate::NE, slow, platter.const_zero(), "")
+            .unwrap();
+        self.builder
+            .build_conditional_branch(is_slow, code_block, data_block)
+            .unwrap();
+
+        // Let the runtime do the store and exit, as the function itself may
+        // have been overwritten.
+        self.builder.position_at_end(code_block);
+        self.builder
+            .build_call(
+                self.externals.store,
+                &[
+                    self.params.arrays.into(),
+                    id.into(),
+                    offset.into(),
+                    value.into(),
+                ],
+                "",
+            )
+            .unwrap();
+        let next_pc = platter.const_int((pc + 1) as u64, false);
+        self.exit(RESULT_OK, next_pc, platter.const_zero(), self.insts + 1);
+
+        self.builder.position_at_end(data_block);
+        self.builder.build_store(value_ptr, value).unwrap();
+        self.insts += 1;
+    }
+
+    fn add(&mut self, a: usize, b: usize, c: usize) {
+        let lhs = self.use_reg(b);
+        let rhs = self.use_reg(c);
+        let value = self.builder.build_int_add(lhs, rhs, "").unwrap();
+        self.def_reg(a, value);
+        self.insts += 1;
+    }
+
+    fn mul(&mut self, a: usize, b: usize, c: usize) {
+        let lhs = self.use_reg(b);
+        let rhs = self.use_reg(c);
+        let value = self.builder.build_int_mul(lhs, rhs, "").unwrap();
+        self.def_reg(a, value);
+        self.insts += 1;
+    }
+
+    fn div(&mut self, a: usize, b: usize, c: usize, pc: usize) {
+        let lhs = self.use_reg(b);
+        let rhs = self.use_reg(c);
+        let nonzero = self
+            .builder
+            .build_int_compare(IntPredicate::NE, rhs, self.platter().const_zero(), "")
+            .unwrap();
+        self.check(nonzero, pc);
+        let value = self.builder.build_int_unsigned_div(lhs, rhs, "").unwrap();
+        self.def_reg(a, value);
+        self.insts += 1;
+    }
+
+    fn nand(&mut self, a: usize, b: usize, c: usize) {
+        let lhs = self.use_reg(b);
+        let rhs = self.use_reg(c);
+        let and_value = self.builder.build_and(lhs, rhs, "").unwrap();
+        let nand_value = self.builder.build_not(and_value, "").unwrap();
+        self.def_reg(a, nand_value);
+        self.insts += 1;
+    }
+
+    fn alloc_array(&mut self, b: usize, c: usize) {
+        let size = self.use_reg(c);
+        let call = self
+            .builder
+            .build_call(
+                self.externals.alloc_array,
+                &[self.params.arrays.into(), size.into()],
+                "",
+            )
+            .unwrap();
+        let id = call.try_as_basic_value().left().unwrap().into_int_value();
+        let call = self
+            .builder
+            .build_call(
+                self.externals.get_arrays_ptr,
+                &[self.params.arrays.into()],
+                "",
+            )
+            .unwrap();
+        let new_arrays_ptr_value = call.try_as_basic_value().left().unwrap();
+        let call = self
+            .builder
+            .build_call(
+                self.externals.get_num_slots,
+                &[self.params.arrays.into()],
+                "",
+            )
+            .unwrap();
+        let new_num_slots_value = call.try_as_basic_value().left().unwrap();
+        self.def_reg(b, id);
+        self.builder
+            .build_store(self.vars.arrays_ptr, new_arrays_ptr_value)
+            .unwrap();
+        self.builder
+            .build_store(self.vars.num_slots, new_num_slots_value)
+            .unwrap();
+        // Let the traces jumped to from here see the new arrays.
+        let arrays_ptr_field =
+            self.ctx_field(offset_of!(TraceContext, arrays_ptr), self.pointer().into());
+        self.builder
+            .build_store(arrays_ptr_field, new_arrays_ptr_value)
+            .unwrap();
+        let num_slots_field =
+            self.ctx_field(offset_of!(TraceContext, num_slots), self.platter().into());
+        self.builder
+            .build_store(num_slots_field, new_num_slots_value)
+            .unwrap();
+        self.insts += 1;
+    }
+
+    fn free_array(&mut self, c: usize, pc: usize) {
+        let platter = self.platter();
+
+        let id = self.use_reg(c);
+        let call = self
+            .builder
+            .build_call(
+                self.externals.free_array,
+                &[self.params.arrays.into(), id.into()],
+                "",
+            )
+            .unwrap();
+        let status = call.try_as_basic_value().left().unwrap().into_int_value();
+        let ok = self
+            .builder
+            .build_int_compare(IntPredicate::EQ, status, platter.const_zero(), "")
+            .unwrap();
+        self.check(ok, pc);
+        self.insts += 1;
+    }
+
+    fn putc(&mut self, c: usize) {
+        let value = self.use_reg(c);
+        self.builder
+            .build_call(
+                self.externals.putc,
+                &[self.params.io.into(), value.into()],
+                "",
+            )
+            .unwrap();
+        self.insts += 1;
+    }
+
+    fn jump(&mut self, b: usize, c: usize, expected_pc: usize, pc: usize) {
+        let platter = self.platter();
+
+        let id = self.use_reg(b);
+        let new_pc = self.use_reg(c);
+
+        let far_block = self.context.append_basic_block(self.func, "far");
+        let near_block = self.context.append_basic_block(self.func, "near");
+        let miss_block = self.context.append_basic_block(self.func, "miss");
+        let next_block = self.context.append_basic_block(self.func, "next");
+
+        let is_far = self
+            .builder
+            .build_int_compare(IntPredicate::NE, id, platter.const_zero(), "")
+            .unwrap();
+        self.builder
+            .build_conditional_branch(is_far, far_block, near_block)
+            .unwrap();
+
+        // The program to load must be an active array.
+        self.builder.position_at_end(far_block);
+        let platter_ptr = platter.ptr_type(AddressSpace::default());
+        let raw_array = raw_array_type(self.context);
+        let array_ptr = self.raw_array_ptr(id);
+        let ptr_ptr = self
+            .builder
+            .build_struct_gep(raw_array, array_ptr, 0, "")
+            .unwrap();
+        let array = self
+            .builder
+            .build_load(platter_ptr, ptr_ptr, "")
+            .unwrap()
+            .into_pointer_value();
+        // Inactive arrays, and identifiers out of range, have a null pointer.
+        let is_active = self.builder.build_is_not_null(array, "").unwrap();
+        self.check(is_active, pc);
+        self.exit(RESULT_JUMP, id, new_pc, self.insts + 1);
+
+        self.builder.position_at_end(near_block);
+        let expected = platter.const_int(expected_pc as u64, false);
+        let cond = self
+            .builder
+            .build_int_compare(IntPredicate::EQ, new_pc, expected, "")
+            .unwrap();
+        self.builder
+            .build_conditional_branch(cond, next_block, miss_block)
+            .unwrap();
+
+        self.builder.position_at_end(miss_block);
+        self.chain(new_pc, self.insts + 1);
+
+        self.builder.position_at_end(next_block);
+        self.insts += 1;
+    }
+
+    fn immediate(&mut self, a: usize, imm: u32) {
+        let value = self.platter().const_int(imm as u64, false);
+        self.def_reg(a, value);
+        self.insts += 1;
+    }
+
+    fn finalize(self, pc: usize) -> CompiledFunc {
+        let platter = self.platter();
+
+        let pc_value = platter.const_int(pc as u64, false);
+        self.chain(pc_value, self.insts);
+
+        // Optimize the function.
+        let fpm = PassManager::create(&self.module);
+        fpm.add_promote_memory_to_register_pass();
+        fpm.add_instruction_combining_pass();
+        fpm.add_reassociate_pass();
+        fpm.add_gvn_pass();
+        fpm.add_cfg_simplification_pass();
+        fpm.initialize();
+        fpm.run_on(&self.func);
+        // self.module.print_to_stderr();
+
+        let engine = self
+            .module
+            .create_jit_execution_engine(OptimizationLevel::Aggressive)
+            .unwrap();
+        self.externals.register(&engine);
+
+        let entry = engine.get_function_address(&self.name).unwrap() as *const u8;
+        let trace_func: TraceFunc = unsafe { std::mem::transmute(entry) };
+
+        // Create a Rust function convenient for calling the generated function,
+        // which keeps calling the traces it chains to until one exits.
+        // The execution engine owns the machine code, so keep it alive.
+        let engine = Engine {
+            engine,
+            _owner: self.owner,
+        };
+        CompiledFunc::new(
+            entry,
+            Box::new(move |ctx: &mut TraceContext, mut regs: [u64; 4]| {
+                let _engine = &engine.engine;
+                let mut func = trace_func;
+                loop {
+                    func(ctx, regs[0], regs[1], regs[2], regs[3]);
+                    let next = std::mem::replace(&mut ctx.next, std::ptr::null());
+                    if next.is_null() {
+                        break;
+                    }
+                    func = unsafe { std::mem::transmute::<*const u8, TraceFunc>(next) };
+                    regs = pack_regs(unsafe { &*ctx.regs });
+                }
+            }),
+        )
+    }
+}
@@ -1,18 +1,182 @@
-use crate::memory::Memory;
+use crate::{
+    io::UmIo,
+    memory::{Arrays, Memory, RawArray},
+};
 
 pub mod cranelift;
+#[cfg(feature = "llvm")]
+pub mod llvm;
+mod runtime;
 
-pub type CompiledFunc = Box<dyn Fn(&mut Memory) -> CompiledFuncResult>;
+/// Machine state shared by compiled traces while they run. Traces receive the
+/// context and the registers packed in pairs into `u64` arguments, and
+/// continue in one another through the chain table without returning to the
+/// dispatcher.
+///
+/// Only cranelift traces jump to each other directly, with guaranteed tail
+/// calls that keep registers in machine registers. The C API of LLVM cannot
+/// build musttail calls, so LLVM traces save the registers to `regs` and
+/// return the next entry point in `next` to the trampoline in
+/// `CompiledFunc`, which calls it.
+#[repr(C)]
+struct TraceContext {
+    /// Where registers are saved when compiled code returns.
+    regs: *mut [u32; 8],
+    arrays: *mut Arrays,
+    result: *mut CompiledFuncResult,
+    /// A `*mut &mut dyn UmIo`, passed to the runtime for output.
+    io: *mut u8,
+    /// Counter of executed instructions.
+    insts: *mut u64,
+    /// Entry points of compiled traces indexed by pc, null where there is
+    /// none.
+    chain: *const *const u8,
+    chain_len: u32,
+    /// Number of jumps between traces.
+    chains: u64,
+    // Cached from `arrays`. Compiled code updates them after allocations.
+    arrays_ptr: *mut RawArray,
+    num_slots: u32,
+    protected_code: *mut u8,
+    /// Entry point of the trace to continue in, for backends whose traces
+    /// return to `CompiledFunc` instead of jumping to each other. Null when
+    /// the last trace exited.
+    next: *const u8,
+}
+
+/// Packs registers in pairs as traces take them.
+fn pack_regs(regs: &[u32; 8]) -> [u64; 4] {
+    [0, 2, 4, 6].map(|i| regs[i] as u64 | (regs[i + 1] as u64) << 32)
+}
+
+/// Calls a generated function with the context and packed registers.
+type TraceCall = Box<dyn Fn(&mut TraceContext, [u64; 4])>;
+
+/// A compiled trace.
+pub struct CompiledFunc {
+    entry: *const u8,
+    call: TraceCall,
+}
+
+impl CompiledFunc {
+    fn new(entry: *const u8, call: TraceCall) -> Self {
+        Self { entry, call }
+    }
+
+    /// Returns the address jumped to by other traces through the chain
+    /// table.
+    pub fn entry(&self) -> *const u8 {
+        self.entry
+    }
+
+    /// Runs the trace and those it jumps to through `chain`, adding the
+    /// number of executed instructions to `insts`. Returns the result and
+    /// the number of jumps between traces.
+    pub fn call(
+        &self,
+        memory: &mut Memory,
+        mut io: &mut dyn UmIo,
+        insts: &mut u64,
+        chain: &[*const u8],
+    ) -> (CompiledFuncResult, u64) {
+        let mut result = CompiledFuncResult::Halt;
+        let regs = memory.regs;
+        let mut ctx = TraceContext {
+            regs: &mut memory.regs,
+            arrays_ptr: memory.arrays.as_mut_ptr(),
+            num_slots: memory.arrays.num_slots() as u32,
+            protected_code: memory.arrays.protected_code_ptr(),
+            arrays: &mut memory.arrays,
+            result: &mut result,
+            io: &mut io as *mut &mut dyn UmIo as *mut u8,
+            insts,
+            chain: chain.as_ptr(),
+            chain_len: chain.len() as u32,
+            chains: 0,
+            next: std::ptr::null(),
+        };
+        (self.call)(&mut ctx, pack_regs(&regs));
+        (result, ctx.chains)
+    }
+}
+
+/// A JIT backend that compiles traces into native functions.
+pub trait CodeGen {
+    type Context<'codegen>: CodeGenContext
+    where
+        Self: 'codegen;
+
+    /// Starts building a new function for a trace.
+    fn start_function(&mut self) -> Self::Context<'_>;
+}
+
+/// Builds a single function by emitting UM instructions in the order they
+/// were traced.
+///
+/// A trace is a straight line of instructions, so every exit of the function
+/// knows statically how many of them have been executed, and adds the number
+/// to the instruction counter. Instructions exiting with a fault are not
+/// counted, as the caller runs them again in the interpreter.
+///
+/// Exits to a pc where another trace is compiled jump straight into it.
+pub trait CodeGenContext {
+    fn conditional_move(&mut self, a: usize, b: usize, c: usize);
+
+    /// Emits an ArrayIndex instruction at `pc`. Out of bounds accesses make
+    /// the function exit with a fault at `pc`.
+    fn load(&mut self, a: usize, b: usize, c: usize, pc: usize);
+
+    /// Emits an ArrayAmendment instruction at `pc`. If it stores to compiled
+    /// code in array 0, the function exits with the next pc. Out of bounds
+    /// accesses make the function exit with a fault at `pc`.
+    fn store(&mut self, a: usize, b: usize, c: usize, pc: usize);
+
+    fn add(&mut self, a: usize, b: usize, c: usize);
+    fn mul(&mut self, a: usize, b: usize, c: usize);
+
+    /// Emits a Division instruction at `pc`, which faults on division by zero.
+    fn div(&mut self, a: usize, b: usize, c: usize, pc: usize);
+
+    fn nand(&mut self, a: usize, b: usize, c: usize);
+    fn alloc_array(&mut self, b: usize, c: usize);
+
+    /// Emits an Abandonment instruction at `pc`, which faults on array 0 and
+    /// inactive arrays.
+    fn free_array(&mut self, c: usize, pc: usize);
+
+    fn putc(&mut self, c: usize);
+
+    /// Emits a LoadProgram instruction at `pc`. The trace continues at
+    /// `expected_pc` in array 0; any other destination exits the function.
+    fn jump(&mut self, b: usize, c: usize, expected_pc: usize, pc: usize);
+
+    fn immediate(&mut self, a: usize, imm: u32);
+
+    /// Finishes the function, which exits with `pc` when the end of the trace
+    /// is reached.
+    fn finalize(self, pc: usize) -> CompiledFunc;
+}
 
 const RESULT_OK: u32 = 0;
 const RESULT_JUMP: u32 = 1;
 const RESULT_HALT: u32 = 2;
+const RESULT_FAULT: u32 = 3;
 
 #[derive(Clone, Copy, Debug)]
 #[repr(C, u32)]
 #[allow(dead_code)] // Some discriminants are constructed in the JIT code.
 pub enum CompiledFuncResult {
-    Ok { pc: u32 } = RESULT_OK,
-    Jump { id: u32, new_pc: u32 } = RESULT_JUMP,
+    Ok {
+        pc: u32,
+    } = RESULT_OK,
+    Jump {
+        id: u32,
+        new_pc: u32,
+    } = RESULT_JUMP,
     Halt = RESULT_HALT,
+    /// The instruction at `pc` faulted. No part of it has been executed, so
+    /// the caller should run it again in the interpreter to learn the cause.
+    Fault {
+        pc: u32,
+    } = RESULT_FAULT,
 }
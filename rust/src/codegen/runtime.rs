@@ -0,0 +1,52 @@
+use crate::{
+    io::UmIo,
+    memory::{Arrays, RawArray},
+};
+
+// Functions called from the generated code. They are shared by all backends,
+// so they use the C calling convention.
+
+/// Protection table entry consulted by stores to arrays other than array 0.
+pub static UNPROTECTED: u8 = 0;
+
+pub extern "C" fn alloc_array(arrays_real: *mut Arrays, size: u32) -> u32 {
+    let arrays: &mut Arrays = unsafe { &mut *arrays_real };
+    arrays.insert(vec![0; size as usize]) as u32
+}
+
+/// Returns a non-zero value if the array can not be abandoned, in which case
+/// the generated code should fault.
+pub extern "C" fn free_array(arrays_real: *mut Arrays, id: u32) -> u32 {
+    let arrays = unsafe { &mut *arrays_real };
+    if id == 0 || !arrays.is_active(id as usize) {
+        return 1;
+    }
+    arrays.remove(id as usize);
+    0
+}
+
+/// Stores a value where the generated code can not store directly: to
+/// compiled code in array 0, or to an array sharing its platters with array 0.
+pub extern "C" fn store(arrays_real: *mut Arrays, id: u32, offset: u32, value: u32) {
+    let arrays = unsafe { &mut *arrays_real };
+    if id == 0 {
+        arrays.store_code(offset as usize, value);
+    } else {
+        arrays[id as usize][offset as usize] = value;
+    }
+}
+
+pub extern "C" fn get_arrays_ptr(arrays_real: *mut Arrays) -> *mut RawArray {
+    let arrays = unsafe { &mut *arrays_real };
+    arrays.as_mut_ptr()
+}
+
+pub extern "C" fn get_num_slots(arrays_real: *mut Arrays) -> u32 {
+    let arrays = unsafe { &mut *arrays_real };
+    arrays.num_slots() as u32
+}
+
+pub extern "C" fn putc(io: *mut &mut dyn UmIo, value: u32) {
+    let io = unsafe { &mut *io };
+    io.put(value as u8);
+}
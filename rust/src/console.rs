@@ -0,0 +1,371 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    io::UmIo,
+    memory::Memory,
+    replay::{InputEvent, InputRecorder},
+    snapshot::{self, Format},
+    stats::Stats,
+};
+
+/// Number of most recent bytes on the screen kept to redraw it when a session
+/// is restored.
+pub const BACKLOG_CAPACITY: usize = 4096;
+
+/// Number of pasted bytes that can wait to be read, as in the C
+/// implementation. Bytes beyond it are dropped.
+pub const PASTE_CAPACITY: usize = 1024 * 1024;
+
+const SNAPSHOT_DEFAULT_FILENAME: &str = "snapshot.umx";
+const SCREEN_RESET: &[u8] = b"\x1bc";
+
+/// State of the input after [`Console::wait_for_input`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitResult {
+    /// The input instruction can be executed.
+    Ready,
+    /// The machine state has been replaced by a snapshot.
+    Reloaded,
+    /// The input has reached its end and the run should be suspended.
+    EndOfInput,
+    /// The user asked to stop the machine.
+    Quit,
+}
+
+enum Action {
+    None,
+    Reload,
+    Quit,
+    Exit,
+}
+
+/// Terminal of the machine on top of its I/O. It keeps a backlog of the
+/// screen, queues pasted input, records and replays input, and runs a host
+/// console entered by typing the escape byte at the beginning of input.
+///
+/// A command can follow the escape byte on the same line, or else the
+/// console prompts for commands until `exit`.
+pub struct Console {
+    io: Box<dyn UmIo>,
+    escape: Option<u8>,
+    suspend_at_eof: bool,
+    backlog: VecDeque<u8>,
+    paste: VecDeque<u8>,
+    recorder: Option<InputRecorder>,
+    replay: VecDeque<InputEvent>,
+    replay_diverged: bool,
+    stats: Stats,
+}
+
+impl Console {
+    pub fn new(io: Box<dyn UmIo>, escape: Option<u8>, suspend_at_eof: bool) -> Self {
+        Self {
+            io,
+            escape,
+            suspend_at_eof,
+            backlog: VecDeque::with_capacity(BACKLOG_CAPACITY),
+            paste: VecDeque::new(),
+            recorder: None,
+            replay: VecDeque::new(),
+            replay_diverged: false,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Records input delivered to the program from now on.
+    pub fn set_recorder(&mut self, recorder: InputRecorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Delivers the events of an input log to the program before any other
+    /// input.
+    pub fn set_replay(&mut self, events: VecDeque<InputEvent>) {
+        self.replay = events;
+    }
+
+    /// Sets the number of instructions executed so far, which timestamps
+    /// recorded input.
+    pub fn set_instruction_count(&mut self, insts: u64) {
+        self.stats.insts = insts;
+    }
+
+    /// Updates the counters of the run shown by the `stat` command.
+    pub fn set_stats(&mut self, stats: Stats) {
+        self.stats = stats;
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Returns the most recent bytes on the screen in chronological order.
+    pub fn backlog(&self) -> Vec<u8> {
+        self.backlog.iter().copied().collect()
+    }
+
+    /// Replaces the backlog with `data`, of which only the last
+    /// [`BACKLOG_CAPACITY`] bytes are kept.
+    pub fn set_backlog(&mut self, data: &[u8]) {
+        let data = &data[data.len().saturating_sub(BACKLOG_CAPACITY)..];
+        self.backlog.clear();
+        self.backlog.extend(data);
+    }
+
+    /// Queues bytes to be read as input before the underlying input, up to
+    /// [`PASTE_CAPACITY`] pending bytes. Returns the number of bytes queued.
+    pub fn feed_paste(&mut self, data: &[u8]) -> usize {
+        let len = data.len().min(PASTE_CAPACITY - self.paste.len());
+        self.paste.extend(&data[..len]);
+        len
+    }
+
+    /// Returns the pasted bytes the program has not read yet.
+    pub fn paste(&self) -> Vec<u8> {
+        self.paste.iter().copied().collect()
+    }
+
+    /// Clears the screen and writes the backlog to it.
+    pub fn redraw(&mut self) {
+        self.write(SCREEN_RESET);
+        for i in 0..self.backlog.len() {
+            self.io.put(self.backlog[i]);
+        }
+    }
+
+    /// Waits until input is available for the input instruction at `pc`,
+    /// running the console when the escape byte is read.
+    pub fn wait_for_input(&mut self, pc: &mut usize, memory: &mut Memory) -> WaitResult {
+        loop {
+            if !self.replay.is_empty() || !self.paste.is_empty() {
+                return WaitResult::Ready;
+            }
+            match self.io.peek() {
+                None if self.suspend_at_eof => return WaitResult::EndOfInput,
+                Some(c) if Some(c) == self.escape => {
+                    self.io.get();
+                }
+                _ => return WaitResult::Ready,
+            }
+            match self.enter(pc, memory) {
+                Action::Reload => return WaitResult::Reloaded,
+                Action::Quit => return WaitResult::Quit,
+                Action::None | Action::Exit => {}
+            }
+        }
+    }
+
+    fn next_replay(&mut self) -> Option<InputEvent> {
+        let event = self.replay.pop_front()?;
+        if event.insts != self.stats.insts && !self.replay_diverged {
+            eprintln!(
+                "umix: replay diverged: input recorded at instruction {} is read at {}",
+                event.insts, self.stats.insts
+            );
+            self.replay_diverged = true;
+        }
+        Some(event)
+    }
+
+    fn feed_backlog(&mut self, value: u8) {
+        if self.backlog.len() == BACKLOG_CAPACITY {
+            self.backlog.pop_front();
+        }
+        self.backlog.push_back(value);
+    }
+
+    /// Writes host messages, which are not part of the backlog.
+    fn write(&mut self, data: &[u8]) {
+        for &value in data {
+            self.io.put(value);
+        }
+    }
+
+    fn read_line(&mut self) -> Option<String> {
+        let mut line = Vec::new();
+        loop {
+            match self.io.get() {
+                None if line.is_empty() => return None,
+                None | Some(b'\n') => break,
+                Some(value) => line.push(value),
+            }
+        }
+        Some(String::from_utf8_lossy(&line).trim().to_owned())
+    }
+
+    fn enter(&mut self, pc: &mut usize, memory: &mut Memory) -> Action {
+        let Some(line) = self.read_line() else {
+            return Action::None;
+        };
+        if !line.is_empty() {
+            let action = self.run_command(&line, pc, memory);
+            if let Action::Reload = action {
+                self.redraw();
+            }
+            return action;
+        }
+
+        self.write(SCREEN_RESET);
+        let mut reloaded = false;
+        loop {
+            self.write(b"um> ");
+            let Some(line) = self.read_line() else {
+                break;
+            };
+            if line.is_empty() {
+                continue;
+            }
+            match self.run_command(&line, pc, memory) {
+                Action::None => {}
+                Action::Reload => reloaded = true,
+                Action::Quit => return Action::Quit,
+                Action::Exit => break,
+            }
+        }
+        self.redraw();
+        if reloaded {
+            Action::Reload
+        } else {
+            Action::None
+        }
+    }
+
+    fn run_command(&mut self, line: &str, pc: &mut usize, memory: &mut Memory) -> Action {
+        let (command, arg) = match line.split_once(' ') {
+            Some((command, arg)) => (command, Some(arg.trim())),
+            None => (line, None),
+        };
+        let path = PathBuf::from(arg.unwrap_or(SNAPSHOT_DEFAULT_FILENAME));
+        match command {
+            "stat" => self.print_stat(memory),
+            "save" => self.command_save(&path, *pc, memory),
+            "load" => return self.command_load(&path, pc, memory),
+            "send" => match arg {
+                Some(arg) => self.command_send(Path::new(arg)),
+                None => self.write(b"no filename specified!\n"),
+            },
+            "halt" | "quit" | "q" => return Action::Quit,
+            "exit" | "x" => return Action::Exit,
+            _ => self.write(format!("unknown command: {command}\n").as_bytes()),
+        }
+        Action::None
+    }
+
+    fn print_stat(&mut self, memory: &Memory) {
+        let stat = self.stats.report(&memory.arrays);
+        self.write(stat.as_bytes());
+    }
+
+    fn command_save(&mut self, path: &Path, pc: usize, memory: &Memory) {
+        let message = match snapshot::save(
+            path,
+            Format::Umix,
+            pc,
+            memory,
+            &self.backlog(),
+            &self.paste(),
+        ) {
+            Ok(()) => format!("saved to {}.\n", path.display()),
+            Err(err) => format!("{err:#}\n"),
+        };
+        self.write(message.as_bytes());
+    }
+
+    fn command_load(&mut self, path: &Path, pc: &mut usize, memory: &mut Memory) -> Action {
+        match snapshot::load(path) {
+            Ok(snapshot) => {
+                *pc = snapshot.pc;
+                *memory = snapshot.memory;
+                self.set_backlog(&snapshot.backlog);
+                self.paste.clear();
+                self.feed_paste(&snapshot.paste);
+                self.write(format!("loaded from {}.\n", path.display()).as_bytes());
+                Action::Reload
+            }
+            Err(err) => {
+                self.write(format!("{err:#}\n").as_bytes());
+                Action::None
+            }
+        }
+    }
+
+    fn command_send(&mut self, path: &Path) {
+        match std::fs::read(path) {
+            Ok(data) => {
+                let len = self.feed_paste(&data);
+                if len < data.len() {
+                    let message =
+                        format!("paste buffer full; dropped {} bytes\n", data.len() - len);
+                    self.write(message.as_bytes());
+                }
+            }
+            Err(err) => self.write(format!("opening {}: {err}\n", path.display()).as_bytes()),
+        }
+    }
+}
+
+impl UmIo for Console {
+    fn put(&mut self, value: u8) {
+        self.io.put(value);
+        self.feed_backlog(value);
+    }
+
+    fn get(&mut self) -> Option<u8> {
+        let value = if let Some(event) = self.next_replay() {
+            event.value
+        } else if let Some(value) = self.paste.pop_front() {
+            // Pasted input is not echoed by the terminal.
+            self.io.put(value);
+            Some(value)
+        } else {
+            self.io.get()
+        };
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(InputEvent {
+                insts: self.stats.insts,
+                value,
+            });
+        }
+        if let Some(value) = value {
+            self.feed_backlog(value);
+        }
+        value
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        if let Some(event) = self.replay.front() {
+            return event.value;
+        }
+        match self.paste.front() {
+            Some(&value) => Some(value),
+            None => self.io.peek(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::StreamIo;
+
+    use super::*;
+
+    #[test]
+    fn paste_is_capped() {
+        let io = StreamIo::new(&b""[..], std::io::sink());
+        let mut console = Console::new(Box::new(io), None, false);
+        assert_eq!(
+            console.feed_paste(&[b'a'; PASTE_CAPACITY - 1]),
+            PASTE_CAPACITY - 1
+        );
+        // Only as much as fits is queued, and the rest is dropped.
+        assert_eq!(console.feed_paste(b"bc"), 1);
+        assert_eq!(console.feed_paste(b"d"), 0);
+        assert_eq!(console.get(), Some(b'a'));
+        assert_eq!(console.feed_paste(b"ef"), 1);
+        let paste = console.paste();
+        assert_eq!(paste.len(), PASTE_CAPACITY);
+        assert_eq!(&paste[PASTE_CAPACITY - 2..], b"be");
+    }
+}
@@ -0,0 +1,340 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+    rc::Rc,
+};
+
+use anyhow::{bail, ensure, Context as _, Result};
+
+use crate::{memory::Memory, program::hash_program};
+
+// Coverage file layout. All values are little-endian u32s unless noted.
+//
+//   magic "UMXC", version
+//   number of distinct programs, then for each program its length and its
+//   platters as loaded
+//   number of generations, then for each generation:
+//     generation as a u64
+//     index of its program, then the bitmap of executed pcs, a bit per
+//     platter in bytes padded to a byte boundary
+const MAGIC: &[u8; 4] = b"UMXC";
+const VERSION: u32 = 2;
+
+/// Executed pcs of the program loaded by one generation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProgramCoverage {
+    /// Number of LoadProgram instructions replacing array 0 before the
+    /// program was loaded.
+    pub generation: u64,
+    /// Array 0 as the program was loaded, before it could modify itself.
+    /// Generations loading the same program share it.
+    pub program: Rc<[u32]>,
+    executed: Vec<u8>,
+}
+
+impl ProgramCoverage {
+    fn new(generation: u64, program: Rc<[u32]>) -> Self {
+        let executed = vec![0; program.len().div_ceil(8)];
+        Self {
+            generation,
+            program,
+            executed,
+        }
+    }
+
+    pub fn is_executed(&self, pc: usize) -> bool {
+        self.executed
+            .get(pc / 8)
+            .is_some_and(|byte| byte & (1 << (pc % 8)) != 0)
+    }
+
+    /// Returns the number of executed pcs.
+    pub fn count(&self) -> usize {
+        self.executed
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+}
+
+/// Bitmaps of executed pcs, forked for every program loaded by LoadProgram.
+#[derive(Clone, Debug, Default)]
+pub struct Coverage {
+    programs: Vec<ProgramCoverage>,
+    /// Distinct programs by hash, which the generations loading them share.
+    loaded: HashMap<u64, Rc<[u32]>>,
+}
+
+impl PartialEq for Coverage {
+    fn eq(&self, other: &Self) -> bool {
+        self.programs == other.programs
+    }
+}
+
+impl Eq for Coverage {}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the shared copy of `program`, adding it if it is new.
+    fn intern(&mut self, program: &[u32]) -> Rc<[u32]> {
+        let hash = hash_program(program);
+        match self.loaded.get(&hash) {
+            Some(loaded) if **loaded == *program => loaded.clone(),
+            // Keep the first program on a hash collision.
+            Some(_) => program.into(),
+            None => self.loaded.entry(hash).or_insert(program.into()).clone(),
+        }
+    }
+
+    /// Marks `pc` as executed before the instruction runs, starting a new
+    /// bitmap from array 0 on the first instruction of a generation.
+    pub fn record(&mut self, generation: u64, pc: usize, memory: &Memory) {
+        if self
+            .programs
+            .last()
+            .is_none_or(|program| program.generation != generation)
+        {
+            let program = self.intern(&memory.arrays[0]);
+            self.programs
+                .push(ProgramCoverage::new(generation, program));
+        }
+        let program = self.programs.last_mut().unwrap();
+        if let Some(byte) = program.executed.get_mut(pc / 8) {
+            *byte |= 1 << (pc % 8);
+        }
+    }
+
+    pub fn program(&self, generation: u64) -> Option<&ProgramCoverage> {
+        self.programs
+            .iter()
+            .find(|program| program.generation == generation)
+    }
+
+    /// Adds the pcs executed in another run. Runs must have loaded the same
+    /// program in each generation they share.
+    pub fn merge(&mut self, other: &Coverage) -> Result<()> {
+        for theirs in other.programs.iter() {
+            match self
+                .programs
+                .iter_mut()
+                .find(|ours| ours.generation == theirs.generation)
+            {
+                Some(ours) => {
+                    ensure!(
+                        ours.program == theirs.program,
+                        "generation {} loaded different programs",
+                        theirs.generation
+                    );
+                    for (a, b) in ours.executed.iter_mut().zip(&theirs.executed) {
+                        *a |= b;
+                    }
+                }
+                None => {
+                    let program = self.intern(&theirs.program);
+                    self.programs.push(ProgramCoverage {
+                        program,
+                        ..theirs.clone()
+                    });
+                }
+            }
+        }
+        self.programs.sort_by_key(|program| program.generation);
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn write(&self, writer: &mut impl Write) -> Result<()> {
+        // Number the distinct programs in order of first use.
+        let mut distinct: Vec<&Rc<[u32]>> = Vec::new();
+        let mut indices = Vec::new();
+        for program in self.programs.iter() {
+            let index = match distinct
+                .iter()
+                .position(|other| Rc::ptr_eq(other, &program.program))
+            {
+                Some(index) => index,
+                None => {
+                    distinct.push(&program.program);
+                    distinct.len() - 1
+                }
+            };
+            indices.push(index as u32);
+        }
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&(distinct.len() as u32).to_le_bytes())?;
+        for program in distinct {
+            writer.write_all(&(program.len() as u32).to_le_bytes())?;
+            for platter in program.iter() {
+                writer.write_all(&platter.to_le_bytes())?;
+            }
+        }
+        writer.write_all(&(self.programs.len() as u32).to_le_bytes())?;
+        for (program, index) in self.programs.iter().zip(indices) {
+            writer.write_all(&program.generation.to_le_bytes())?;
+            writer.write_all(&index.to_le_bytes())?;
+            writer.write_all(&program.executed)?;
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        let mut reader = BufReader::new(file);
+        Self::read(&mut reader).with_context(|| format!("loading {}", path.display()))
+    }
+
+    fn read(reader: &mut impl Read) -> Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("not a coverage file");
+        }
+        let version = read_u32(reader)?;
+        ensure!(version == VERSION, "unsupported coverage version {version}");
+        let mut coverage = Coverage::new();
+        let num_programs = read_u32(reader)?;
+        let mut distinct = Vec::new();
+        for _ in 0..num_programs {
+            let len = read_u32(reader)? as usize;
+            let mut program = Vec::new();
+            for _ in 0..len {
+                program.push(read_u32(reader)?);
+            }
+            distinct.push(coverage.intern(&program));
+        }
+        let num_generations = read_u32(reader)?;
+        for _ in 0..num_generations {
+            let mut generation = [0; 8];
+            reader.read_exact(&mut generation)?;
+            let generation = u64::from_le_bytes(generation);
+            let index = read_u32(reader)?;
+            let Some(program) = distinct.get(index as usize) else {
+                bail!("invalid program {index} of generation {generation}");
+            };
+            let mut executed = vec![0; program.len().div_ceil(8)];
+            reader.read_exact(&mut executed)?;
+            coverage.programs.push(ProgramCoverage {
+                generation,
+                program: program.clone(),
+                executed,
+            });
+        }
+        Ok(coverage)
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns coverage of runs executing `pcs` of a program in each
+    /// generation.
+    fn coverage(runs: &[(u64, &[u32], &[usize])]) -> Coverage {
+        let mut coverage = Coverage::new();
+        for &(generation, program, pcs) in runs {
+            let memory = Memory::new(program.to_vec());
+            for &pc in pcs {
+                coverage.record(generation, pc, &memory);
+            }
+        }
+        coverage
+    }
+
+    fn executed(coverage: &Coverage, generation: u64) -> Vec<usize> {
+        let program = coverage.program(generation).unwrap();
+        (0..program.program.len())
+            .filter(|&pc| program.is_executed(pc))
+            .collect()
+    }
+
+    #[test]
+    fn merge() {
+        let program: &[u32] = &[0; 10];
+        let other: &[u32] = &[1; 3];
+        let mut ours = coverage(&[(0, program, &[0, 1, 9]), (2, other, &[0])]);
+        let theirs = coverage(&[(0, program, &[1, 2, 8]), (1, other, &[2])]);
+        ours.merge(&theirs).unwrap();
+
+        assert_eq!(executed(&ours, 0), [0, 1, 2, 8, 9]);
+        assert_eq!(ours.program(0).unwrap().count(), 5);
+        assert_eq!(executed(&ours, 1), [2]);
+        assert_eq!(executed(&ours, 2), [0]);
+        // Generations stay in order.
+        let generations: Vec<u64> = ours.programs.iter().map(|p| p.generation).collect();
+        assert_eq!(generations, [0, 1, 2]);
+    }
+
+    #[test]
+    fn merge_into_empty() {
+        let theirs = coverage(&[(0, &[0; 4], &[3])]);
+        let mut ours = Coverage::new();
+        ours.merge(&theirs).unwrap();
+        assert_eq!(ours, theirs);
+    }
+
+    #[test]
+    fn merge_different_programs() {
+        let mut ours = coverage(&[(0, &[0; 4], &[0])]);
+        let theirs = coverage(&[(0, &[1; 4], &[1])]);
+        let err = ours.merge(&theirs).err().unwrap();
+        assert_eq!(err.to_string(), "generation 0 loaded different programs");
+    }
+
+    #[test]
+    fn save_and_load() {
+        let coverage = coverage(&[(0, &[7; 9], &[0, 8]), (3, &[5], &[0])]);
+        let mut data = Vec::new();
+        coverage.write(&mut data).unwrap();
+        let loaded = Coverage::read(&mut &data[..]).unwrap();
+        assert_eq!(loaded, coverage);
+    }
+
+    #[test]
+    fn programs_are_shared() {
+        let program: &[u32] = &[7; 9];
+        let coverage = coverage(&[(0, program, &[0]), (1, &[5], &[0]), (2, program, &[1])]);
+        let shared = |coverage: &Coverage| {
+            Rc::ptr_eq(
+                &coverage.program(0).unwrap().program,
+                &coverage.program(2).unwrap().program,
+            )
+        };
+        assert!(shared(&coverage));
+        assert_eq!(coverage.loaded.len(), 2);
+
+        let mut data = Vec::new();
+        coverage.write(&mut data).unwrap();
+        // Each program is written once: header, programs, generations.
+        assert_eq!(
+            data.len(),
+            12 + (4 + 9 * 4) + (4 + 4) + 4 + 3 * (8 + 4) + 2 + 1 + 2
+        );
+        let loaded = Coverage::read(&mut &data[..]).unwrap();
+        assert_eq!(loaded, coverage);
+        assert!(shared(&loaded));
+
+        let mut merged = Coverage::new();
+        merged.merge(&loaded).unwrap();
+        assert!(shared(&merged));
+    }
+}
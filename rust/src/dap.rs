@@ -0,0 +1,1007 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, TryRecvError},
+};
+
+use anyhow::{bail, Context as _, Result};
+use serde_json::{json, Value};
+
+use crate::{
+    error::UmError,
+    instruction::disassemble,
+    interpreter::{execute_step, fetch, StepResult},
+    io::UmIo,
+    memory::Memory,
+    program::load_program,
+};
+
+const THREAD_ID: u64 = 1;
+const FRAME_ID: u64 = 1;
+const REGISTERS_REF: u64 = 1;
+const ARRAYS_REF: u64 = 2;
+/// Variables reference of array 0; array N is `ARRAY_REF_BASE + N`.
+const ARRAY_REF_BASE: u64 = 1000;
+/// Upper bound of variables references, which are 32-bit signed integers.
+const MAX_REF: u64 = i32::MAX as u64;
+
+/// Number of instructions run between checks for requests from the client.
+const POLL_INTERVAL: u64 = 1 << 14;
+/// Platters listed when the client does not page an array.
+const DEFAULT_PLATTERS: usize = 1000;
+
+/// Program I/O in a debug session. Output is sent to the client as output
+/// events, and input is typed into the debug console.
+#[derive(Default)]
+struct SessionIo {
+    output: Vec<u8>,
+    input: VecDeque<u8>,
+}
+
+impl UmIo for SessionIo {
+    fn put(&mut self, value: u8) {
+        self.output.push(value);
+    }
+
+    fn get(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.input.front().copied()
+    }
+}
+
+/// Assembler listing of a codex. Lines starting with a pc, in decimal as in
+/// `umix dump` or in hexadecimal with 0x, followed by a colon are mapped to
+/// that instruction; other lines such as labels and comments are ignored.
+struct Listing {
+    path: PathBuf,
+    /// The pc of each line, 0-based.
+    pcs: Vec<Option<usize>>,
+    lines: HashMap<usize, usize>,
+}
+
+impl Listing {
+    fn load(path: &Path) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let pcs: Vec<Option<usize>> = text.lines().map(parse_listing_pc).collect();
+        let mut lines = HashMap::new();
+        for (line, pc) in pcs.iter().enumerate() {
+            if let &Some(pc) = pc {
+                lines.entry(pc).or_insert(line);
+            }
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            pcs,
+            lines,
+        })
+    }
+
+    /// Resolves a 1-based line to the first instruction at or after it,
+    /// returning its pc and line.
+    fn resolve(&self, line: usize) -> Option<(usize, usize)> {
+        (line.max(1) - 1..self.pcs.len()).find_map(|i| self.pcs[i].map(|pc| (pc, i + 1)))
+    }
+}
+
+fn parse_listing_pc(line: &str) -> Option<usize> {
+    let (pc, _) = line.trim_start().split_once(':')?;
+    match pc.trim_end().strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None if !pc.is_empty() && pc.bytes().all(|c| c.is_ascii_digit()) => pc.parse().ok(),
+        None => None,
+    }
+}
+
+/// Breakpoint resolved to the generation and pc of an instruction, and the
+/// line showing it.
+type ResolvedBreakpoint = (u64, usize, usize);
+
+/// Reason execution stopped.
+enum Stop {
+    Step,
+    Breakpoint,
+    Pause,
+    Input,
+    Fault(UmError),
+    Halt,
+}
+
+struct Session {
+    name: String,
+    memory: Memory,
+    pc: usize,
+    /// Number of times LoadProgram has replaced array 0.
+    generation: u64,
+    listing: Option<Listing>,
+    io: SessionIo,
+    /// Breakpoints set in each source, as (generation, pc).
+    source_breakpoints: HashMap<String, Vec<(u64, usize)>>,
+    breakpoints: HashSet<(u64, usize)>,
+    running: bool,
+    halted: bool,
+}
+
+impl Session {
+    /// Executes one instruction, returning why execution should stop if it
+    /// should.
+    fn step(&mut self) -> Option<Stop> {
+        if self.halted {
+            return Some(Stop::Halt);
+        }
+        let inst = match fetch(&self.memory, self.pc) {
+            Ok(inst) => inst,
+            Err(err) => return Some(Stop::Fault(err)),
+        };
+        if inst.opcode() == 11 && self.io.input.is_empty() {
+            return Some(Stop::Input);
+        }
+        match execute_step(self.pc, inst, &mut self.memory, &mut self.io) {
+            Ok(StepResult::Halt) => {
+                self.halted = true;
+                return Some(Stop::Halt);
+            }
+            Ok(StepResult::Next) => self.pc += 1,
+            Ok(StepResult::Jump { id, new_pc }) => {
+                if id != 0 {
+                    self.memory.arrays.dup0(id as usize);
+                    self.generation += 1;
+                }
+                self.pc = new_pc;
+            }
+            Err(err) => return Some(Stop::Fault(err)),
+        }
+        if self.breakpoints.contains(&(self.generation, self.pc)) {
+            return Some(Stop::Breakpoint);
+        }
+        None
+    }
+
+    /// Returns the source and 1-based line of the current instruction.
+    fn location(&self) -> (Value, usize) {
+        if let (0, Some(listing)) = (self.generation, &self.listing) {
+            if let Some(&line) = listing.lines.get(&self.pc) {
+                return (listing_source(listing), line + 1);
+            }
+        }
+        (self.dump_source(self.generation), self.pc + 1)
+    }
+
+    /// Source of the `umix dump` listing of the program of a generation.
+    fn dump_source(&self, generation: u64) -> Value {
+        let name = if generation == 0 {
+            format!("{} (dump)", self.name)
+        } else {
+            format!("{} (generation {generation})", self.name)
+        };
+        json!({ "name": name, "sourceReference": generation + 1 })
+    }
+}
+
+fn listing_source(listing: &Listing) -> Value {
+    let name = listing
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned());
+    json!({ "name": name, "path": listing.path })
+}
+
+/// Debug Adapter Protocol server sending responses and events to `writer`.
+struct Server<W> {
+    writer: W,
+    requests: Receiver<Value>,
+    seq: u64,
+    session: Option<Session>,
+    /// Whether the client has finished configuring breakpoints.
+    configured: bool,
+    stop_on_entry: bool,
+    /// Stop to report after the response to the current request.
+    pending_stop: Option<Stop>,
+}
+
+/// Serves the Debug Adapter Protocol over the standard input and output until
+/// the client disconnects.
+pub fn run() -> Result<()> {
+    serve(BufReader::new(std::io::stdin()), std::io::stdout())
+}
+
+/// Serves requests read from `reader` until the client disconnects.
+fn serve(mut reader: impl BufRead + Send + 'static, writer: impl Write) -> Result<()> {
+    let (sender, requests) = mpsc::channel();
+    // Read requests in the background so that a running program can be
+    // paused.
+    std::thread::spawn(move || {
+        while let Ok(Some(message)) = read_message(&mut reader) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    let mut server = Server {
+        writer,
+        requests,
+        seq: 0,
+        session: None,
+        configured: false,
+        stop_on_entry: false,
+        pending_stop: None,
+    };
+    server.serve()
+}
+
+impl<W: Write> Server<W> {
+    fn serve(&mut self) -> Result<()> {
+        loop {
+            if self.session.as_ref().is_some_and(|session| session.running) {
+                self.run_slice()?;
+                match self.requests.try_recv() {
+                    Ok(request) => {
+                        if !self.handle(&request)? {
+                            return Ok(());
+                        }
+                    }
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            } else {
+                let Ok(request) = self.requests.recv() else {
+                    return Ok(());
+                };
+                if !self.handle(&request)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Runs the program for a while, reporting a stop if it stops.
+    fn run_slice(&mut self) -> Result<()> {
+        let session = self.session.as_mut().unwrap();
+        let mut stop = None;
+        for _ in 0..POLL_INTERVAL {
+            stop = session.step();
+            if stop.is_some() {
+                break;
+            }
+        }
+        self.flush_output()?;
+        if let Some(stop) = stop {
+            self.stopped(stop)?;
+        }
+        Ok(())
+    }
+
+    fn stopped(&mut self, stop: Stop) -> Result<()> {
+        let session = self.session.as_mut().unwrap();
+        session.running = false;
+        let (reason, text) = match stop {
+            Stop::Step => ("step", None),
+            Stop::Breakpoint => ("breakpoint", None),
+            Stop::Pause => ("pause", None),
+            Stop::Input => (
+                "pause",
+                Some("waiting for input; type it in the debug console".to_owned()),
+            ),
+            Stop::Fault(err) => ("exception", Some(err.to_string())),
+            Stop::Halt => {
+                self.send_event("exited", json!({ "exitCode": 0 }))?;
+                return self.send_event("terminated", json!({}));
+            }
+        };
+        self.send_event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "description": text,
+                "text": text,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+            }),
+        )
+    }
+
+    fn flush_output(&mut self) -> Result<()> {
+        let session = self.session.as_mut().unwrap();
+        if session.io.output.is_empty() {
+            return Ok(());
+        }
+        let output = String::from_utf8_lossy(&std::mem::take(&mut session.io.output)).into_owned();
+        self.send_event("output", json!({ "category": "stdout", "output": output }))
+    }
+
+    /// Handles a request, returning false when the session ends.
+    fn handle(&mut self, request: &Value) -> Result<bool> {
+        if request["type"] != "request" {
+            return Ok(true);
+        }
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsSingleThreadExecutionRequests": false,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "configurationDone" => {
+                self.configured = true;
+                Ok(json!({}))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "um" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                { "name": "Arrays", "variablesReference": ARRAYS_REF, "expensive": false },
+            ] })),
+            "variables" => self.variables(args),
+            "source" => self.source(args),
+            "continue" => self
+                .resume()
+                .map(|()| json!({ "allThreadsContinued": true })),
+            "next" | "stepIn" | "stepOut" => self.single_step(),
+            "pause" => self.pause(),
+            "evaluate" => self.evaluate(args),
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(json!({})))?;
+                return Ok(false);
+            }
+            _ => Err(anyhow::anyhow!("unsupported request {command}")),
+        };
+        self.respond(request, result)?;
+        if command == "initialize" {
+            self.send_event("initialized", json!({}))?;
+        }
+        if command == "configurationDone" {
+            self.start()?;
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value> {
+        let Some(program) = args["program"].as_str() else {
+            bail!("program is not specified");
+        };
+        let program = Path::new(program);
+        let listing = match args["listing"].as_str() {
+            Some(path) => Some(Listing::load(Path::new(path))?),
+            None => {
+                // Look for a listing next to the codex.
+                let path = program.with_extension("lst");
+                path.exists().then(|| Listing::load(&path)).transpose()?
+            }
+        };
+        let mut io = SessionIo::default();
+        if let Some(input) = args["input"].as_str() {
+            io.input.extend(input.bytes());
+        }
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.session = Some(Session {
+            name: program
+                .file_name()
+                .map_or_else(String::new, |name| name.to_string_lossy().into_owned()),
+            memory: Memory::new(load_program(program)?),
+            pc: 0,
+            generation: 0,
+            listing,
+            io,
+            source_breakpoints: HashMap::new(),
+            breakpoints: HashSet::new(),
+            running: false,
+            halted: false,
+        });
+        if self.configured {
+            self.start()?;
+        }
+        Ok(json!({}))
+    }
+
+    /// Starts the program once it is launched and configured.
+    fn start(&mut self) -> Result<()> {
+        let Some(session) = &self.session else {
+            return Ok(());
+        };
+        if session.breakpoints.contains(&(0, session.pc)) {
+            return self.stopped(Stop::Breakpoint);
+        }
+        if self.stop_on_entry {
+            self.send_event(
+                "stopped",
+                json!({ "reason": "entry", "threadId": THREAD_ID, "allThreadsStopped": true }),
+            )
+        } else {
+            self.resume()
+        }
+    }
+
+    fn session(&mut self) -> Result<&mut Session> {
+        self.session.as_mut().context("no program is launched")
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value> {
+        let session = self.session()?;
+        let source = &args["source"];
+        let lines: Vec<usize> = args["breakpoints"]
+            .as_array()
+            .map(|breakpoints| {
+                breakpoints
+                    .iter()
+                    .filter_map(|bp| bp["line"].as_u64())
+                    .map(|line| line as usize)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let (key, resolved): (String, Vec<Option<ResolvedBreakpoint>>) =
+            if let Some(reference) = source["sourceReference"].as_u64().filter(|&r| r > 0) {
+                // A dump listing, where line N is pc N - 1.
+                let generation = reference - 1;
+                let resolved = lines
+                    .iter()
+                    .map(|&line| Some((generation, line.max(1) - 1, line.max(1))))
+                    .collect();
+                (format!("ref:{reference}"), resolved)
+            } else {
+                let path = source["path"].as_str().unwrap_or_default();
+                let listing = session
+                    .listing
+                    .as_ref()
+                    .filter(|listing| Path::new(path) == listing.path);
+                let resolved = lines
+                    .iter()
+                    .map(|&line| {
+                        let (pc, line) = listing?.resolve(line)?;
+                        Some((0, pc, line))
+                    })
+                    .collect();
+                (format!("path:{path}"), resolved)
+            };
+
+        session.source_breakpoints.insert(
+            key,
+            resolved
+                .iter()
+                .flatten()
+                .map(|&(generation, pc, _)| (generation, pc))
+                .collect(),
+        );
+        session.breakpoints = session
+            .source_breakpoints
+            .values()
+            .flatten()
+            .copied()
+            .collect();
+        let breakpoints: Vec<Value> = resolved
+            .iter()
+            .zip(&lines)
+            .map(|(resolved, &line)| match resolved {
+                Some((_, _, line)) => json!({ "verified": true, "line": line }),
+                None => json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "no instruction at or after this line",
+                }),
+            })
+            .collect();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn stack_trace(&mut self) -> Result<Value> {
+        let session = self.session()?;
+        let (source, line) = session.location();
+        let name = match session.memory.arrays[0].get(session.pc) {
+            Some(&code) => format!("{:08}: {}", session.pc, disassemble(code)),
+            None => format!("{:08}", session.pc),
+        };
+        Ok(json!({
+            "stackFrames": [{
+                "id": FRAME_ID,
+                "name": name,
+                "source": source,
+                "line": line,
+                "column": 1,
+            }],
+            "totalFrames": 1,
+        }))
+    }
+
+    fn variables(&mut self, args: &Value) -> Result<Value> {
+        let session = self.session()?;
+        let reference = args["variablesReference"].as_u64().unwrap_or_default();
+        let variables: Vec<Value> = match reference {
+            REGISTERS_REF => {
+                let mut variables: Vec<Value> = session
+                    .memory
+                    .regs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, reg)| {
+                        json!({
+                            "name": format!("r{i}"),
+                            "value": format!("0x{reg:08x} ({reg})"),
+                            "variablesReference": 0,
+                        })
+                    })
+                    .collect();
+                variables.push(json!({
+                    "name": "pc",
+                    "value": format!("{:08} (generation {})", session.pc, session.generation),
+                    "variablesReference": 0,
+                }));
+                variables
+            }
+            ARRAYS_REF => session
+                .memory
+                .arrays
+                .slots()
+                .enumerate()
+                .filter_map(|(id, array)| Some((id, array?)))
+                .map(|(id, array)| {
+                    let reference = ARRAY_REF_BASE + id as u64;
+                    let reference = if reference <= MAX_REF { reference } else { 0 };
+                    json!({
+                        "name": format!("{id}"),
+                        "value": format!("{} platters", array.len()),
+                        "variablesReference": reference,
+                        "indexedVariables": array.len(),
+                    })
+                })
+                .collect(),
+            reference if reference >= ARRAY_REF_BASE => {
+                let id = (reference - ARRAY_REF_BASE) as usize;
+                let Some(array) = session.memory.arrays.get(id) else {
+                    bail!("array {id} is not active");
+                };
+                let start = args["start"].as_u64().unwrap_or(0) as usize;
+                let count = args["count"]
+                    .as_u64()
+                    .map_or(DEFAULT_PLATTERS, |count| count as usize);
+                let end = array.len().min(start.saturating_add(count));
+                (start.min(end)..end)
+                    .map(|i| {
+                        json!({
+                            "name": format!("[{i}]"),
+                            "value": format!("0x{:08x}", array[i]),
+                            "variablesReference": 0,
+                        })
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn source(&mut self, args: &Value) -> Result<Value> {
+        let session = self.session()?;
+        let reference = args["sourceReference"]
+            .as_u64()
+            .or_else(|| args["source"]["sourceReference"].as_u64())
+            .unwrap_or_default();
+        if reference != session.generation + 1 {
+            bail!("the program of this source is no longer loaded");
+        }
+        let content: String = session.memory.arrays[0]
+            .iter()
+            .enumerate()
+            .map(|(pc, &code)| format!("{pc:08}: {}\n", disassemble(code)))
+            .collect();
+        Ok(json!({ "content": content }))
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        let session = self.session()?;
+        session.running = true;
+        Ok(())
+    }
+
+    fn single_step(&mut self) -> Result<Value> {
+        let session = self.session()?;
+        let stop = session.step().unwrap_or(Stop::Step);
+        self.respond_later(stop)?;
+        Ok(json!({}))
+    }
+
+    fn pause(&mut self) -> Result<Value> {
+        let session = self.session()?;
+        if session.running {
+            session.running = false;
+            self.respond_later(Stop::Pause)?;
+        }
+        Ok(json!({}))
+    }
+
+    /// Queues a line typed into the debug console as input of the program.
+    fn evaluate(&mut self, args: &Value) -> Result<Value> {
+        let session = self.session()?;
+        if args["context"] != "repl" {
+            bail!("expressions are not supported; type input in the debug console");
+        }
+        let expression = args["expression"].as_str().unwrap_or_default();
+        session.io.input.extend(expression.bytes());
+        session.io.input.push_back(b'\n');
+        Ok(json!({
+            "result": format!("queued {} bytes of input", expression.len() + 1),
+            "variablesReference": 0,
+        }))
+    }
+
+    fn respond_later(&mut self, stop: Stop) -> Result<()> {
+        self.flush_output()?;
+        self.pending_stop = Some(stop);
+        Ok(())
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value>) -> Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+        });
+        match result {
+            Ok(body) => {
+                response["success"] = json!(true);
+                response["body"] = body;
+            }
+            Err(err) => {
+                response["success"] = json!(false);
+                response["message"] = json!(format!("{err:#}"));
+            }
+        }
+        self.send(response)?;
+        if let Some(stop) = self.pending_stop.take() {
+            self.stopped(stop)?;
+        }
+        Ok(())
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, mut message: Value) -> Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let data = message.to_string();
+        write!(self.writer, "Content-Length: {}\r\n\r\n{data}", data.len())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads a message framed with a Content-Length header, or `None` at the end
+/// of input.
+fn read_message(reader: &mut impl BufRead) -> Result<Option<Value>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                len = Some(value.trim().parse::<usize>()?);
+            }
+        }
+    }
+    let Some(len) = len else {
+        bail!("message without Content-Length");
+    };
+    let mut data = vec![0; len];
+    reader.read_exact(&mut data)?;
+    Ok(Some(serde_json::from_slice(&data)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/{name}", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    fn request(command: &str, arguments: Value) -> Value {
+        json!({ "type": "request", "command": command, "arguments": arguments })
+    }
+
+    /// Serves `requests` over in-memory streams, returning the messages sent
+    /// back.
+    fn session(requests: &[Value]) -> Vec<Value> {
+        let mut input = Vec::new();
+        for (i, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(i + 1);
+            let data = request.to_string();
+            write!(input, "Content-Length: {}\r\n\r\n{data}", data.len()).unwrap();
+        }
+        let mut output = Vec::new();
+        serve(Cursor::new(input), &mut output).unwrap();
+        let mut reader = &output[..];
+        std::iter::from_fn(|| read_message(&mut reader).unwrap()).collect()
+    }
+
+    /// Returns the bodies of the successful responses to `command` in order.
+    fn responses<'a>(messages: &'a [Value], command: &str) -> Vec<&'a Value> {
+        messages
+            .iter()
+            .filter(|message| {
+                message["type"] == "response"
+                    && message["command"] == command
+                    && message["success"] == true
+            })
+            .map(|message| &message["body"])
+            .collect()
+    }
+
+    /// Returns the commands and messages of the failed responses in order.
+    fn failures(messages: &[Value]) -> Vec<(&str, &str)> {
+        messages
+            .iter()
+            .filter(|message| message["type"] == "response" && message["success"] == false)
+            .map(|message| {
+                (
+                    message["command"].as_str().unwrap(),
+                    message["message"].as_str().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the bodies of `event` events in order.
+    fn events<'a>(messages: &'a [Value], event: &str) -> Vec<&'a Value> {
+        messages
+            .iter()
+            .filter(|message| message["type"] == "event" && message["event"] == event)
+            .map(|message| &message["body"])
+            .collect()
+    }
+
+    fn output(messages: &[Value]) -> String {
+        events(messages, "output")
+            .iter()
+            .map(|body| body["output"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn read_messages() {
+        let mut reader = &b"Content-Length: 2\r\nX: y\r\n\r\n{}content-length: 4\r\n\r\nnull"[..];
+        assert_eq!(read_message(&mut reader).unwrap(), Some(json!({})));
+        assert_eq!(read_message(&mut reader).unwrap(), Some(Value::Null));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+        assert!(read_message(&mut &b"X: y\r\n\r\n{}"[..]).is_err());
+    }
+
+    #[test]
+    fn breakpoints_in_listing() {
+        let listing = testdata("debug.lst");
+        let messages = session(&[
+            request("initialize", json!({})),
+            // The listing next to the codex is found.
+            request("launch", json!({ "program": testdata("debug.um") })),
+            // Line 15 is the label of the loop, resolved to its first
+            // instruction on the next line.
+            request(
+                "setBreakpoints",
+                json!({
+                    "source": { "path": listing },
+                    "breakpoints": [{ "line": 15 }, { "line": 100 }],
+                }),
+            ),
+            request("configurationDone", json!({})),
+            request("stackTrace", json!({ "threadId": THREAD_ID })),
+            request("continue", json!({ "threadId": THREAD_ID })),
+            request("stackTrace", json!({ "threadId": THREAD_ID })),
+            request(
+                "setBreakpoints",
+                json!({ "source": { "path": listing }, "breakpoints": [] }),
+            ),
+            request("continue", json!({ "threadId": THREAD_ID })),
+            request("disconnect", json!({})),
+        ]);
+
+        assert_eq!(failures(&messages), []);
+        let breakpoints = responses(&messages, "setBreakpoints");
+        assert_eq!(
+            breakpoints[0]["breakpoints"],
+            json!([
+                { "verified": true, "line": 16 },
+                {
+                    "verified": false,
+                    "line": 100,
+                    "message": "no instruction at or after this line",
+                },
+            ])
+        );
+        let stops: Vec<&Value> = events(&messages, "stopped")
+            .iter()
+            .map(|body| &body["reason"])
+            .collect();
+        assert_eq!(stops, ["breakpoint", "breakpoint"]);
+        for frames in responses(&messages, "stackTrace") {
+            let frame = &frames["stackFrames"][0];
+            assert_eq!(
+                frame["source"],
+                json!({ "name": "debug.lst", "path": listing })
+            );
+            assert_eq!(frame["line"], 16);
+            assert!(frame["name"].as_str().unwrap().starts_with("00000013: "));
+        }
+        assert_eq!(output(&messages), "ok\n");
+        assert_eq!(events(&messages, "exited"), [&json!({ "exitCode": 0 })]);
+        assert_eq!(events(&messages, "terminated").len(), 1);
+    }
+
+    #[test]
+    fn step_and_inspect() {
+        let thread = json!({ "threadId": THREAD_ID });
+        let messages = session(&[
+            request("initialize", json!({})),
+            request(
+                "launch",
+                json!({ "program": testdata("debug.um"), "stopOnEntry": true }),
+            ),
+            request("configurationDone", json!({})),
+            request("next", thread.clone()),
+            request("stackTrace", thread.clone()),
+            request("stepIn", thread.clone()),
+            request("stackTrace", thread.clone()),
+            request("next", thread.clone()),
+            request("next", thread.clone()),
+            request("next", thread.clone()),
+            request("scopes", json!({ "frameId": FRAME_ID })),
+            request("variables", json!({ "variablesReference": REGISTERS_REF })),
+            request("variables", json!({ "variablesReference": ARRAYS_REF })),
+            request(
+                "variables",
+                json!({ "variablesReference": ARRAY_REF_BASE + 1 }),
+            ),
+            request(
+                "variables",
+                json!({ "variablesReference": ARRAY_REF_BASE + 1, "start": 1, "count": 5 }),
+            ),
+            request("disconnect", json!({})),
+        ]);
+
+        assert_eq!(failures(&messages), []);
+        let stops: Vec<&Value> = events(&messages, "stopped")
+            .iter()
+            .map(|body| &body["reason"])
+            .collect();
+        assert_eq!(stops, ["entry", "step", "step", "step", "step", "step"]);
+        // Listing lines are one past the pc, after the comment on line 1.
+        let lines: Vec<&Value> = responses(&messages, "stackTrace")
+            .iter()
+            .map(|frames| &frames["stackFrames"][0]["line"])
+            .collect();
+        assert_eq!(lines, [3, 4]);
+
+        let scopes = responses(&messages, "scopes")[0]["scopes"]
+            .as_array()
+            .unwrap();
+        let names: Vec<&Value> = scopes.iter().map(|scope| &scope["name"]).collect();
+        assert_eq!(names, ["Registers", "Arrays"]);
+
+        // After 5 instructions, array 1 holds 'o' at offset 0.
+        let variables = responses(&messages, "variables");
+        let regs = variables[0]["variables"].as_array().unwrap();
+        assert_eq!(regs.len(), 9);
+        assert_eq!(
+            regs[1],
+            json!({ "name": "r1", "value": "0x00000003 (3)", "variablesReference": 0 })
+        );
+        assert_eq!(regs[2]["value"], "0x00000001 (1)");
+        assert_eq!(regs[3]["value"], "0x0000006f (111)");
+        assert_eq!(regs[8]["name"], "pc");
+        assert_eq!(regs[8]["value"], "00000005 (generation 0)");
+        let arrays = variables[1]["variables"].as_array().unwrap();
+        let arrays: Vec<(&Value, &Value, &Value)> = arrays
+            .iter()
+            .map(|array| {
+                (
+                    &array["name"],
+                    &array["value"],
+                    &array["variablesReference"],
+                )
+            })
+            .collect();
+        assert_eq!(
+            arrays,
+            [
+                (&json!("0"), &json!("25 platters"), &json!(ARRAY_REF_BASE)),
+                (
+                    &json!("1"),
+                    &json!("3 platters"),
+                    &json!(ARRAY_REF_BASE + 1)
+                ),
+            ]
+        );
+        let platters = |body: &Value| -> Vec<(String, String)> {
+            body["variables"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|platter| {
+                    let field = |name: &str| platter[name].as_str().unwrap().to_owned();
+                    (field("name"), field("value"))
+                })
+                .collect()
+        };
+        assert_eq!(
+            platters(variables[2]),
+            [
+                ("[0]".to_owned(), "0x0000006f".to_owned()),
+                ("[1]".to_owned(), "0x00000000".to_owned()),
+                ("[2]".to_owned(), "0x00000000".to_owned()),
+            ]
+        );
+        assert_eq!(platters(variables[3]).len(), 2);
+    }
+
+    #[test]
+    fn dump_listing_fallback() {
+        let thread = json!({ "threadId": THREAD_ID });
+        // cow.um has no listing, and loads a copy of itself, which continues
+        // at pc 17.
+        let messages = session(&[
+            request("initialize", json!({})),
+            request(
+                "launch",
+                json!({ "program": testdata("cow.um"), "stopOnEntry": true }),
+            ),
+            request(
+                "setBreakpoints",
+                json!({ "source": { "sourceReference": 2 }, "breakpoints": [{ "line": 18 }] }),
+            ),
+            request("configurationDone", json!({})),
+            request("stackTrace", thread.clone()),
+            request("source", json!({ "sourceReference": 1 })),
+            request("continue", thread.clone()),
+            request("stackTrace", thread.clone()),
+            request("source", json!({ "sourceReference": 2 })),
+            request("source", json!({ "sourceReference": 1 })),
+            request("disconnect", json!({})),
+        ]);
+
+        let breakpoints = responses(&messages, "setBreakpoints");
+        assert_eq!(
+            breakpoints[0]["breakpoints"],
+            json!([{ "verified": true, "line": 18 }])
+        );
+        let frames = responses(&messages, "stackTrace");
+        assert_eq!(
+            frames[0]["stackFrames"][0]["source"],
+            json!({ "name": "cow.um (dump)", "sourceReference": 1 })
+        );
+        assert_eq!(frames[0]["stackFrames"][0]["line"], 1);
+        assert_eq!(
+            frames[1]["stackFrames"][0]["source"],
+            json!({ "name": "cow.um (generation 1)", "sourceReference": 2 })
+        );
+        assert_eq!(frames[1]["stackFrames"][0]["line"], 18);
+
+        // Sources list array 0 as `umix dump` does, while it is loaded.
+        let sources = responses(&messages, "source");
+        assert_eq!(sources.len(), 2);
+        for source in sources {
+            let content = source["content"].as_str().unwrap();
+            let lines: Vec<&str> = content.lines().collect();
+            assert!(lines[17].starts_with("00000017: "), "{content}");
+        }
+        // The program of generation 0 is gone after the reload.
+        assert_eq!(
+            failures(&messages),
+            [("source", "the program of this source is no longer loaded")]
+        );
+    }
+}
@@ -0,0 +1,790 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    error::UmError,
+    instruction::disassemble,
+    interpreter::{execute_step, fetch, StepResult},
+    io::UmIo,
+    memory::Memory,
+    watch::{parse_number, Access, Watch, WatchHit, Watchpoints},
+};
+
+const HELP: &str = "\
+commands:
+  step [N]              execute N instructions (default 1)
+  continue              run until a breakpoint, a catch, halt or a fault
+  reverse-step [N]      go back N instructions (default 1)
+  reverse-continue      go back to the previous breakpoint, watchpoint or catch
+  last-write WATCH      go back to the last write to ID[OFFSET] or rN
+  break [GEN:]PC        stop before executing PC, optionally only in program
+                        generation GEN
+  catch load            stop after LoadProgram replaces the program
+  watch WATCH           stop after an access to ID[OFFSET] or ID[START..END],
+                        optionally followed by :r, :w or :rw, or a write to rN
+  delete N              delete breakpoint N
+  unwatch N             delete watchpoint N
+  info                  list breakpoints, watchpoints and catches
+  regs                  print registers
+  disas [PC [COUNT]]    disassemble array 0 (default: around pc)
+  array ID [OFF [CNT]]  print platters of an array
+  arrays                list active arrays
+  quit                  exit the debugger
+numbers can be decimal or hexadecimal with 0x. an empty line repeats the last
+command. instructions executed again after going back read the input they read
+the first time, and their output is not shown again.";
+
+/// Number of instructions between checkpoints of the history, initially.
+const CHECKPOINT_INTERVAL: u64 = 1 << 16;
+/// Maximum number of checkpoints kept. When exceeded, every other checkpoint
+/// is dropped and the interval doubles.
+const MAX_CHECKPOINTS: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+struct Breakpoint {
+    pc: usize,
+    generation: Option<u64>,
+}
+
+/// Reason execution stopped.
+#[derive(Clone, Copy, Debug)]
+enum Stop {
+    Breakpoint(usize),
+    Watch,
+    Load { id: u32 },
+    Halt,
+    Fault(UmError),
+}
+
+/// State of the machine between instructions. Clones of it are the
+/// checkpoints of the history.
+#[derive(Clone)]
+struct Machine {
+    memory: Memory,
+    pc: usize,
+    /// Number of times LoadProgram has replaced array 0.
+    generation: u64,
+    /// Number of instructions executed.
+    insts: u64,
+    /// Number of input instructions executed.
+    inputs: usize,
+}
+
+/// I/O of the program under the debugger. Input is logged so that
+/// instructions executed again after going back in the history read the same
+/// input, and their output is dropped.
+struct HistoryIo<'a> {
+    io: &'a mut dyn UmIo,
+    log: &'a mut Vec<Option<u8>>,
+    inputs: &'a mut usize,
+    replaying: bool,
+}
+
+impl UmIo for HistoryIo<'_> {
+    fn put(&mut self, value: u8) {
+        if !self.replaying {
+            self.io.put(value);
+        }
+    }
+
+    fn get(&mut self) -> Option<u8> {
+        let value = match self.log.get(*self.inputs) {
+            Some(&value) => value,
+            None => {
+                let value = self.io.get();
+                self.log.push(value);
+                value
+            }
+        };
+        *self.inputs += 1;
+        value
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        match self.log.get(*self.inputs) {
+            Some(&value) => value,
+            None => self.io.peek(),
+        }
+    }
+}
+
+/// Interactive debugger running the machine one instruction at a time with
+/// the interpreter.
+///
+/// Commands are read from the same input as the program, so the program
+/// reads whatever follows a `step` or `continue` line when it asks for input.
+///
+/// The debugger keeps periodic checkpoints of the machine and a log of input
+/// so that it can go back in the history by re-executing from a checkpoint.
+pub struct Debugger {
+    machine: Machine,
+    io: Box<dyn UmIo>,
+    input_log: Vec<Option<u8>>,
+    /// Number of instructions executed in the furthest state reached.
+    frontier: u64,
+    checkpoints: Vec<Machine>,
+    checkpoint_interval: u64,
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    next_breakpoint: usize,
+    catch_load: bool,
+    watchpoints: Watchpoints,
+    /// Set once the program has halted or faulted.
+    finished: Option<Stop>,
+}
+
+impl Debugger {
+    pub fn new(memory: Memory, pc: usize, io: Box<dyn UmIo>) -> Self {
+        let machine = Machine {
+            memory,
+            pc,
+            generation: 0,
+            insts: 0,
+            inputs: 0,
+        };
+        Self {
+            checkpoints: vec![machine.clone()],
+            machine,
+            io,
+            input_log: Vec::new(),
+            frontier: 0,
+            checkpoint_interval: CHECKPOINT_INTERVAL,
+            breakpoints: BTreeMap::new(),
+            next_breakpoint: 1,
+            catch_load: false,
+            watchpoints: Watchpoints::new(),
+            finished: None,
+        }
+    }
+
+    /// Runs the command loop until `quit` or the end of input.
+    pub fn run(&mut self) {
+        self.print_location();
+        let mut last_line = String::new();
+        loop {
+            print!("(umdb) ");
+            let Some(mut line) = self.read_line() else {
+                println!();
+                return;
+            };
+            if line.is_empty() {
+                line = last_line.clone();
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let Some((&command, args)) = words.split_first() else {
+                continue;
+            };
+            match command {
+                "step" | "s" => self.command_step(args),
+                "continue" | "c" => {
+                    let stop = self.resume(u64::MAX);
+                    self.report(stop);
+                }
+                "reverse-step" | "rs" => self.command_reverse_step(args),
+                "reverse-continue" | "rc" => self.command_reverse_continue(),
+                "last-write" | "lw" => self.command_last_write(args),
+                "break" | "b" => self.command_break(args),
+                "catch" => match args {
+                    ["load"] => {
+                        self.catch_load = !self.catch_load;
+                        let state = if self.catch_load { "on" } else { "off" };
+                        println!("catching LoadProgram {state}");
+                    }
+                    _ => println!("usage: catch load"),
+                },
+                "delete" | "d" => match args.first().and_then(|arg| parse_number(arg)) {
+                    Some(n) if self.breakpoints.remove(&(n as usize)).is_some() => {}
+                    _ => println!("no such breakpoint"),
+                },
+                "watch" | "w" => match args.first().map(|arg| arg.parse::<Watch>()) {
+                    Some(Ok(watch)) => {
+                        let n = self.watchpoints.insert(watch.clone());
+                        println!("watchpoint {n} on {watch}");
+                    }
+                    Some(Err(err)) => println!("{err}"),
+                    None => println!("usage: watch WATCH"),
+                },
+                "unwatch" => match args.first().and_then(|arg| parse_number(arg)) {
+                    Some(n) if self.watchpoints.remove(n as usize) => {}
+                    _ => println!("no such watchpoint"),
+                },
+                "info" | "i" => self.print_breakpoints(),
+                "regs" | "r" => self.print_regs(),
+                "disas" | "l" => self.command_disas(args),
+                "array" | "x" => self.command_array(args),
+                "arrays" => self.print_arrays(),
+                "help" | "h" => println!("{HELP}"),
+                "quit" | "q" => return,
+                _ => println!("unknown command: {command} (try help)"),
+            }
+            last_line = line;
+        }
+    }
+
+    fn read_line(&mut self) -> Option<String> {
+        let mut line = Vec::new();
+        loop {
+            match self.io.get() {
+                None if line.is_empty() => return None,
+                None | Some(b'\n') => break,
+                Some(value) => line.push(value),
+            }
+        }
+        Some(String::from_utf8_lossy(&line).trim().to_owned())
+    }
+
+    /// Executes one instruction, appending the watchpoints it triggers to
+    /// `hits`. Returns a LoadProgram stop if catching them, a halt or a fault.
+    fn execute(&mut self, hits: &mut Vec<WatchHit>) -> Option<Stop> {
+        let machine = &mut self.machine;
+        let mut io = HistoryIo {
+            io: &mut *self.io,
+            log: &mut self.input_log,
+            inputs: &mut machine.inputs,
+            replaying: machine.insts < self.frontier,
+        };
+        let memory = &mut machine.memory;
+        let pc = machine.pc;
+        let result = fetch(memory, pc).and_then(|inst| {
+            if self.watchpoints.is_empty() {
+                execute_step(pc, inst, memory, &mut io)
+            } else {
+                self.watchpoints
+                    .execute_step(pc, inst, memory, &mut io, hits)
+            }
+        });
+        let stop = match result {
+            Ok(StepResult::Next) => {
+                machine.pc += 1;
+                None
+            }
+            Ok(StepResult::Jump { id, new_pc }) => {
+                machine.pc = new_pc;
+                if id == 0 {
+                    None
+                } else {
+                    machine.memory.arrays.dup0(id as usize);
+                    machine.generation += 1;
+                    self.catch_load.then_some(Stop::Load { id })
+                }
+            }
+            Ok(StepResult::Halt) => return Some(Stop::Halt),
+            Err(err) => return Some(Stop::Fault(err)),
+        };
+        machine.insts += 1;
+        if machine.insts > self.frontier {
+            self.frontier = machine.insts;
+            self.checkpoint();
+        }
+        stop
+    }
+
+    fn checkpoint(&mut self) {
+        if !self.machine.insts.is_multiple_of(self.checkpoint_interval) {
+            return;
+        }
+        self.checkpoints.push(self.machine.clone());
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            self.checkpoint_interval *= 2;
+            let interval = self.checkpoint_interval;
+            self.checkpoints
+                .retain(|checkpoint| checkpoint.insts.is_multiple_of(interval));
+        }
+    }
+
+    /// Executes one instruction, returning why execution should stop if it
+    /// should.
+    fn step(&mut self) -> Option<Stop> {
+        if let Some(stop) = self.finished {
+            return Some(stop);
+        }
+        let mut hits = Vec::new();
+        let stop = self.execute(&mut hits);
+        for hit in hits.iter() {
+            println!("{hit}");
+        }
+        if let Some(Stop::Halt | Stop::Fault(_)) = stop {
+            self.finished = stop;
+        }
+        stop.or((!hits.is_empty()).then_some(Stop::Watch))
+    }
+
+    /// Executes up to `count` instructions, stopping early at breakpoints.
+    fn resume(&mut self, count: u64) -> Option<Stop> {
+        for _ in 0..count {
+            if let Some(stop) = self.step() {
+                return Some(stop);
+            }
+            if let Some(n) = self.breakpoint_at_pc() {
+                return Some(Stop::Breakpoint(n));
+            }
+        }
+        None
+    }
+
+    /// Moves to the state after `insts` instructions, which must have been
+    /// reached before, by re-executing from a checkpoint.
+    fn travel(&mut self, insts: u64) {
+        let machine = &self.machine;
+        if !(machine.insts <= insts
+            && self
+                .checkpoints
+                .iter()
+                .all(|checkpoint| checkpoint.insts <= machine.insts || checkpoint.insts > insts))
+        {
+            let checkpoint = self
+                .checkpoints
+                .iter()
+                .rev()
+                .find(|checkpoint| checkpoint.insts <= insts)
+                .unwrap();
+            self.machine = checkpoint.clone();
+        }
+        self.finished = None;
+        let mut hits = Vec::new();
+        while self.machine.insts < insts {
+            self.execute(&mut hits);
+            hits.clear();
+        }
+    }
+
+    /// Searches the history backwards for the latest state before the current
+    /// one to stop at: after an instruction reaching a breakpoint or a caught
+    /// LoadProgram if `breakpoints` is set, or before an instruction
+    /// triggering a watchpoint. Returns the number of instructions executed in
+    /// the state and the watchpoints triggered.
+    fn search_back(&mut self, breakpoints: bool) -> Option<(u64, Vec<WatchHit>)> {
+        let current = self.machine.insts;
+        for i in (0..self.checkpoints.len()).rev() {
+            let start = self.checkpoints[i].insts;
+            if start >= current {
+                continue;
+            }
+            let end = self
+                .checkpoints
+                .get(i + 1)
+                .map_or(current, |checkpoint| checkpoint.insts.min(current));
+            self.machine = self.checkpoints[i].clone();
+            let mut found = None;
+            let mut hits = Vec::new();
+            while self.machine.insts < end {
+                let stop = self.execute(&mut hits);
+                let insts = self.machine.insts;
+                if !hits.is_empty() {
+                    found = Some((insts - 1, std::mem::take(&mut hits)));
+                }
+                if breakpoints
+                    && insts < current
+                    && (self.breakpoint_at_pc().is_some()
+                        || matches!(stop, Some(Stop::Load { .. })))
+                {
+                    found = Some((insts, Vec::new()));
+                }
+            }
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+
+    fn command_reverse_step(&mut self, args: &[&str]) {
+        let count = match args.first() {
+            Some(arg) => match parse_number(arg) {
+                Some(count) => count as u64,
+                None => {
+                    println!("invalid count: {arg}");
+                    return;
+                }
+            },
+            None => 1,
+        };
+        if self.machine.insts < count {
+            println!("reached the beginning of the history");
+        }
+        self.travel(self.machine.insts.saturating_sub(count));
+        self.print_location();
+    }
+
+    fn command_reverse_continue(&mut self) {
+        match self.search_back(true) {
+            Some((insts, hits)) => {
+                self.travel(insts);
+                for hit in hits.iter() {
+                    println!("{hit}");
+                }
+                if let Some(n) = self.breakpoint_at_pc() {
+                    println!("breakpoint {n}");
+                }
+            }
+            None => {
+                self.travel(0);
+                println!("reached the beginning of the history");
+            }
+        }
+        self.print_location();
+    }
+
+    fn command_last_write(&mut self, args: &[&str]) {
+        let watch = match args.first().map(|arg| arg.parse::<Watch>()) {
+            Some(Ok(Watch::Array { id, offsets, .. })) => Watch::Array {
+                id,
+                offsets,
+                access: Access::Write,
+            },
+            Some(Ok(watch)) => watch,
+            Some(Err(err)) => {
+                println!("{err}");
+                return;
+            }
+            None => {
+                println!("usage: last-write WATCH");
+                return;
+            }
+        };
+        let current = self.machine.insts;
+        let mut watchpoints = Watchpoints::new();
+        watchpoints.insert(watch.clone());
+        let saved = std::mem::replace(&mut self.watchpoints, watchpoints);
+        let found = self.search_back(false);
+        self.watchpoints = saved;
+        match found {
+            Some((insts, hits)) => {
+                self.travel(insts);
+                for hit in hits.iter() {
+                    println!("last {}", hit.describe());
+                }
+            }
+            None => {
+                self.travel(current);
+                println!("no write to {watch} in the history");
+            }
+        }
+        self.print_location();
+    }
+
+    fn breakpoint_at_pc(&self) -> Option<usize> {
+        self.breakpoints
+            .iter()
+            .find(|(_, bp)| {
+                bp.pc == self.machine.pc
+                    && bp.generation.is_none_or(|g| g == self.machine.generation)
+            })
+            .map(|(&n, _)| n)
+    }
+
+    fn report(&mut self, stop: Option<Stop>) {
+        match stop {
+            None => {}
+            Some(Stop::Breakpoint(n)) => println!("breakpoint {n}"),
+            Some(Stop::Watch) => {}
+            Some(Stop::Load { id }) => println!(
+                "loaded program from array {id} (generation {})",
+                self.machine.generation
+            ),
+            Some(Stop::Halt) => {
+                println!("program halted after {} instructions", self.machine.insts);
+                return;
+            }
+            Some(Stop::Fault(err)) => {
+                println!(
+                    "program faulted after {} instructions: {err}",
+                    self.machine.insts
+                );
+                return;
+            }
+        }
+        self.print_location();
+    }
+
+    fn print_location(&self) {
+        match self.machine.memory.arrays[0].get(self.machine.pc) {
+            Some(&code) => println!(
+                "=> {}:{:08}: {}",
+                self.machine.generation,
+                self.machine.pc,
+                disassemble(code)
+            ),
+            None => println!(
+                "=> {}:{:08}: <out of range>",
+                self.machine.generation, self.machine.pc
+            ),
+        }
+    }
+
+    fn command_step(&mut self, args: &[&str]) {
+        let count = match args.first() {
+            Some(arg) => match parse_number(arg) {
+                Some(count) => count as u64,
+                None => {
+                    println!("invalid count: {arg}");
+                    return;
+                }
+            },
+            None => 1,
+        };
+        let stop = self.resume(count);
+        if stop.is_none() {
+            self.print_location();
+        } else {
+            self.report(stop);
+        }
+    }
+
+    fn command_break(&mut self, args: &[&str]) {
+        let Some(arg) = args.first() else {
+            println!("usage: break [GEN:]PC");
+            return;
+        };
+        let (generation, pc) = match arg.split_once(':') {
+            Some((generation, pc)) => (parse_number(generation).map(|g| Some(g as u64)), pc),
+            None => (Some(None), *arg),
+        };
+        let (Some(generation), Some(pc)) = (generation, parse_number(pc)) else {
+            println!("invalid breakpoint: {arg}");
+            return;
+        };
+        let n = self.next_breakpoint;
+        self.next_breakpoint += 1;
+        let bp = Breakpoint {
+            pc: pc as usize,
+            generation,
+        };
+        self.breakpoints.insert(n, bp);
+        println!("breakpoint {n} at {}", format_breakpoint(bp));
+    }
+
+    fn print_breakpoints(&self) {
+        for (n, &bp) in self.breakpoints.iter() {
+            println!("breakpoint {n} at {}", format_breakpoint(bp));
+        }
+        for (n, watch) in self.watchpoints.iter() {
+            println!("watchpoint {n} on {watch}");
+        }
+        if self.catch_load {
+            println!("catching LoadProgram");
+        }
+    }
+
+    fn print_regs(&self) {
+        for (i, reg) in self.machine.memory.regs.iter().enumerate() {
+            println!("r{i}  0x{reg:08x}  {reg}");
+        }
+        println!(
+            "pc  {:08}  generation {}",
+            self.machine.pc, self.machine.generation
+        );
+        println!(
+            "executed {} instructions, {} in the history",
+            self.machine.insts, self.frontier
+        );
+    }
+
+    fn command_disas(&self, args: &[&str]) {
+        let numbers: Option<Vec<u32>> = args.iter().map(|arg| parse_number(arg)).collect();
+        let (start, count) = match numbers.as_deref() {
+            Some([]) => (self.machine.pc.saturating_sub(4), 10),
+            Some(&[pc]) => (pc as usize, 10),
+            Some(&[pc, count]) => (pc as usize, count as usize),
+            _ => {
+                println!("usage: disas [PC [COUNT]]");
+                return;
+            }
+        };
+        let program = &self.machine.memory.arrays[0];
+        let end = program.len().min(start.saturating_add(count));
+        for (pc, &code) in program.iter().enumerate().take(end).skip(start) {
+            let marker = if pc == self.machine.pc { "=>" } else { "  " };
+            println!("{marker} {pc:08}: {}", disassemble(code));
+        }
+    }
+
+    fn command_array(&self, args: &[&str]) {
+        let numbers: Option<Vec<u32>> = args.iter().map(|arg| parse_number(arg)).collect();
+        let (id, offset, count) = match numbers.as_deref() {
+            Some(&[id]) => (id, 0, 32),
+            Some(&[id, offset]) => (id, offset as usize, 32),
+            Some(&[id, offset, count]) => (id, offset as usize, count as usize),
+            _ => {
+                println!("usage: array ID [OFFSET [COUNT]]");
+                return;
+            }
+        };
+        let Some(array) = self.machine.memory.arrays.get(id as usize) else {
+            println!("array {id} is not active");
+            return;
+        };
+        let end = array.len().min(offset.saturating_add(count));
+        if offset >= end {
+            println!("array {id} has {} platters", array.len());
+            return;
+        }
+        for (i, chunk) in array[offset..end].chunks(4).enumerate() {
+            let values: Vec<String> = chunk.iter().map(|value| format!("0x{value:08x}")).collect();
+            println!("{id}[{:08}]: {}", offset + i * 4, values.join(" "));
+        }
+    }
+
+    fn print_arrays(&self) {
+        for (id, array) in self.machine.memory.arrays.slots().enumerate() {
+            if let Some(array) = array {
+                println!("array {id}: {} platters", array.len());
+            }
+        }
+    }
+}
+
+fn format_breakpoint(bp: Breakpoint) -> String {
+    match bp.generation {
+        Some(generation) => format!("{generation}:{:08}", bp.pc),
+        None => format!("{:08}", bp.pc),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{io::StreamIo, program::load_program};
+
+    use super::*;
+
+    /// Number of instructions executed, generation, pc and registers.
+    type State = (u64, u64, usize, [u32; 8]);
+
+    /// Returns a debugger of a program in testdata, taking checkpoints every
+    /// `interval` instructions at first.
+    fn debugger(program: &str, interval: u64) -> Debugger {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(program);
+        let memory = Memory::new(load_program(&path).unwrap());
+        let io = StreamIo::new(&b""[..], std::io::sink());
+        let mut debugger = Debugger::new(memory, 0, Box::new(io));
+        debugger.checkpoint_interval = interval;
+        debugger
+    }
+
+    fn state(debugger: &Debugger) -> State {
+        let machine = &debugger.machine;
+        (
+            machine.insts,
+            machine.generation,
+            machine.pc,
+            machine.memory.regs,
+        )
+    }
+
+    /// Steps until the program halts, returning the state after each number
+    /// of instructions.
+    fn run_to_halt(debugger: &mut Debugger) -> Vec<State> {
+        let mut states = vec![state(debugger)];
+        while debugger.step().is_none() {
+            states.push(state(debugger));
+        }
+        assert!(matches!(debugger.finished, Some(Stop::Halt)));
+        states
+    }
+
+    #[test]
+    fn checkpoints_thin_out() {
+        // reload.um runs 147369 instructions before halting, which take 2303
+        // checkpoints 64 instructions apart, thinned down to 36 checkpoints
+        // 4096 instructions apart.
+        let mut debugger = debugger("reload.um", 64);
+        let states = run_to_halt(&mut debugger);
+        assert_eq!(states.len(), 147370);
+        assert_eq!(debugger.checkpoint_interval, 4096);
+        let checkpoints: Vec<u64> = debugger.checkpoints.iter().map(|c| c.insts).collect();
+        let expected: Vec<u64> = (0..=147369 / 4096).map(|i| i * 4096).collect();
+        assert_eq!(checkpoints, expected);
+    }
+
+    #[test]
+    fn reverse_step() {
+        let mut debugger = debugger("reload.um", 64);
+        let states = run_to_halt(&mut debugger);
+        // Go back by growing distances, across thinned out checkpoints and
+        // LoadProgram instructions, then forward again.
+        let mut insts = states.len() as u64 - 1;
+        for count in [1, 2, 100, 4095, 4096, 4097, 30000, 54321] {
+            debugger.command_reverse_step(&[&count.to_string()]);
+            insts -= count;
+            assert_eq!(state(&debugger), states[insts as usize]);
+        }
+        debugger.command_step(&["1000"]);
+        assert_eq!(state(&debugger), states[insts as usize + 1000]);
+        debugger.command_reverse_step(&["1000000"]);
+        assert_eq!(state(&debugger), states[0]);
+    }
+
+    #[test]
+    fn reverse_continue() {
+        let mut debugger = debugger("reload.um", 64);
+        let states = run_to_halt(&mut debugger);
+        // Breakpoints stop where the pc reaches them, after the instruction
+        // before.
+        let hits: Vec<u64> = states
+            .iter()
+            .filter(|&&(_, generation, pc, _)| generation == 1 && pc == 9)
+            .map(|&(insts, ..)| insts)
+            .collect();
+        assert_eq!(hits.len(), 3000);
+        debugger.command_break(&["1:9"]);
+        for &insts in hits.iter().rev().take(3) {
+            debugger.command_reverse_continue();
+            assert_eq!(state(&debugger), states[insts as usize]);
+        }
+        debugger.breakpoints.clear();
+
+        // Caught LoadProgram instructions stop after loading the program.
+        debugger.catch_load = true;
+        debugger.travel(states.len() as u64 - 1);
+        for generation in (1..=7).rev() {
+            debugger.command_reverse_continue();
+            let insts = states.iter().position(|s| s.1 == generation).unwrap();
+            assert_eq!(state(&debugger), states[insts]);
+        }
+        debugger.command_reverse_continue();
+        assert_eq!(state(&debugger), states[0]);
+    }
+
+    #[test]
+    fn last_write() {
+        let mut debugger = debugger("debug.um", 64);
+        let states = run_to_halt(&mut debugger);
+        // Stops before the store of 'k' to 1[1] at pc 7.
+        debugger.command_last_write(&["1[1]"]);
+        assert_eq!(state(&debugger), states[7]);
+        // Searching again goes further back to the allocation of the array,
+        // which zeroes it.
+        debugger.command_last_write(&["1[1]"]);
+        assert_eq!(state(&debugger), states[1]);
+        debugger.command_step(&["1000"]);
+        // The last write to r4 is the increment at pc 15 in the third
+        // iteration of the loop, 13 + 2 * 11 + 2 instructions in.
+        debugger.command_last_write(&["r4"]);
+        assert_eq!(state(&debugger), states[37]);
+        assert_eq!(debugger.machine.pc, 15);
+        debugger.command_last_write(&["r4"]);
+        assert_eq!(state(&debugger), states[26]);
+    }
+
+    #[test]
+    fn last_write_in_history() {
+        // Each generation of reload.um counts its runs in 0[62] at pc 5.
+        let mut debugger = debugger("reload.um", 64);
+        let states = run_to_halt(&mut debugger);
+        let writes: Vec<usize> = states
+            .iter()
+            .filter(|&&(_, _, pc, _)| pc == 5)
+            .map(|&(insts, ..)| insts as usize)
+            .collect();
+        assert_eq!(writes.len(), 8);
+        for &insts in writes.iter().rev() {
+            debugger.command_last_write(&["0[62]"]);
+            assert_eq!(state(&debugger), states[insts]);
+        }
+        // No write is left, and the state does not change.
+        debugger.command_last_write(&["0[62]"]);
+        assert_eq!(state(&debugger), states[writes[0]]);
+    }
+}
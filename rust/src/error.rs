@@ -0,0 +1,94 @@
+use crate::instruction::ParsedInstruction;
+
+/// A fault that stops the Universal Machine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UmError {
+    InvalidOpcode {
+        pc: usize,
+        code: u32,
+    },
+    OutOfBounds {
+        pc: usize,
+        code: u32,
+        id: u32,
+        offset: u32,
+    },
+    InactiveArray {
+        pc: usize,
+        code: u32,
+        id: u32,
+    },
+    DivideByZero {
+        pc: usize,
+        code: u32,
+    },
+    FreeArrayZero {
+        pc: usize,
+        code: u32,
+    },
+    PcOutOfRange {
+        pc: usize,
+    },
+    /// Compiled code reported a fault on an instruction the interpreter
+    /// executed without one. This is a bug of the JIT.
+    Internal {
+        pc: usize,
+        code: u32,
+    },
+}
+
+impl UmError {
+    pub fn pc(&self) -> usize {
+        match *self {
+            Self::InvalidOpcode { pc, .. }
+            | Self::OutOfBounds { pc, .. }
+            | Self::InactiveArray { pc, .. }
+            | Self::DivideByZero { pc, .. }
+            | Self::FreeArrayZero { pc, .. }
+            | Self::PcOutOfRange { pc }
+            | Self::Internal { pc, .. } => pc,
+        }
+    }
+
+    /// Returns the raw instruction that caused the fault, if any.
+    pub fn code(&self) -> Option<u32> {
+        match *self {
+            Self::InvalidOpcode { code, .. }
+            | Self::OutOfBounds { code, .. }
+            | Self::InactiveArray { code, .. }
+            | Self::DivideByZero { code, .. }
+            | Self::FreeArrayZero { code, .. }
+            | Self::Internal { code, .. } => Some(code),
+            Self::PcOutOfRange { .. } => None,
+        }
+    }
+}
+
+impl std::fmt::Display for UmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Self::InvalidOpcode { .. } => write!(f, "invalid opcode")?,
+            Self::OutOfBounds { id, offset, .. } => {
+                write!(f, "offset {offset} is out of bounds of array {id}")?
+            }
+            Self::InactiveArray { id, .. } => write!(f, "array {id} is not active")?,
+            Self::DivideByZero { .. } => write!(f, "division by zero")?,
+            Self::FreeArrayZero { .. } => write!(f, "abandoning array 0")?,
+            Self::PcOutOfRange { .. } => write!(f, "pc is out of range of array 0")?,
+            Self::Internal { .. } => write!(
+                f,
+                "internal error: compiled code faulted on a valid instruction"
+            )?,
+        }
+        write!(f, " at {:08}", self.pc())?;
+        if let Some(code) = self.code() {
+            match ParsedInstruction::from_u32(code) {
+                Some(inst) => write!(f, ": {inst:?}")?,
+                None => write!(f, ": [0x{code:08x}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for UmError {}
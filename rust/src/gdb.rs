@@ -0,0 +1,626 @@
+use std::{
+    collections::BTreeSet,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use anyhow::{Context as _, Result};
+
+use crate::{
+    console::{Console, WaitResult},
+    error::UmError,
+    interpreter::{self, execute_step, fetch, Exit, Hooks, StepResult},
+    memory::Memory,
+};
+
+// Registers are r0..r7 and pc, 32 bits each in little endian. The pc register
+// holds the byte address of the instruction in array 0.
+//
+// Memory addresses carry the array identifier in the upper 32 bits and the
+// byte offset into the array in the lower 32 bits. Platters are exposed as
+// 4 little-endian bytes, so reading a word returns the platter value.
+//
+// Byte offsets do not reach platters from 2^30 on. Registers cannot be read
+// while the pc is there, which takes an array 0 of more than 4 GiB.
+const NUM_REGS: usize = 9;
+const PC_REG: usize = 8;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.umix.um">
+    <reg name="r0" bitsize="32" type="uint32" regnum="0"/>
+    <reg name="r1" bitsize="32" type="uint32"/>
+    <reg name="r2" bitsize="32" type="uint32"/>
+    <reg name="r3" bitsize="32" type="uint32"/>
+    <reg name="r4" bitsize="32" type="uint32"/>
+    <reg name="r5" bitsize="32" type="uint32"/>
+    <reg name="r6" bitsize="32" type="uint32"/>
+    <reg name="r7" bitsize="32" type="uint32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// Number of instructions run between checks for an interrupt from gdb.
+const INTERRUPT_CHECK_INTERVAL: u64 = 1 << 16;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// Reason execution stopped.
+enum Stop {
+    Step,
+    Breakpoint,
+    Interrupt,
+    Fault(UmError),
+    Exit(Exit),
+}
+
+/// Packet connection to gdb.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    /// Reads the next packet, or `None` when gdb disconnects. An interrupt
+    /// request is returned as a packet of the single byte 0x03.
+    fn read_packet(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let Some(c) = self.read_byte()? else {
+                return Ok(None);
+            };
+            match c {
+                b'$' => {}
+                0x03 => return Ok(Some(vec![0x03])),
+                // Acknowledgements, and garbage between packets.
+                _ => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                let Some(c) = self.read_byte()? else {
+                    return Ok(None);
+                };
+                if c == b'#' {
+                    break;
+                }
+                data.push(c);
+            }
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum)?;
+            let checksum = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+            if checksum != Some(checksum_of(&data)) {
+                self.writer.write_all(b"-")?;
+                continue;
+            }
+            self.writer.write_all(b"+")?;
+            return Ok(Some(unescape(&data)));
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> Result<()> {
+        write!(self.writer, "${data}#{:02x}", checksum_of(data.as_bytes()))?;
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut buf = [0];
+        match self.reader.read(&mut buf) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(buf[0])),
+            Err(err) if err.kind() == ErrorKind::ConnectionReset => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Returns whether gdb has asked to interrupt the running program,
+    /// without waiting.
+    fn interrupted(&mut self) -> Result<bool> {
+        loop {
+            if self.reader.buffer().is_empty() {
+                self.reader.get_ref().set_nonblocking(true)?;
+                let result = self.reader.fill_buf().map(|buf| buf.is_empty());
+                self.reader.get_ref().set_nonblocking(false)?;
+                match result {
+                    // gdb disconnected; stop to notice it.
+                    Ok(true) => return Ok(true),
+                    Err(err) if err.kind() == ErrorKind::ConnectionReset => return Ok(true),
+                    Ok(false) => {}
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            // Skip acknowledgements and garbage as read_packet does, but
+            // leave a packet to it.
+            let buf = self.reader.buffer();
+            let skip = buf
+                .iter()
+                .position(|&c| c == 0x03 || c == b'$')
+                .unwrap_or(buf.len());
+            self.reader.consume(skip);
+            match self.reader.buffer().first() {
+                Some(0x03) => {
+                    self.reader.consume(1);
+                    return Ok(true);
+                }
+                Some(_) => return Ok(false),
+                None => {}
+            }
+        }
+    }
+}
+
+/// Runs the machine under the control of gdb connecting to `addr` with the
+/// remote serial protocol, doing I/O through `console`.
+pub fn serve(addr: &str, memory: &mut Memory, pc: usize, console: &mut Console) -> Result<Exit> {
+    let listener = TcpListener::bind(addr).with_context(|| format!("listening on {addr}"))?;
+    eprintln!("umix: waiting for gdb on {}", listener.local_addr()?);
+    let (stream, peer) = listener.accept()?;
+    eprintln!("umix: gdb connected from {peer}");
+    stream.set_nodelay(true)?;
+    let mut stub = Stub {
+        conn: Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        },
+        memory,
+        pc,
+        console,
+        insts: 0,
+        breakpoints: BTreeSet::new(),
+    };
+    stub.serve()
+}
+
+struct Stub<'a> {
+    conn: Connection,
+    memory: &'a mut Memory,
+    pc: usize,
+    console: &'a mut Console,
+    insts: u64,
+    breakpoints: BTreeSet<usize>,
+}
+
+impl Stub<'_> {
+    fn serve(&mut self) -> Result<Exit> {
+        loop {
+            let Some(packet) = self.conn.read_packet()? else {
+                eprintln!("umix: gdb disconnected");
+                return self.detach();
+            };
+            let packet = String::from_utf8_lossy(&packet).into_owned();
+            let reply = match packet.as_bytes().first() {
+                Some(b'?') => stop_reply(SIGTRAP),
+                Some(b'g') => (0..NUM_REGS)
+                    .map(|n| self.reg(n).map(hex_u32))
+                    .collect::<Option<String>>()
+                    .unwrap_or_else(|| "E01".to_owned()),
+                Some(b'G') => match parse_hex_bytes(&packet[1..]) {
+                    Some(data) if data.len() == NUM_REGS * 4 => {
+                        for (n, chunk) in data.chunks_exact(4).enumerate() {
+                            self.set_reg(n, u32::from_le_bytes(chunk.try_into().unwrap()));
+                        }
+                        "OK".to_owned()
+                    }
+                    _ => "E01".to_owned(),
+                },
+                Some(b'p') => match usize::from_str_radix(&packet[1..], 16) {
+                    Ok(n) if n < NUM_REGS => self.reg(n).map_or_else(|| "E01".to_owned(), hex_u32),
+                    _ => "E01".to_owned(),
+                },
+                Some(b'P') => self
+                    .write_reg(&packet[1..])
+                    .unwrap_or_else(|| "E01".to_owned()),
+                Some(b'm') => self
+                    .read_memory(&packet[1..])
+                    .unwrap_or_else(|| "E01".to_owned()),
+                Some(b'M') => self
+                    .write_memory(&packet[1..])
+                    .unwrap_or_else(|| "E01".to_owned()),
+                Some(b'Z' | b'z') => self
+                    .set_breakpoint(&packet)
+                    .unwrap_or_else(|| "E01".to_owned()),
+                Some(b's') => {
+                    let stop = self.step().unwrap_or(Stop::Step);
+                    match self.report(stop)? {
+                        Some(exit) => return Ok(exit),
+                        None => continue,
+                    }
+                }
+                Some(b'c') => {
+                    let stop = self.resume()?;
+                    match self.report(stop)? {
+                        Some(exit) => return Ok(exit),
+                        None => continue,
+                    }
+                }
+                Some(b'k') => return Ok(Exit::Halt),
+                Some(b'D') => {
+                    self.conn.write_packet("OK")?;
+                    return self.detach();
+                }
+                Some(b'H') => "OK".to_owned(),
+                _ if packet.starts_with("qSupported") => {
+                    "PacketSize=4000;qXfer:features:read+;swbreak+".to_owned()
+                }
+                _ if packet.starts_with("qXfer:features:read:target.xml:") => read_xfer(
+                    TARGET_XML,
+                    &packet["qXfer:features:read:target.xml:".len()..],
+                )
+                .unwrap_or_else(|| "E01".to_owned()),
+                _ if packet == "qAttached" => "1".to_owned(),
+                _ if packet == "qC" => "QC1".to_owned(),
+                _ if packet == "qfThreadInfo" => "m1".to_owned(),
+                _ if packet == "qsThreadInfo" => "l".to_owned(),
+                // Includes interrupts while stopped, which need no reply.
+                _ => String::new(),
+            };
+            if packet.as_bytes() != [0x03] {
+                self.conn.write_packet(&reply)?;
+            }
+        }
+    }
+
+    /// Sends the stop reply for `stop`, returning how the run ends if the
+    /// program has exited.
+    fn report(&mut self, stop: Stop) -> Result<Option<Exit>> {
+        let (reply, exit) = match stop {
+            Stop::Step => (stop_reply(SIGTRAP), None),
+            Stop::Breakpoint => (format!("T{SIGTRAP:02x}swbreak:;"), None),
+            Stop::Interrupt => (stop_reply(SIGINT), None),
+            Stop::Fault(err) => {
+                eprintln!("umix: {err}");
+                let signal = match err {
+                    UmError::InvalidOpcode { .. } => SIGILL,
+                    UmError::DivideByZero { .. } => SIGFPE,
+                    _ => SIGSEGV,
+                };
+                (stop_reply(signal), None)
+            }
+            Stop::Exit(exit) => ("W00".to_owned(), Some(exit)),
+        };
+        self.conn.write_packet(&reply)?;
+        Ok(exit)
+    }
+
+    /// Runs the rest of the program without gdb.
+    fn detach(&mut self) -> Result<Exit> {
+        Ok(interpreter::run(
+            self.memory,
+            self.pc,
+            self.console,
+            &mut Hooks::default(),
+        )?)
+    }
+
+    /// Executes one instruction, returning why execution should stop if it
+    /// should.
+    fn step(&mut self) -> Option<Stop> {
+        let inst = match fetch(self.memory, self.pc) {
+            Ok(inst) => inst,
+            Err(err) => return Some(Stop::Fault(err)),
+        };
+        if inst.opcode() == 11 {
+            self.console.set_instruction_count(self.insts);
+            match self.console.wait_for_input(&mut self.pc, self.memory) {
+                WaitResult::Ready => {}
+                WaitResult::Reloaded => return None,
+                WaitResult::EndOfInput => {
+                    return Some(Stop::Exit(Exit::EndOfInput { pc: self.pc }))
+                }
+                WaitResult::Quit => return Some(Stop::Exit(Exit::Halt)),
+            }
+        }
+        match execute_step(self.pc, inst, self.memory, self.console) {
+            Ok(StepResult::Halt) => return Some(Stop::Exit(Exit::Halt)),
+            Ok(StepResult::Next) => self.pc += 1,
+            Ok(StepResult::Jump { id, new_pc }) => {
+                if id != 0 {
+                    self.memory.arrays.dup0(id as usize);
+                }
+                self.pc = new_pc;
+            }
+            Err(err) => return Some(Stop::Fault(err)),
+        }
+        self.insts += 1;
+        None
+    }
+
+    /// Runs until a breakpoint, an interrupt from gdb, a fault or the end of
+    /// the program.
+    fn resume(&mut self) -> Result<Stop> {
+        let mut count: u64 = 0;
+        loop {
+            if let Some(stop) = self.step() {
+                return Ok(stop);
+            }
+            if self.breakpoints.contains(&self.pc) {
+                return Ok(Stop::Breakpoint);
+            }
+            count += 1;
+            if count.is_multiple_of(INTERRUPT_CHECK_INTERVAL) && self.conn.interrupted()? {
+                return Ok(Stop::Interrupt);
+            }
+        }
+    }
+
+    /// Returns the value of register `n`, or `None` if it is the pc and its
+    /// byte address does not fit in 32 bits.
+    fn reg(&self, n: usize) -> Option<u32> {
+        if n == PC_REG {
+            self.pc
+                .checked_mul(4)
+                .and_then(|addr| u32::try_from(addr).ok())
+        } else {
+            Some(self.memory.regs[n])
+        }
+    }
+
+    fn set_reg(&mut self, n: usize, value: u32) {
+        if n == PC_REG {
+            self.pc = (value / 4) as usize;
+        } else {
+            self.memory.regs[n] = value;
+        }
+    }
+
+    fn write_reg(&mut self, args: &str) -> Option<String> {
+        let (n, value) = args.split_once('=')?;
+        let n = usize::from_str_radix(n, 16)
+            .ok()
+            .filter(|&n| n < NUM_REGS)?;
+        let value = parse_hex_bytes(value)?;
+        self.set_reg(n, u32::from_le_bytes(value.try_into().ok()?));
+        Some("OK".to_owned())
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (addr, len) = args.split_once(',')?;
+        let (addr, len) = (parse_hex(addr)?, parse_hex(len)? as usize);
+        let array = self.memory.arrays.get((addr >> 32) as usize)?;
+        let start = (addr & 0xffff_ffff) as usize;
+        // Reads past the end of the array return what is available.
+        let end = start.saturating_add(len).min(array.len() * 4);
+        if start >= end && len > 0 {
+            return None;
+        }
+        Some(
+            (start..end)
+                .map(|i| format!("{:02x}", array[i / 4].to_le_bytes()[i % 4]))
+                .collect(),
+        )
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (addr, len) = range.split_once(',')?;
+        let (addr, len) = (parse_hex(addr)?, parse_hex(len)? as usize);
+        let data = parse_hex_bytes(data).filter(|data| data.len() == len)?;
+        let id = (addr >> 32) as usize;
+        let start = (addr & 0xffff_ffff) as usize;
+        let array = self.memory.arrays.get(id)?;
+        if start.checked_add(len)? > array.len() * 4 {
+            return None;
+        }
+        let mut values: Vec<(usize, u32)> = Vec::new();
+        for (i, &byte) in (start..).zip(data.iter()) {
+            if values.last().is_none_or(|&(offset, _)| offset != i / 4) {
+                values.push((i / 4, array[i / 4]));
+            }
+            let (_, value) = values.last_mut().unwrap();
+            let mut bytes = value.to_le_bytes();
+            bytes[i % 4] = byte;
+            *value = u32::from_le_bytes(bytes);
+        }
+        for (offset, value) in values {
+            if id == 0 {
+                self.memory.arrays.store_code(offset, value);
+            } else {
+                self.memory.arrays[id][offset] = value;
+            }
+        }
+        Some("OK".to_owned())
+    }
+
+    fn set_breakpoint(&mut self, packet: &str) -> Option<String> {
+        let mut args = packet[1..].split(',');
+        if args.next()? != "0" {
+            // Only software breakpoints are supported.
+            return Some(String::new());
+        }
+        let addr = parse_hex(args.next()?)?;
+        if addr >> 32 != 0 {
+            // Breakpoints are only meaningful in array 0.
+            return None;
+        }
+        let pc = (addr / 4) as usize;
+        if packet.starts_with('Z') {
+            self.breakpoints.insert(pc);
+        } else {
+            self.breakpoints.remove(&pc);
+        }
+        Some("OK".to_owned())
+    }
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{signal:02x}")
+}
+
+/// Serves a chunk of an object requested by a `qXfer` read of `OFFSET,LENGTH`.
+fn read_xfer(object: &str, args: &str) -> Option<String> {
+    let (offset, len) = args.split_once(',')?;
+    let (offset, len) = (parse_hex(offset)? as usize, parse_hex(len)? as usize);
+    let rest = object.get(offset.min(object.len())..)?;
+    if rest.len() <= len {
+        Some(format!("l{rest}"))
+    } else {
+        Some(format!("m{}", &rest[..len]))
+    }
+}
+
+/// Returns the checksum of packet data as sent, before unescaping.
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &c| sum.wrapping_add(c))
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut iter = data.iter();
+    while let Some(&c) = iter.next() {
+        match c {
+            b'}' => {
+                if let Some(&c) = iter.next() {
+                    result.push(c ^ 0x20);
+                }
+            }
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+fn hex_u32(value: u32) -> String {
+    value
+        .to_le_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s, 16).ok()
+}
+
+fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use crate::io::StreamIo;
+
+    use super::*;
+
+    /// Returns a connection and the socket of gdb on the other end.
+    fn connect() -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let connection = Connection {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        };
+        (connection, client)
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(checksum_of(b""), 0);
+        assert_eq!(checksum_of(b"OK"), 0x9a);
+        assert_eq!(checksum_of(b"qSupported"), 0x37);
+        // The sum wraps around.
+        assert_eq!(checksum_of(&[0xff, 0x02]), 0x01);
+    }
+
+    #[test]
+    fn packets() {
+        let (mut connection, mut client) = connect();
+        // A corrupted packet is rejected and the next one accepted.
+        client.write_all(b"+$g#00$g#67\x03$X0,1:}]#f9").unwrap();
+        assert_eq!(connection.read_packet().unwrap(), Some(b"g".to_vec()));
+        assert_eq!(connection.read_packet().unwrap(), Some(vec![0x03]));
+        assert_eq!(connection.read_packet().unwrap(), Some(b"X0,1:}".to_vec()));
+        connection.write_packet("OK").unwrap();
+        drop(connection);
+
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).unwrap();
+        assert_eq!(replies, "-++$OK#9a");
+    }
+
+    #[test]
+    fn interrupts() {
+        let (mut connection, mut client) = connect();
+        // Acknowledgements before the interrupt are skipped.
+        client.write_all(b"++\x03").unwrap();
+        connection.reader.fill_buf().unwrap();
+        assert!(connection.interrupted().unwrap());
+        assert!(!connection.interrupted().unwrap());
+        // A packet is left to read_packet.
+        client.write_all(b"+$g#67").unwrap();
+        connection.reader.fill_buf().unwrap();
+        assert!(!connection.interrupted().unwrap());
+        assert_eq!(connection.read_packet().unwrap(), Some(b"g".to_vec()));
+        // Disconnection interrupts.
+        drop(client);
+        assert!(connection.interrupted().unwrap());
+    }
+
+    #[test]
+    fn pc_register() {
+        let (conn, _client) = connect();
+        let mut memory = Memory::new(vec![0; 4]);
+        let io = StreamIo::new(&b""[..], std::io::sink());
+        let mut console = Console::new(Box::new(io), None, false);
+        let mut stub = Stub {
+            conn,
+            memory: &mut memory,
+            pc: 3,
+            console: &mut console,
+            insts: 0,
+            breakpoints: BTreeSet::new(),
+        };
+        assert_eq!(stub.reg(PC_REG), Some(12));
+        stub.pc = (1 << 30) - 1;
+        assert_eq!(stub.reg(PC_REG), Some(u32::MAX - 3));
+        // Beyond 32-bit byte addresses.
+        stub.pc = 1 << 30;
+        assert_eq!(stub.reg(PC_REG), None);
+        assert_eq!(stub.write_reg("8=fcffffff").as_deref(), Some("OK"));
+        assert_eq!(stub.pc, (1 << 30) - 1);
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(hex_u32(0x12345678), "78563412");
+        assert_eq!(hex_u32(0), "00000000");
+        assert_eq!(parse_hex("ff"), Some(255));
+        assert_eq!(parse_hex("100000000"), Some(1 << 32));
+        assert_eq!(parse_hex(""), None);
+        assert_eq!(parse_hex("xyz"), None);
+        assert_eq!(parse_hex_bytes("00ff7a"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(parse_hex_bytes(""), Some(vec![]));
+        assert_eq!(parse_hex_bytes("abc"), None);
+        assert_eq!(parse_hex_bytes("zz"), None);
+    }
+
+    #[test]
+    fn unescape_data() {
+        assert_eq!(unescape(b"a}]b}\x03"), b"a}b#");
+        // A trailing escape byte is dropped.
+        assert_eq!(unescape(b"a}"), b"a");
+    }
+
+    #[test]
+    fn xfer() {
+        assert_eq!(read_xfer("abcdef", "0,4").as_deref(), Some("mabcd"));
+        assert_eq!(read_xfer("abcdef", "4,4").as_deref(), Some("lef"));
+        assert_eq!(read_xfer("abcdef", "10,4").as_deref(), Some("l"));
+        assert_eq!(read_xfer("abcdef", "x"), None);
+    }
+}
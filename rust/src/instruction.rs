@@ -42,8 +42,27 @@ impl Instruction {
     pub fn parse(self) -> Option<ParsedInstruction> {
         ParsedInstruction::from_u32(self.0)
     }
+
+    /// Returns the register this instruction writes, given the registers
+    /// before it.
+    pub fn written_register(self, regs: &[u32; 8]) -> Option<usize> {
+        match self.opcode() {
+            0 if regs[self.c()] != 0 => Some(self.a()),
+            1 | 3 | 4 | 5 | 6 => Some(self.a()),
+            8 => Some(self.b()),
+            11 => Some(self.c()),
+            13 => Some(self.imm_a()),
+            _ => None,
+        }
+    }
 }
 
+/// Mnemonics of the opcodes used in disassembly.
+pub const MNEMONICS: [&str; 14] = [
+    "cmove", "load", "store", "add", "mul", "div", "nand", "halt", "alloc", "free", "out", "in",
+    "jmp", "imm",
+];
+
 pub enum ParsedInstruction {
     ConditionalMove { a: usize, b: usize, c: usize },
     ArrayIndex { a: usize, b: usize, c: usize },
@@ -159,3 +178,11 @@ impl std::fmt::Debug for ParsedInstruction {
         }
     }
 }
+
+/// Formats a platter as an instruction, or as a raw value if it is not one.
+pub fn disassemble(code: u32) -> String {
+    match ParsedInstruction::from_u32(code) {
+        Some(inst) => format!("{inst:?}"),
+        None => format!("[0x{code:08x}]"),
+    }
+}
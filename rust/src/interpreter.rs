@@ -1,6 +1,24 @@
-use std::io::{Read as _, Write as _};
+use crate::{
+    console::{Console, WaitResult},
+    coverage::Coverage,
+    error::UmError,
+    instruction::Instruction,
+    io::UmIo,
+    memory::Memory,
+    profile::Profiler,
+    trace::{TraceRecord, Tracer},
+    watch::Watchpoints,
+};
 
-use crate::{instruction::Instruction, memory::Memory};
+/// Reason a run of the machine stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exit {
+    /// The program halted, or the user quit from the console.
+    Halt,
+    /// The program is waiting for input at `pc` after the standard input has
+    /// reached its end.
+    EndOfInput { pc: usize },
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum StepResult {
@@ -9,8 +27,41 @@ pub enum StepResult {
     Jump { id: u32, new_pc: usize },
 }
 
-pub fn execute_step(inst: Instruction, memory: &mut Memory) -> StepResult {
-    match inst.opcode() {
+/// Fetches the instruction at `pc` in array 0.
+pub fn fetch(memory: &Memory, pc: usize) -> Result<Instruction, UmError> {
+    match memory.arrays[0].get(pc) {
+        Some(&code) => Ok(Instruction::from_u32(code)),
+        None => Err(UmError::PcOutOfRange { pc }),
+    }
+}
+
+#[cold]
+fn index_error(memory: &Memory, pc: usize, inst: Instruction, id: u32, offset: u32) -> UmError {
+    let code = inst.to_u32();
+    if memory.arrays.is_active(id as usize) {
+        UmError::OutOfBounds {
+            pc,
+            code,
+            id,
+            offset,
+        }
+    } else {
+        UmError::InactiveArray { pc, code, id }
+    }
+}
+
+/// Executes the instruction `inst` at `pc`, doing I/O with `io`. On a fault,
+/// the machine state is left unchanged.
+// Inlined into the run loops, which are much slower with a call per
+// instruction.
+#[inline(always)]
+pub fn execute_step(
+    pc: usize,
+    inst: Instruction,
+    memory: &mut Memory,
+    io: &mut dyn UmIo,
+) -> Result<StepResult, UmError> {
+    let result = match inst.opcode() {
         0 => {
             if memory.regs[inst.c()] != 0 {
                 memory.regs[inst.a()] = memory.regs[inst.b()];
@@ -18,13 +69,31 @@ pub fn execute_step(inst: Instruction, memory: &mut Memory) -> StepResult {
             StepResult::Next
         }
         1 => {
-            memory.regs[inst.a()] =
-                memory.arrays[memory.regs[inst.b()] as usize][memory.regs[inst.c()] as usize];
+            let id = memory.regs[inst.b()];
+            let offset = memory.regs[inst.c()];
+            let Some(&value) = memory
+                .arrays
+                .get(id as usize)
+                .and_then(|array| array.get(offset as usize))
+            else {
+                return Err(index_error(memory, pc, inst, id, offset));
+            };
+            memory.regs[inst.a()] = value;
             StepResult::Next
         }
         2 => {
-            memory.arrays[memory.regs[inst.a()] as usize][memory.regs[inst.b()] as usize] =
-                memory.regs[inst.c()];
+            let id = memory.regs[inst.a()];
+            let offset = memory.regs[inst.b()];
+            let value = memory.regs[inst.c()];
+            match memory
+                .arrays
+                .get_mut(id as usize)
+                .and_then(|array| array.get_mut(offset as usize))
+            {
+                None => return Err(index_error(memory, pc, inst, id, offset)),
+                Some(_) if id == 0 => memory.arrays.store_code(offset as usize, value),
+                Some(cell) => *cell = value,
+            }
             StepResult::Next
         }
         3 => {
@@ -36,7 +105,13 @@ pub fn execute_step(inst: Instruction, memory: &mut Memory) -> StepResult {
             StepResult::Next
         }
         5 => {
-            memory.regs[inst.a()] = memory.regs[inst.b()] / memory.regs[inst.c()];
+            let Some(value) = memory.regs[inst.b()].checked_div(memory.regs[inst.c()]) else {
+                return Err(UmError::DivideByZero {
+                    pc,
+                    code: inst.to_u32(),
+                });
+            };
+            memory.regs[inst.a()] = value;
             StepResult::Next
         }
         6 => {
@@ -52,53 +127,173 @@ pub fn execute_step(inst: Instruction, memory: &mut Memory) -> StepResult {
         }
         9 => {
             let id = memory.regs[inst.c()];
+            let code = inst.to_u32();
+            if id == 0 {
+                return Err(UmError::FreeArrayZero { pc, code });
+            }
+            if !memory.arrays.is_active(id as usize) {
+                return Err(UmError::InactiveArray { pc, code, id });
+            }
             memory.arrays.remove(id as usize);
             StepResult::Next
         }
         10 => {
-            let value = memory.regs[inst.c()];
-            std::io::stdout()
-                .write_all(&[value as u8])
-                .expect("write error");
+            io.put(memory.regs[inst.c()] as u8);
             StepResult::Next
         }
         11 => {
-            std::io::stdout().flush().expect("flush error");
-            let mut buf = [0];
-            let size = std::io::stdin().read(&mut buf).expect("read error");
-            if size == 0 {
-                memory.regs[inst.c()] = !0;
-            } else {
-                memory.regs[inst.c()] = buf[0] as u32;
-            }
+            memory.regs[inst.c()] = io.get().map_or(!0, u32::from);
             StepResult::Next
         }
         12 => {
             let id = memory.regs[inst.b()];
             let new_pc = memory.regs[inst.c()] as usize;
+            if !memory.arrays.is_active(id as usize) {
+                return Err(UmError::InactiveArray {
+                    pc,
+                    code: inst.to_u32(),
+                    id,
+                });
+            }
             StepResult::Jump { id, new_pc }
         }
         13 => {
             memory.regs[inst.imm_a()] = inst.imm_value();
             StepResult::Next
         }
-        op => {
-            panic!("unknown opcode {op}");
+        _ => {
+            return Err(UmError::InvalidOpcode {
+                pc,
+                code: inst.to_u32(),
+            });
+        }
+    };
+    Ok(result)
+}
+
+/// Observers of executed instructions. The interpreter checks them on a
+/// slower path, and compiled code does not support them.
+#[derive(Default)]
+pub struct Hooks {
+    /// Watchpoints reported to the standard error when triggered.
+    pub watchpoints: Watchpoints,
+    pub tracer: Option<Tracer>,
+    pub profiler: Option<Profiler>,
+    pub coverage: Option<Coverage>,
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.watchpoints.is_empty()
+            && self.tracer.is_none()
+            && self.profiler.is_none()
+            && self.coverage.is_none()
+    }
+
+    /// Executes an instruction like [`execute_step`], running the observers.
+    fn execute_step(
+        &mut self,
+        pc: usize,
+        generation: u64,
+        inst: Instruction,
+        memory: &mut Memory,
+        io: &mut dyn UmIo,
+    ) -> Result<StepResult, UmError> {
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(generation, pc, memory);
+        }
+        let regs = memory.regs;
+        let result = if self.watchpoints.is_empty() {
+            execute_step(pc, inst, memory, io)?
+        } else {
+            let mut hits = Vec::new();
+            let result = self
+                .watchpoints
+                .execute_step(pc, inst, memory, io, &mut hits)?;
+            for hit in hits {
+                eprintln!("umix: {hit}");
+            }
+            result
+        };
+        if let Some(tracer) = &mut self.tracer {
+            let access = match inst.opcode() {
+                1 => Some((regs[inst.b()], regs[inst.c()])),
+                2 => Some((regs[inst.a()], regs[inst.b()])),
+                _ => None,
+            };
+            tracer.record(&TraceRecord {
+                generation,
+                pc,
+                code: inst.to_u32(),
+                write: inst
+                    .written_register(&regs)
+                    .map(|reg| (reg, memory.regs[reg])),
+                access,
+            });
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(generation, pc, inst);
         }
+        Ok(result)
     }
 }
 
-pub fn run(program: Vec<u32>) {
-    let mut memory = Memory::new(program);
-    let mut pc = 0;
+/// Runs the machine from `pc`, doing I/O through `console` and running
+/// `hooks` if any.
+pub fn run(
+    memory: &mut Memory,
+    pc: usize,
+    console: &mut Console,
+    hooks: &mut Hooks,
+) -> Result<Exit, UmError> {
+    let mut insts: u64 = 0;
+    // Pick the loop once, so that running without hooks does not check them
+    // on every instruction.
+    let result = if hooks.is_empty() {
+        run_counted::<false>(memory, pc, console, hooks, &mut insts)
+    } else {
+        run_counted::<true>(memory, pc, console, hooks, &mut insts)
+    };
+    // Keep the count even if the machine faults.
+    console.set_instruction_count(insts);
+    result
+}
+
+/// Runs the machine for `run`, counting executed instructions into `insts`.
+/// `hooks` is only used if `HOOKED` is true.
+fn run_counted<const HOOKED: bool>(
+    memory: &mut Memory,
+    mut pc: usize,
+    console: &mut Console,
+    hooks: &mut Hooks,
+    insts: &mut u64,
+) -> Result<Exit, UmError> {
+    // Number of times LoadProgram has replaced array 0.
+    let mut generation: u64 = 0;
     loop {
-        let inst = Instruction::from_u32(memory.arrays[0][pc]);
-        match execute_step(inst, &mut memory) {
-            StepResult::Halt => return,
+        let inst = fetch(memory, pc)?;
+        if inst.opcode() == 11 {
+            console.set_instruction_count(*insts);
+            match console.wait_for_input(&mut pc, memory) {
+                WaitResult::Ready => {}
+                WaitResult::Reloaded => continue,
+                WaitResult::EndOfInput => return Ok(Exit::EndOfInput { pc }),
+                WaitResult::Quit => return Ok(Exit::Halt),
+            }
+        }
+        *insts += 1;
+        let result = if HOOKED {
+            hooks.execute_step(pc, generation, inst, memory, console)?
+        } else {
+            execute_step(pc, inst, memory, console)?
+        };
+        match result {
+            StepResult::Halt => return Ok(Exit::Halt),
             StepResult::Next => pc += 1,
             StepResult::Jump { id, new_pc, .. } => {
                 if id != 0 {
                     memory.arrays.dup0(id as usize);
+                    generation += 1;
                 }
                 pc = new_pc
             }
@@ -0,0 +1,48 @@
+use std::io::{BufRead, Write};
+
+/// Input and output of the machine, used by both the interpreter and
+/// compiled code.
+pub trait UmIo {
+    /// Writes a byte output by the program.
+    fn put(&mut self, value: u8);
+
+    /// Reads a byte of input for the program. Returns `None` at the end of
+    /// input.
+    fn get(&mut self) -> Option<u8>;
+
+    /// Returns the next byte of input without consuming it, waiting for it if
+    /// needed. Returns `None` at the end of input.
+    fn peek(&mut self) -> Option<u8>;
+}
+
+/// I/O over a pair of byte streams, such as the standard input and output,
+/// files, sockets or in-memory buffers.
+pub struct StreamIo<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: BufRead, W: Write> StreamIo<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer }
+    }
+}
+
+impl<R: BufRead, W: Write> UmIo for StreamIo<R, W> {
+    fn put(&mut self, value: u8) {
+        self.writer.write_all(&[value]).expect("write error");
+    }
+
+    fn get(&mut self) -> Option<u8> {
+        let value = self.peek()?;
+        self.reader.consume(1);
+        Some(value)
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        // Let the user see any prompt before waiting for input.
+        self.writer.flush().expect("flush error");
+        let buf = self.reader.fill_buf().expect("read error");
+        buf.first().copied()
+    }
+}
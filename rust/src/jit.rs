@@ -1,109 +1,441 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 use crate::{
-    codegen::{cranelift::CraneliftCodeGen, CompiledFunc, CompiledFuncResult},
-    instruction::Instruction,
-    interpreter::{execute_step, StepResult},
-    memory::Memory,
+    codegen::{CodeGen, CodeGenContext, CompiledFunc, CompiledFuncResult},
+    console::{Console, WaitResult},
+    error::UmError,
+    interpreter::{execute_step, fetch, Exit, StepResult},
+    io::UmIo,
+    memory::{Arrays, Memory},
+    program::hash_program,
+    stats::Stats,
 };
 
 const JIT_MAX_INSTRUCTIONS: usize = 1000;
 const JIT_HOT_SPOT_THRESHOLD: usize = 100;
+const JIT_HOT_SIDE_EXIT_THRESHOLD: usize = 20;
+const JIT_SAVED_PROGRAMS: usize = 16;
 
-fn tracing_run(
+struct Trace {
+    func: CompiledFunc,
+    // Offsets in array 0 and contents of the instructions compiled into the
+    // function.
+    code: Vec<(usize, u32)>,
+}
+
+impl Trace {
+    /// Returns whether the instructions compiled into the function are those
+    /// of `program`.
+    fn matches(&self, program: &[u32]) -> bool {
+        self.code
+            .iter()
+            .all(|&(pc, code)| program.get(pc) == Some(&code))
+    }
+}
+
+/// A function compiled before the run, such as ahead of time by `umix
+/// compile`.
+pub struct PrecompiledTrace {
+    pub entry_pc: usize,
+    pub func: CompiledFunc,
+    /// Offsets in array 0 and contents of the instructions compiled into the
+    /// function.
+    pub code: Vec<(usize, u32)>,
+}
+
+/// Compiled functions keyed by their entry pc.
+///
+/// Instructions covered by compiled functions are protected in `Arrays`, so
+/// that functions are invalidated when the program modifies itself.
+#[derive(Default)]
+struct CodeCache {
+    traces: HashMap<usize, Trace>,
+    owners: HashMap<usize, Vec<usize>>,
+    // Entry points of compiled functions indexed by pc, through which
+    // compiled code jumps straight into other functions.
+    chain: Vec<*const u8>,
+}
+
+impl CodeCache {
+    fn get(&self, pc: usize) -> Option<&Trace> {
+        self.traces.get(&pc)
+    }
+
+    fn contains(&self, pc: usize) -> bool {
+        self.traces.contains_key(&pc)
+    }
+
+    fn insert(&mut self, entry_pc: usize, trace: Trace, arrays: &mut Arrays) {
+        for &(pc, _) in trace.code.iter() {
+            let owners = self.owners.entry(pc).or_default();
+            if !owners.contains(&entry_pc) {
+                owners.push(entry_pc);
+            }
+            arrays.set_code_protected(pc, true);
+        }
+        self.chain.resize(arrays[0].len(), std::ptr::null());
+        self.chain[entry_pc] = trace.func.entry();
+        self.traces.insert(entry_pc, trace);
+    }
+
+    /// Removes compiled functions covering modified instructions, and returns
+    /// their entry pcs.
+    fn invalidate(&mut self, arrays: &mut Arrays) -> Vec<usize> {
+        let mut entry_pcs = Vec::new();
+        for pc in arrays.take_modified_code() {
+            for entry_pc in self.owners.remove(&pc).unwrap_or_default() {
+                let Some(trace) = self.traces.remove(&entry_pc) else {
+                    continue;
+                };
+                self.chain[entry_pc] = std::ptr::null();
+                for (pc, _) in trace.code {
+                    if let Some(owners) = self.owners.get_mut(&pc) {
+                        owners.retain(|&owner| owner != entry_pc);
+                        if owners.is_empty() {
+                            self.owners.remove(&pc);
+                            arrays.set_code_protected(pc, false);
+                        }
+                    }
+                }
+                entry_pcs.push(entry_pc);
+            }
+            arrays.set_code_protected(pc, false);
+        }
+        entry_pcs
+    }
+
+    fn clear(&mut self) {
+        self.traces.clear();
+        self.owners.clear();
+        self.chain.clear();
+    }
+
+    /// Takes all compiled functions out, leaving the cache empty.
+    fn take(&mut self) -> Vec<(usize, Trace)> {
+        let traces = self.traces.drain().collect();
+        self.clear();
+        traces
+    }
+}
+
+/// Compiled functions of programs replaced by LoadProgram, keyed by a hash
+/// of array 0 as it was loaded, so that reloading the same program reuses
+/// its native code instead of warming up again. A program differing only in
+/// a few platters, such as data kept in array 0, reuses the functions of the
+/// last saved program of the same length that still match it.
+#[derive(Default)]
+struct SavedPrograms {
+    programs: VecDeque<SavedProgram>,
+    /// Hash of the program in array 0, computed once when it is loaded.
+    current: Option<u64>,
+}
+
+struct SavedProgram {
+    hash: u64,
+    len: usize,
+    traces: Vec<(usize, Trace)>,
+    hits: Hits,
+}
+
+impl SavedPrograms {
+    /// Saves the compiled functions of the program in array 0, forgetting the
+    /// least recently saved program if there are too many.
+    fn save(&mut self, arrays: &Arrays, cache: &mut CodeCache, hits: &mut Hits) {
+        let hash = match self.current.take() {
+            Some(hash) => hash,
+            None => hash_program(&arrays[0]),
+        };
+        self.programs.retain(|program| program.hash != hash);
+        if self.programs.len() == JIT_SAVED_PROGRAMS {
+            self.programs.pop_front();
+        }
+        self.programs.push_back(SavedProgram {
+            hash,
+            len: arrays[0].len(),
+            traces: cache.take(),
+            hits: std::mem::take(hits),
+        });
+    }
+
+    /// Restores the compiled functions saved for the program in array 0, if
+    /// any, and returns the number of them. Functions are checked against
+    /// the program instruction by instruction, in case it differs.
+    fn restore(&mut self, arrays: &mut Arrays, cache: &mut CodeCache, hits: &mut Hits) -> usize {
+        let hash = hash_program(&arrays[0]);
+        self.current = Some(hash);
+        let len = arrays[0].len();
+        let Some(index) = self
+            .programs
+            .iter()
+            .position(|program| program.hash == hash)
+            .or_else(|| self.programs.iter().rposition(|program| program.len == len))
+        else {
+            return 0;
+        };
+        let program = self.programs.remove(index).unwrap();
+        *hits = program.hits;
+        let mut restored = 0;
+        for (entry_pc, trace) in program.traces {
+            if trace.matches(&arrays[0]) {
+                cache.insert(entry_pc, trace, arrays);
+                restored += 1;
+            } else {
+                // Let the entry point warm up again.
+                hits.remove(entry_pc);
+            }
+        }
+        restored
+    }
+}
+
+/// Replaces array 0 with array `id`, switching to the compiled functions of
+/// the new program if it has been loaded before.
+fn load_program(
+    memory: &mut Memory,
+    id: usize,
+    cache: &mut CodeCache,
+    hits: &mut Hits,
+    saved: &mut SavedPrograms,
+    stats: &mut Stats,
+) {
+    saved.save(&memory.arrays, cache, hits);
+    memory.arrays.dup0(id);
+    stats.traces_reused += saved.restore(&mut memory.arrays, cache, hits) as u64;
+}
+
+/// Execution counts of pcs where tracing may start.
+#[derive(Default)]
+struct Hits {
+    /// Entries to pcs by backward jumps and program loads.
+    loops: HashMap<usize, usize>,
+    /// Exits from compiled functions to pcs without one, which are extended
+    /// by branch traces once hot.
+    side_exits: HashMap<usize, usize>,
+}
+
+impl Hits {
+    fn remove(&mut self, pc: usize) {
+        self.loops.remove(&pc);
+        self.side_exits.remove(&pc);
+    }
+
+    fn clear(&mut self) {
+        self.loops.clear();
+        self.side_exits.clear();
+    }
+}
+
+/// Drops compiled functions invalidated by self-modifying code, and lets
+/// their entry points warm up again.
+fn invalidate_modified_code(memory: &mut Memory, cache: &mut CodeCache, hits: &mut Hits) {
+    if !memory.arrays.has_modified_code() {
+        return;
+    }
+    for entry_pc in cache.invalidate(&mut memory.arrays) {
+        hits.remove(entry_pc);
+    }
+}
+
+/// Runs the machine from `start_pc` while compiling the executed
+/// instructions into a trace. A branch trace, starting at a side exit of
+/// compiled code, is kept however short it is, as it saves a return to the
+/// dispatcher on every exit.
+fn tracing_run<C: CodeGen>(
     memory: &mut Memory,
     start_pc: usize,
-    codegen: &mut CraneliftCodeGen,
-    compiled_funcs: &HashMap<usize, CompiledFunc>,
-) -> (Option<CompiledFunc>, usize) {
+    io: &mut dyn UmIo,
+    codegen: &mut C,
+    cache: &CodeCache,
+    stats: &mut Stats,
+    branch: bool,
+) -> Result<(Option<Trace>, usize), UmError> {
     let mut ctx = codegen.start_function();
 
     // Start tracing.
     let mut pc = start_pc;
     let mut insts = 0;
+    let mut traced: Vec<(usize, u32)> = Vec::new();
     while insts < JIT_MAX_INSTRUCTIONS {
-        let inst = Instruction::from_u32(memory.arrays[0][pc]);
-        // eprintln!("{:08}: {:?}", pc, inst.parse().unwrap());
+        let inst = fetch(memory, pc)?;
+        // A jump to another array replaces array 0, which is left to the
+        // dispatcher, so the trace exits before it.
+        if inst.opcode() == 12 && memory.regs[inst.b()] != 0 {
+            break;
+        }
         match inst.opcode() {
             0 => ctx.conditional_move(inst.a(), inst.b(), inst.c()),
-            1 => ctx.load(inst.a(), inst.b(), inst.c()),
-            2 => ctx.store(inst.a(), inst.b(), inst.c()),
+            1 => ctx.load(inst.a(), inst.b(), inst.c(), pc),
+            2 => ctx.store(inst.a(), inst.b(), inst.c(), pc),
             3 => ctx.add(inst.a(), inst.b(), inst.c()),
             4 => ctx.mul(inst.a(), inst.b(), inst.c()),
-            5 => ctx.div(inst.a(), inst.b(), inst.c()),
+            5 => ctx.div(inst.a(), inst.b(), inst.c(), pc),
             6 => ctx.nand(inst.a(), inst.b(), inst.c()),
             7 => break,
             8 => ctx.alloc_array(inst.b(), inst.c()),
-            9 => ctx.free_array(inst.c()),
+            9 => ctx.free_array(inst.c(), pc),
             10 => ctx.putc(inst.c()),
-            11 => ctx.getc(inst.c()),
-            12 => ctx.jump(inst.b(), inst.c(), memory.regs[inst.c()] as usize),
+            // Input is left to the interpreter, where the whole machine state
+            // is available to the host while it waits for input.
+            11 => break,
+            12 => ctx.jump(inst.b(), inst.c(), memory.regs[inst.c()] as usize, pc),
             13 => ctx.immediate(inst.imm_a(), inst.imm_value()),
             _ => break,
         }
+        traced.push((pc, inst.to_u32()));
 
-        match execute_step(inst, memory) {
+        stats.insts += 1;
+        match execute_step(pc, inst, memory, io)? {
             StepResult::Halt => break,
             StepResult::Next => pc += 1,
-            StepResult::Jump { id, new_pc } => {
-                if id != 0 {
-                    break;
-                }
-                pc = new_pc
-            }
+            StepResult::Jump { new_pc, .. } => pc = new_pc,
         }
 
         insts += 1;
 
-        if pc == start_pc || compiled_funcs.contains_key(&pc) {
+        if pc == start_pc || cache.contains(pc) {
             break;
         }
     }
 
     let compiled_func = ctx.finalize(pc);
 
-    if insts <= 3 {
-        (None, pc)
+    // The trace may have overwritten its own instructions.
+    let stale = traced
+        .iter()
+        .any(|&(pc, code)| memory.arrays[0][pc] != code);
+
+    let min_insts = if branch { 1 } else { 4 };
+    if insts < min_insts || stale {
+        Ok((None, pc))
     } else {
-        (Some(compiled_func), pc)
+        let trace = Trace {
+            func: compiled_func,
+            code: traced,
+        };
+        Ok((Some(trace), pc))
     }
 }
 
-pub fn run(program: Vec<u32>) {
-    let mut memory = Memory::new(program);
-    let mut compiled_funcs: HashMap<usize, CompiledFunc> = HashMap::new();
-    let mut hits: HashMap<usize, usize> = HashMap::new();
-    let mut codegen = CraneliftCodeGen::new();
+/// Runs the machine from `pc` with the tracing JIT, doing I/O through
+/// `console`.
+pub fn run<C: CodeGen>(
+    memory: &mut Memory,
+    pc: usize,
+    console: &mut Console,
+    codegen: C,
+) -> Result<Exit, UmError> {
+    run_precompiled(memory, pc, console, codegen, Vec::new())
+}
+
+/// Same as `run`, but starts with functions compiled for the program in
+/// array 0. Those not matching the program are dropped.
+pub fn run_precompiled<C: CodeGen>(
+    memory: &mut Memory,
+    pc: usize,
+    console: &mut Console,
+    codegen: C,
+    traces: Vec<PrecompiledTrace>,
+) -> Result<Exit, UmError> {
+    let mut stats = console.stats();
+    let result = dispatch(memory, pc, console, codegen, traces, &mut stats);
+    // Keep the counts even if the machine faults.
+    console.set_stats(stats);
+    result
+}
+
+/// Runs the machine for `run_precompiled`, counting into `stats`.
+fn dispatch<C: CodeGen>(
+    memory: &mut Memory,
+    mut pc: usize,
+    console: &mut Console,
+    mut codegen: C,
+    traces: Vec<PrecompiledTrace>,
+    stats: &mut Stats,
+) -> Result<Exit, UmError> {
+    let mut cache = CodeCache::default();
+    for precompiled in traces {
+        let trace = Trace {
+            func: precompiled.func,
+            code: precompiled.code,
+        };
+        if trace.matches(&memory.arrays[0]) {
+            cache.insert(precompiled.entry_pc, trace, &mut memory.arrays);
+        }
+    }
+    let mut hits = Hits::default();
+    let mut saved = SavedPrograms::default();
 
-    let mut pc = 0;
     loop {
+        let mut side_exit = false;
+
         // Run the JIT function if it exists.
-        while let Some(jit_func) = compiled_funcs.get(&pc) {
-            match jit_func(&mut memory) {
+        while let Some(trace) = cache.get(pc) {
+            let (result, chains) = trace
+                .func
+                .call(memory, console, &mut stats.insts, &cache.chain);
+            stats.trace_entries += 1;
+            stats.trace_chains += chains;
+            invalidate_modified_code(memory, &mut cache, &mut hits);
+            match result {
                 CompiledFuncResult::Ok { pc: new_pc } => {
+                    stats.trace_exits += 1;
+                    side_exit = true;
                     pc = new_pc as usize;
                 }
                 CompiledFuncResult::Jump { id, new_pc } => {
+                    side_exit = false;
                     if id != 0 {
-                        hits.clear();
-                        compiled_funcs.clear();
-                        memory.arrays.dup0(id as usize);
+                        load_program(
+                            memory,
+                            id as usize,
+                            &mut cache,
+                            &mut hits,
+                            &mut saved,
+                            stats,
+                        );
                     }
                     pc = new_pc as usize;
                 }
-                CompiledFuncResult::Halt => return,
+                CompiledFuncResult::Halt => return Ok(Exit::Halt),
+                CompiledFuncResult::Fault { pc } => {
+                    // Compiled code bails out before executing the faulting
+                    // instruction, so let the interpreter report the fault.
+                    let pc = pc as usize;
+                    let inst = fetch(memory, pc)?;
+                    stats.insts += 1;
+                    return match execute_step(pc, inst, memory, console) {
+                        Err(err) => Err(err),
+                        Ok(_) => Err(UmError::Internal {
+                            pc,
+                            code: inst.to_u32(),
+                        }),
+                    };
+                }
             }
         }
 
-        // This is a good candidate for tracing.
+        // This is a good candidate for tracing. Side exits get hot sooner, as
+        // compiled code has already proven hot around them.
         {
-            let count = hits.entry(pc).or_insert(0);
+            let (counts, threshold) = if side_exit {
+                (&mut hits.side_exits, JIT_HOT_SIDE_EXIT_THRESHOLD)
+            } else {
+                (&mut hits.loops, JIT_HOT_SPOT_THRESHOLD)
+            };
+            let count = counts.entry(pc).or_insert(0);
             *count += 1;
-            if *count == JIT_HOT_SPOT_THRESHOLD {
-                let (compiled_func, new_pc) =
-                    tracing_run(&mut memory, pc, &mut codegen, &compiled_funcs);
-                if let Some(compiled_func) = compiled_func {
-                    compiled_funcs.insert(pc, compiled_func);
+            if *count == threshold {
+                let (trace, new_pc) =
+                    tracing_run(memory, pc, console, &mut codegen, &cache, stats, side_exit)?;
+                invalidate_modified_code(memory, &mut cache, &mut hits);
+                if let Some(trace) = trace {
+                    stats.traces_compiled += 1;
+                    if side_exit {
+                        stats.branch_traces_compiled += 1;
+                    }
+                    // Other traces exiting to the pc now jump straight into
+                    // the new one through the chain table.
+                    cache.insert(pc, trace, &mut memory.arrays);
                 }
                 pc = new_pc;
                 // Try the newly compiled function.
@@ -112,17 +444,40 @@ pub fn run(program: Vec<u32>) {
         }
 
         // Run the interpreter.
-        while !compiled_funcs.contains_key(&pc) {
-            let inst = Instruction::from_u32(memory.arrays[0][pc]);
-            match execute_step(inst, &mut memory) {
-                StepResult::Halt => return,
-                StepResult::Next => pc += 1,
+        while !cache.contains(pc) {
+            let inst = fetch(memory, pc)?;
+            if inst.opcode() == 11 {
+                console.set_stats(*stats);
+                match console.wait_for_input(&mut pc, memory) {
+                    WaitResult::Ready => {}
+                    WaitResult::Reloaded => {
+                        hits.clear();
+                        cache.clear();
+                        saved = SavedPrograms::default();
+                        continue;
+                    }
+                    WaitResult::EndOfInput => return Ok(Exit::EndOfInput { pc }),
+                    WaitResult::Quit => return Ok(Exit::Halt),
+                }
+            }
+            stats.insts += 1;
+            match execute_step(pc, inst, memory, console)? {
+                StepResult::Halt => return Ok(Exit::Halt),
+                StepResult::Next => {
+                    invalidate_modified_code(memory, &mut cache, &mut hits);
+                    pc += 1
+                }
                 StepResult::Jump { id, new_pc } => {
                     let tracing_candidate = id != 0 || new_pc < pc;
                     if id != 0 {
-                        memory.arrays.dup0(id as usize);
-                        hits.clear();
-                        compiled_funcs.clear();
+                        load_program(
+                            memory,
+                            id as usize,
+                            &mut cache,
+                            &mut hits,
+                            &mut saved,
+                            stats,
+                        );
                     }
                     pc = new_pc;
                     if tracing_candidate {
@@ -1,14 +1,51 @@
-use std::path::{Path, PathBuf};
+use std::{
+    fs::File,
+    io::{BufWriter, IsTerminal as _, Write as _},
+    ops::Range,
+    path::PathBuf,
+    time::Duration,
+};
 
-use anyhow::Result;
-use clap::Parser as _;
-use instruction::ParsedInstruction;
+use anyhow::{bail, ensure, Context as _, Result};
+use aot::Image;
+use clap::{ArgMatches, CommandFactory as _, FromArgMatches as _};
+use codegen::cranelift::CraneliftCodeGen;
+#[cfg(feature = "llvm")]
+use codegen::llvm::LlvmCodeGen;
+use console::{Console, PASTE_CAPACITY};
+use coverage::Coverage;
+use debugger::Debugger;
+use instruction::{disassemble, Instruction, ParsedInstruction, MNEMONICS};
+use interpreter::{Exit, Hooks};
+use io::StreamIo;
+use memory::Memory;
+use profile::{Profiler, Weight};
+use program::load_program;
+use replay::InputRecorder;
+use snapshot::Format;
+use trace::{TraceReader, Tracer};
+use watch::Watch;
 
+mod aot;
 mod codegen;
+mod console;
+mod coverage;
+mod dap;
+mod debugger;
+mod error;
+mod gdb;
 mod instruction;
 mod interpreter;
+mod io;
 mod jit;
 mod memory;
+mod profile;
+mod program;
+mod replay;
+mod snapshot;
+mod stats;
+mod trace;
+mod watch;
 
 #[derive(clap::Parser, Debug)]
 struct Args {
@@ -16,10 +53,48 @@ struct Args {
     command: Command,
 }
 
+/// Runs the program compiled into this executable.
+#[derive(clap::Parser, Debug)]
+struct ImageArgs {
+    #[command(flatten)]
+    console: ConsoleArgs,
+}
+
 #[derive(clap::Subcommand, Debug)]
 enum Command {
     Run(RunArgs),
     Dump(DumpArgs),
+    /// Compiles a codex ahead of time into an executable, which is a copy of
+    /// umix carrying the program and the machine code of its traces. Code
+    /// that could not be compiled runs in the JIT.
+    Compile(CompileArgs),
+    /// Runs a codex under an interactive debugger.
+    Debug(DebugArgs),
+    /// Serves the Debug Adapter Protocol over the standard input and output.
+    Dap,
+    /// Runs a codex in the interpreter and reports where it spends time.
+    Profile(ProfileArgs),
+    /// Prints instructions recorded by run --trace.
+    TraceView(TraceViewArgs),
+    #[clap(subcommand)]
+    Snapshot(SnapshotCommand),
+    #[clap(subcommand)]
+    Coverage(CoverageCommand),
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum SnapshotCommand {
+    /// Prints the machine state saved in a snapshot.
+    Info(SnapshotInfoArgs),
+    /// Converts a snapshot to another format, e.g. to migrate sessions of the
+    /// C implementation.
+    Convert(SnapshotConvertArgs),
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum CoverageCommand {
+    /// Combines the coverage of several runs of the same codex.
+    Merge(CoverageMergeArgs),
 }
 
 #[derive(Clone, Copy, Debug, clap::ValueEnum)]
@@ -28,46 +103,541 @@ enum RunMode {
     Interpreter,
 }
 
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum Backend {
+    Cranelift,
+    #[cfg(feature = "llvm")]
+    Llvm,
+}
+
 #[derive(clap::Args, Debug)]
 struct RunArgs {
     #[arg(long, default_value = "jit")]
     mode: RunMode,
 
-    codex: PathBuf,
+    #[arg(long, default_value = "cranelift")]
+    backend: Backend,
+
+    /// Resumes the machine from a snapshot instead of booting a codex.
+    #[arg(long, conflicts_with = "codex")]
+    resume: Option<PathBuf>,
+
+    #[command(flatten)]
+    console: ConsoleArgs,
+
+    /// Reports accesses to array platters or registers, written as
+    /// ID[OFFSET] or ID[START..END] optionally followed by :r, :w or :rw, or
+    /// as rN. Can be repeated.
+    #[arg(long, value_name = "WATCH")]
+    watch: Vec<Watch>,
+
+    /// Records every executed instruction to a trace file, which can be
+    /// read with trace-view.
+    #[arg(long, value_name = "FILE")]
+    trace: Option<PathBuf>,
+
+    /// Saves bitmaps of the executed instructions of each program loaded
+    /// into array 0, which can be shown with dump --coverage.
+    #[arg(long, value_name = "FILE")]
+    coverage: Option<PathBuf>,
+
+    /// Waits for gdb to connect to a TCP address and runs the program under
+    /// its control with the remote serial protocol.
+    #[arg(long, value_name = "ADDR", conflicts_with_all = ["watch", "trace", "coverage"])]
+    gdb: Option<String>,
+
+    #[arg(required_unless_present = "resume")]
+    codex: Option<PathBuf>,
+}
+
+/// Options of the console and the run shared by run and compiled
+/// executables.
+#[derive(clap::Args, Debug)]
+struct ConsoleArgs {
+    /// Saves a snapshot and exits when the program waits for input after the
+    /// standard input has reached its end.
+    #[arg(long)]
+    save_on_eof: Option<PathBuf>,
+
+    /// Byte that enters the host console at the beginning of input. An empty
+    /// value disables the console.
+    #[arg(long, default_value = "!", value_parser = parse_escape)]
+    escape: Escape,
+
+    /// Feeds the contents of a file as input ahead of the standard input, as
+    /// if it were pasted. Can be repeated.
+    #[arg(long, value_name = "FILE")]
+    input: Vec<PathBuf>,
+
+    /// Feeds a string as input ahead of the standard input, as if it were
+    /// pasted. Can be repeated, also together with --input.
+    #[arg(long, value_name = "STRING")]
+    input_str: Vec<String>,
+
+    /// Records input delivered to the program, timestamped with instruction
+    /// counts, to a log.
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Delivers input from a log made by --record before any other input,
+    /// reporting if the run diverges from the recorded one.
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+
+    /// Prints statistics of the run to the standard error when the machine
+    /// stops.
+    #[arg(long)]
+    stats: bool,
+
+    /// Format of the snapshot saved by --save-on-eof.
+    #[arg(long, default_value = "umix")]
+    save_format: Format,
 }
 
 #[derive(clap::Args, Debug)]
 struct DumpArgs {
+    /// Marks instructions executed in runs saved by run --coverage with `+`
+    /// and the others with `-`. Without a codex, lists the program loaded in
+    /// the generation instead.
+    #[arg(long, value_name = "FILE")]
+    coverage: Option<PathBuf>,
+
+    /// Generation of the program in the coverage, counted by LoadProgram
+    /// instructions replacing array 0.
+    #[arg(long, default_value_t = 0, requires = "coverage")]
+    generation: u64,
+
+    #[arg(required_unless_present = "coverage")]
+    codex: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct CompileArgs {
+    #[arg(short, long, value_name = "FILE")]
+    output: PathBuf,
+
+    codex: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct DebugArgs {
     codex: PathBuf,
 }
 
-fn load_program(path: &Path) -> Result<Vec<u32>> {
-    let data = std::fs::read(path)?;
-    let program: Vec<u32> = data
-        .chunks_exact(4)
-        .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
-        .collect();
-    Ok(program)
+#[derive(clap::Args, Debug)]
+struct ProfileArgs {
+    /// Interval in milliseconds between samples of the wall-clock time.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    interval: u64,
+
+    /// Number of rows in the tables of hot basic blocks and instructions.
+    #[arg(long, default_value_t = 20)]
+    top: usize,
+
+    /// Writes the report to a file instead of the standard error.
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Writes folded stacks for flame graph tools to a file.
+    #[arg(long, value_name = "FILE")]
+    folded: Option<PathBuf>,
+
+    /// Quantity weighing the folded stacks.
+    #[arg(long, default_value = "instructions")]
+    weight: Weight,
+
+    codex: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct TraceViewArgs {
+    /// Shows only instructions at a pc, or in a range START..END.
+    #[arg(long, value_parser = parse_pc_range)]
+    pc: Option<Range<usize>>,
+
+    /// Shows only instructions with a mnemonic such as load or store. Can be
+    /// repeated.
+    #[arg(long, value_parser = parse_opcode)]
+    opcode: Vec<u32>,
+
+    /// Shows only instructions of the program of a generation, counted by
+    /// LoadProgram instructions replacing array 0.
+    #[arg(long)]
+    generation: Option<u64>,
+
+    trace: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct CoverageMergeArgs {
+    #[arg(short, long, value_name = "FILE")]
+    output: PathBuf,
+
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct SnapshotInfoArgs {
+    snapshot: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct SnapshotConvertArgs {
+    #[arg(long)]
+    format: Format,
+
+    input: PathBuf,
+    output: PathBuf,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Escape(Option<u8>);
+
+fn parse_escape(s: &str) -> Result<Escape, String> {
+    match s.as_bytes() {
+        [] => Ok(Escape(None)),
+        &[c] => Ok(Escape(Some(c))),
+        _ => Err("must be a single byte".to_owned()),
+    }
+}
+
+fn parse_pc_range(s: &str) -> Result<Range<usize>, String> {
+    let parse = |s: &str| s.parse::<usize>().map_err(|err| err.to_string());
+    match s.split_once("..") {
+        Some((start, end)) => Ok(parse(start)?..parse(end)?),
+        None => {
+            let pc = parse(s)?;
+            Ok(pc..pc + 1)
+        }
+    }
+}
+
+fn parse_opcode(s: &str) -> Result<u32, String> {
+    match MNEMONICS.iter().position(|&mnemonic| mnemonic == s) {
+        Some(opcode) => Ok(opcode as u32),
+        None => Err(format!("must be one of {}", MNEMONICS.join(", "))),
+    }
+}
+
+/// Reads the --input and --input-str sources in the order they appear on the
+/// command line.
+fn read_inputs(args: &ConsoleArgs, matches: &ArgMatches) -> Result<Vec<u8>> {
+    let mut sources: Vec<(usize, Vec<u8>)> = Vec::new();
+    let indices = matches.indices_of("input").into_iter().flatten();
+    for (index, path) in indices.zip(&args.input) {
+        let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        sources.push((index, data));
+    }
+    let indices = matches.indices_of("input_str").into_iter().flatten();
+    for (index, input) in indices.zip(&args.input_str) {
+        sources.push((index, input.as_bytes().to_vec()));
+    }
+    sources.sort_by_key(|&(index, _)| index);
+    Ok(sources.into_iter().flat_map(|(_, data)| data).collect())
+}
+
+/// Queues the --input and --input-str sources, which must fit in the paste
+/// buffer together with any input pasted in a resumed session.
+fn feed_inputs(console: &mut Console, input: &[u8]) -> Result<()> {
+    ensure!(
+        console.feed_paste(input) == input.len(),
+        "input exceeds the paste buffer of {PASTE_CAPACITY} bytes"
+    );
+    Ok(())
+}
+
+/// Creates the console on the standard input and output.
+fn open_console(args: &ConsoleArgs) -> Result<Console> {
+    let io = StreamIo::new(std::io::stdin().lock(), std::io::stdout());
+    let mut console = Console::new(Box::new(io), args.escape.0, args.save_on_eof.is_some());
+    if let Some(path) = &args.replay {
+        console.set_replay(replay::load(path)?);
+    }
+    if let Some(path) = &args.record {
+        console.set_recorder(InputRecorder::create(path)?);
+    }
+    Ok(console)
+}
+
+/// Reports the end of a run, and saves a snapshot if it stopped at the end
+/// of input.
+fn finish_run(
+    args: &ConsoleArgs,
+    console: &Console,
+    memory: &Memory,
+    result: Result<Exit>,
+) -> Result<()> {
+    if args.stats {
+        eprint!("{}", console.stats().report(&memory.arrays));
+    }
+    let exit = result?;
+    if let (Exit::EndOfInput { pc }, Some(path)) = (exit, &args.save_on_eof) {
+        snapshot::save(
+            path,
+            args.save_format,
+            pc,
+            memory,
+            &console.backlog(),
+            &console.paste(),
+        )?;
+        eprintln!("umix: snapshot saved to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Runs the program of an executable made by compile.
+fn run_image(image: Image) -> Result<()> {
+    let matches = ImageArgs::command().try_get_matches()?;
+    let args = ImageArgs::from_arg_matches(&matches)?;
+    let input = read_inputs(&args.console, &matches)?;
+    let mut console = open_console(&args.console)?;
+    feed_inputs(&mut console, &input)?;
+    let mut memory = image.boot();
+    let result = image.run(&mut memory, &mut console);
+    finish_run(&args.console, &console, &memory, result)
 }
 
 fn main() -> Result<()> {
-    let args = Args::try_parse()?;
+    // Executables made by compile run their own program.
+    if let Some(image) = Image::embedded()? {
+        return run_image(image);
+    }
+    let matches = Args::command().try_get_matches()?;
+    let args = Args::from_arg_matches(&matches)?;
     match args.command {
         Command::Run(args) => {
-            let program = load_program(&args.codex)?;
-            match args.mode {
-                RunMode::Jit => jit::run(program),
-                RunMode::Interpreter => interpreter::run(program),
+            let input = read_inputs(&args.console, matches.subcommand_matches("run").unwrap())?;
+            let mut console = open_console(&args.console)?;
+            let (pc, mut memory) = match (&args.resume, &args.codex) {
+                (Some(path), _) => {
+                    let snapshot = snapshot::load(path)?;
+                    console.set_backlog(&snapshot.backlog);
+                    // Input pasted in the saved session comes first.
+                    console.feed_paste(&snapshot.paste);
+                    // Redraw the screen of the saved session.
+                    if std::io::stdout().is_terminal() {
+                        console.redraw();
+                    }
+                    (snapshot.pc, snapshot.memory)
+                }
+                (None, Some(path)) => (0, Memory::new(load_program(path)?)),
+                (None, None) => unreachable!(),
+            };
+            feed_inputs(&mut console, &input)?;
+            let mut hooks = Hooks::default();
+            for watch in args.watch {
+                hooks.watchpoints.insert(watch);
+            }
+            if let Some(path) = &args.trace {
+                hooks.tracer = Some(Tracer::create(path)?);
+            }
+            if args.coverage.is_some() {
+                hooks.coverage = Some(Coverage::new());
+            }
+            let mut mode = args.mode;
+            if matches!(mode, RunMode::Jit) && !hooks.watchpoints.is_empty() {
+                // Compiled code does not check watchpoints.
+                eprintln!("umix: watchpoints are armed; running in the interpreter");
+                mode = RunMode::Interpreter;
+            }
+            if matches!(mode, RunMode::Jit) && hooks.tracer.is_some() {
+                // Compiled code does not trace instructions.
+                eprintln!("umix: tracing instructions; running in the interpreter");
+                mode = RunMode::Interpreter;
+            }
+            if matches!(mode, RunMode::Jit) && hooks.coverage.is_some() {
+                eprintln!("umix: measuring coverage; running in the interpreter");
+                mode = RunMode::Interpreter;
+            }
+            if matches!(mode, RunMode::Jit) && args.gdb.is_some() {
+                eprintln!("umix: debugging with gdb; running in the interpreter");
+                mode = RunMode::Interpreter;
             }
+            // Report the run even if the program faults.
+            let result = match (mode, &args.gdb) {
+                (_, Some(addr)) => gdb::serve(addr, &mut memory, pc, &mut console),
+                (RunMode::Jit, None) => match args.backend {
+                    Backend::Cranelift => {
+                        jit::run(&mut memory, pc, &mut console, CraneliftCodeGen::new())
+                            .map_err(Into::into)
+                    }
+                    #[cfg(feature = "llvm")]
+                    Backend::Llvm => jit::run(&mut memory, pc, &mut console, LlvmCodeGen::new())
+                        .map_err(Into::into),
+                },
+                (RunMode::Interpreter, None) => {
+                    interpreter::run(&mut memory, pc, &mut console, &mut hooks).map_err(Into::into)
+                }
+            };
+            if let Some(tracer) = hooks.tracer {
+                tracer.finish()?;
+            }
+            if let (Some(coverage), Some(path)) = (&hooks.coverage, &args.coverage) {
+                coverage.save(path)?;
+            }
+            finish_run(&args.console, &console, &memory, result)?;
         }
         Command::Dump(args) => {
-            let program = load_program(&args.codex)?;
-            for (pc, code) in program.into_iter().enumerate() {
-                match ParsedInstruction::from_u32(code) {
-                    Some(inst) => println!("{pc:08}: {inst:?}"),
-                    None => println!("{pc:08}: [0x{code:08x}]"),
+            let coverage = match &args.coverage {
+                Some(path) => {
+                    let coverage = Coverage::load(path)?;
+                    let program = coverage.program(args.generation).with_context(|| {
+                        format!(
+                            "no program of generation {} in the coverage",
+                            args.generation
+                        )
+                    })?;
+                    Some(program.clone())
+                }
+                None => None,
+            };
+            let program = match (&args.codex, &coverage) {
+                (Some(path), _) => load_program(path)?,
+                (None, Some(coverage)) => coverage.program.to_vec(),
+                (None, None) => unreachable!(),
+            };
+            if coverage
+                .as_ref()
+                .is_some_and(|coverage| *coverage.program != *program)
+            {
+                bail!(
+                    "the coverage of generation {} is of another program",
+                    args.generation
+                );
+            }
+            for (pc, code) in program.iter().enumerate() {
+                match &coverage {
+                    Some(coverage) => {
+                        let mark = if coverage.is_executed(pc) { '+' } else { '-' };
+                        println!("{mark} {pc:08}: {}", disassemble(*code));
+                    }
+                    None => println!("{pc:08}: {}", disassemble(*code)),
                 }
             }
+            if let Some(coverage) = &coverage {
+                eprintln!(
+                    "umix: {} of {} instructions executed",
+                    coverage.count(),
+                    coverage.program.len()
+                );
+            }
+        }
+        Command::Compile(args) => {
+            let image = Image::compile(load_program(&args.codex)?);
+            image.save_executable(&args.output)?;
+            let (traces, insts) = image.size();
+            eprintln!(
+                "umix: compiled {traces} traces of {insts} instructions to {}",
+                args.output.display()
+            );
+        }
+        Command::Debug(args) => {
+            let memory = Memory::new(load_program(&args.codex)?);
+            let io = StreamIo::new(std::io::stdin().lock(), std::io::stdout());
+            Debugger::new(memory, 0, Box::new(io)).run();
+        }
+        Command::Dap => dap::run()?,
+        Command::Profile(args) => {
+            let mut memory = Memory::new(load_program(&args.codex)?);
+            let io = StreamIo::new(std::io::stdin().lock(), std::io::stdout());
+            let mut console = Console::new(Box::new(io), None, false);
+            let mut hooks = Hooks {
+                profiler: Some(Profiler::new(Duration::from_millis(args.interval))),
+                ..Hooks::default()
+            };
+            // Report the profile even if the program faults.
+            let result = interpreter::run(&mut memory, 0, &mut console, &mut hooks);
+            let profiler = hooks.profiler.unwrap();
+            match &args.output {
+                Some(path) => {
+                    let mut w = BufWriter::new(File::create(path)?);
+                    profiler.write_report(&mut w, args.top)?;
+                    w.flush()?;
+                }
+                None => profiler.write_report(&mut std::io::stderr().lock(), args.top)?,
+            }
+            if let Some(path) = &args.folded {
+                let mut w = BufWriter::new(File::create(path)?);
+                profiler.write_folded(&mut w, args.weight)?;
+                w.flush()?;
+            }
+            result?;
+        }
+        Command::TraceView(args) => {
+            let mut out = BufWriter::new(std::io::stdout().lock());
+            for record in TraceReader::open(&args.trace)? {
+                let record = record?;
+                let inst = Instruction::from_u32(record.code);
+                if args.pc.as_ref().is_some_and(|pc| !pc.contains(&record.pc))
+                    || (!args.opcode.is_empty() && !args.opcode.contains(&inst.opcode()))
+                    || args.generation.is_some_and(|g| g != record.generation)
+                {
+                    continue;
+                }
+                let mut line = format!(
+                    "{}:{:08}: {:<24}",
+                    record.generation,
+                    record.pc,
+                    disassemble(record.code)
+                );
+                if let Some((reg, value)) = record.write {
+                    line += &format!(" r{reg}=0x{value:08x}");
+                }
+                if let Some((id, offset)) = record.access {
+                    line += &format!(" {id}[{offset}]");
+                }
+                writeln!(out, "{}", line.trim_end())?;
+            }
+            out.flush()?;
+        }
+        Command::Coverage(CoverageCommand::Merge(args)) => {
+            let mut coverage = Coverage::new();
+            for path in args.inputs.iter() {
+                coverage
+                    .merge(&Coverage::load(path)?)
+                    .with_context(|| format!("merging {}", path.display()))?;
+            }
+            coverage.save(&args.output)?;
+        }
+        Command::Snapshot(SnapshotCommand::Info(args)) => {
+            let snapshot::Snapshot {
+                pc,
+                memory,
+                backlog,
+                paste,
+            } = snapshot::load(&args.snapshot)?;
+            match memory.arrays[0]
+                .get(pc)
+                .and_then(|&code| ParsedInstruction::from_u32(code))
+            {
+                Some(inst) => println!("pc: {pc:08}: {inst:?}"),
+                None => println!("pc: {pc:08}"),
+            }
+            for (i, reg) in memory.regs.iter().enumerate() {
+                println!("r{i}: 0x{reg:08x}");
+            }
+            let arrays: Vec<&[u32]> = memory.arrays.slots().flatten().collect();
+            let platters: usize = arrays.iter().map(|array| array.len()).sum();
+            println!(
+                "arrays: {} active, {} slots, {} platters",
+                arrays.len(),
+                memory.arrays.num_slots(),
+                platters
+            );
+            println!("backlog: {} bytes", backlog.len());
+            println!("paste: {} bytes", paste.len());
+        }
+        Command::Snapshot(SnapshotCommand::Convert(args)) => {
+            let snapshot = snapshot::load(&args.input)?;
+            snapshot::save(
+                &args.output,
+                args.format,
+                snapshot.pc,
+                &snapshot.memory,
+                &snapshot.backlog,
+                &snapshot.paste,
+            )?;
         }
     }
 
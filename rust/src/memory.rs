@@ -1,35 +1,143 @@
 use std::ops::{Index, IndexMut};
 
-#[derive(Clone, Default, Debug)]
+/// Location of an array, laid out for access from compiled code. Inactive
+/// arrays have a null pointer and zero length.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct RawArray {
+    pub ptr: *mut u32,
+    pub len: u32,
+    /// Non-zero while the platters are shared between array 0 and the array
+    /// it was loaded from, in which case stores must go through `Arrays` to
+    /// break the share.
+    pub shared: u32,
+}
+
+impl RawArray {
+    const INACTIVE: Self = Self {
+        ptr: std::ptr::null_mut(),
+        len: 0,
+        shared: 0,
+    };
+
+    fn new(array: &mut [u32]) -> Self {
+        Self {
+            ptr: array.as_mut_ptr(),
+            len: array.len() as u32,
+            shared: 0,
+        }
+    }
+}
+
+/// Counters of array operations since the arrays were built.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ArrayStats {
+    /// Arrays allocated, including the program.
+    pub allocations: u64,
+    pub frees: u64,
+    /// Non-trivial LoadProgram instructions, which replace array 0.
+    pub loads: u64,
+    /// Platters in active arrays, now and at the peak. Platters shared
+    /// between array 0 and another array are counted for both.
+    pub platters: u64,
+    pub peak_platters: u64,
+    /// Copies of arrays shared by LoadProgram, made when either side was
+    /// about to be modified.
+    pub cow_breaks: u64,
+}
+
+impl ArrayStats {
+    fn resize(&mut self, old: usize, new: usize) {
+        self.platters = self.platters + new as u64 - old as u64;
+        self.peak_platters = self.peak_platters.max(self.platters);
+    }
+}
+
+#[derive(Debug)]
 pub struct Arrays {
     arrays: Vec<Option<Vec<u32>>>,
-    ptrs: Vec<*mut u32>,
+    // Locations of the arrays for compiled code, followed by an inactive
+    // entry that compiled code reads for identifiers out of range.
+    ptrs: Vec<RawArray>,
     vacants: Vec<usize>,
+    // The array whose platters array 0 shares copy-on-write since it was
+    // loaded from it, or 0. Array 0 then holds an empty placeholder.
+    shared: usize,
+    // Offsets in array 0 covered by compiled code, and those of them that
+    // have been overwritten since the last call to take_modified_code.
+    protected_code: Vec<u8>,
+    modified_code: Vec<usize>,
+    stats: ArrayStats,
 }
 
 impl Arrays {
     pub fn new() -> Self {
-        Self::default()
+        Self {
+            arrays: Vec::new(),
+            ptrs: vec![RawArray::INACTIVE],
+            vacants: Vec::new(),
+            shared: 0,
+            protected_code: Vec::new(),
+            modified_code: Vec::new(),
+            stats: ArrayStats::default(),
+        }
+    }
+
+    /// Builds arrays from their slots, where `None` marks an inactive slot.
+    /// `vacants` lists inactive slots in the order they are reused from the
+    /// back.
+    pub fn from_slots(slots: Vec<Option<Vec<u32>>>, vacants: Vec<usize>) -> Self {
+        let mut arrays = Self {
+            arrays: slots,
+            vacants,
+            ..Self::new()
+        };
+        arrays.ptrs = arrays
+            .arrays
+            .iter_mut()
+            .map(|array| match array {
+                Some(array) => RawArray::new(array),
+                None => RawArray::INACTIVE,
+            })
+            .chain([RawArray::INACTIVE])
+            .collect();
+        let platters = arrays.slots().flatten().map(|array| array.len()).sum();
+        arrays.stats.resize(0, platters);
+        arrays
+    }
+
+    pub fn slots(&self) -> impl Iterator<Item = Option<&[u32]>> {
+        (0..self.arrays.len()).map(|id| self.get(id))
+    }
+
+    pub fn vacants(&self) -> &[usize] {
+        &self.vacants
+    }
+
+    pub fn stats(&self) -> &ArrayStats {
+        &self.stats
     }
 
     pub fn insert(&mut self, mut array: Vec<u32>) -> usize {
+        self.stats.allocations += 1;
+        self.stats.resize(0, array.len());
         match self.vacants.pop() {
             Some(id) => {
-                self.ptrs[id] = array.as_mut_ptr();
+                self.ptrs[id] = RawArray::new(&mut array);
                 self.arrays[id] = Some(array);
                 assert_eq!(
                     self.arrays[id].as_mut().unwrap().as_mut_ptr(),
-                    self.ptrs[id]
+                    self.ptrs[id].ptr
                 );
                 id
             }
             None => {
                 let id = self.arrays.len();
-                self.ptrs.push(array.as_mut_ptr());
+                self.ptrs.insert(id, RawArray::new(&mut array));
                 self.arrays.push(Some(array));
                 assert_eq!(
                     self.arrays[id].as_mut().unwrap().as_mut_ptr(),
-                    self.ptrs[id]
+                    self.ptrs[id].ptr
                 );
                 id
             }
@@ -37,36 +145,149 @@ impl Arrays {
     }
 
     pub fn remove(&mut self, id: usize) {
-        assert!(self.arrays[id].is_some());
-        self.arrays[id] = None;
-        self.ptrs[id] = std::ptr::null_mut();
+        let array = self.arrays[id].take().unwrap();
+        self.stats.frees += 1;
+        self.stats.resize(array.len(), 0);
+        if id == self.shared {
+            // Array 0 takes over the platters, which stay where they are.
+            self.arrays[0] = Some(array);
+            self.ptrs[0].shared = 0;
+            self.shared = 0;
+        }
+        self.ptrs[id] = RawArray::INACTIVE;
         self.vacants.push(id);
     }
 
+    /// Replaces array 0 with array `id` for LoadProgram. The platters are
+    /// shared until either array is modified.
     pub fn dup0(&mut self, id: usize) {
         if id == 0 {
             return;
         }
-        self.arrays[0] = self.arrays[id].clone();
-        self.ptrs[0] = self.arrays[0].as_mut().unwrap().as_mut_ptr();
+        self.stats.loads += 1;
+        self.stats.resize(self[0].len(), self[id].len());
+        self.share(id);
+        self.protected_code.clear();
+        self.modified_code.clear();
+    }
+
+    fn share(&mut self, id: usize) {
+        if self.shared != 0 {
+            self.ptrs[self.shared].shared = 0;
+        }
+        self.arrays[0] = Some(Vec::new());
+        self.shared = id;
+        self.ptrs[id].shared = 1;
+        self.ptrs[0] = self.ptrs[id];
+    }
+
+    /// Gives array 0 its own copy of the platters it shares, if any, before
+    /// array 0 or the array it shares them with is modified.
+    fn break_share(&mut self) {
+        if self.shared == 0 {
+            return;
+        }
+        let mut array = self[self.shared].to_vec();
+        self.ptrs[0] = RawArray::new(&mut array);
+        self.arrays[0] = Some(array);
+        self.ptrs[self.shared].shared = 0;
+        self.shared = 0;
+        self.stats.cow_breaks += 1;
+    }
+
+    /// Returns the slot holding the platters of array `id`.
+    fn slot(&self, id: usize) -> usize {
+        if id == 0 && self.shared != 0 {
+            self.shared
+        } else {
+            id
+        }
+    }
+
+    /// Stores a value to array 0, recording the offset if it changes code
+    /// covered by compiled functions.
+    pub fn store_code(&mut self, offset: usize, value: u32) {
+        let cell = &mut self[0][offset];
+        let changed = *cell != value;
+        *cell = value;
+        if changed && self.protected_code.get(offset).copied().unwrap_or(0) != 0 {
+            self.modified_code.push(offset);
+        }
     }
 
-    pub fn as_mut_ptr(&mut self) -> *mut *mut u32 {
+    pub fn set_code_protected(&mut self, offset: usize, protected: bool) {
+        // Keep the table as long as array 0 so that compiled code can consult
+        // it without bounds checks.
+        let len = self[0].len();
+        self.protected_code.resize(len, 0);
+        self.protected_code[offset] = protected as u8;
+    }
+
+    pub fn protected_code_ptr(&mut self) -> *mut u8 {
+        self.protected_code.as_mut_ptr()
+    }
+
+    pub fn has_modified_code(&self) -> bool {
+        !self.modified_code.is_empty()
+    }
+
+    pub fn take_modified_code(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.modified_code)
+    }
+
+    pub fn is_active(&self, id: usize) -> bool {
+        matches!(self.arrays.get(id), Some(Some(_)))
+    }
+
+    pub fn get(&self, id: usize) -> Option<&[u32]> {
+        self.arrays.get(self.slot(id))?.as_deref()
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut [u32]> {
+        if self.shared != 0 && (id == 0 || id == self.shared) {
+            self.break_share();
+        }
+        self.arrays.get_mut(id)?.as_deref_mut()
+    }
+
+    /// Returns the number of array identifiers in use, including inactive
+    /// ones.
+    pub fn num_slots(&self) -> usize {
+        self.arrays.len()
+    }
+
+    /// Returns the locations of the arrays, which are followed by an
+    /// inactive entry at `num_slots`.
+    pub fn as_mut_ptr(&mut self) -> *mut RawArray {
         self.ptrs.as_mut_ptr()
     }
 }
 
+impl Clone for Arrays {
+    fn clone(&self) -> Self {
+        // The raw pointers must refer to the copied arrays.
+        let mut arrays = Self::from_slots(self.arrays.clone(), self.vacants.clone());
+        if self.shared != 0 {
+            arrays.share(self.shared);
+        }
+        arrays.protected_code = self.protected_code.clone();
+        arrays.modified_code = self.modified_code.clone();
+        arrays.stats = self.stats;
+        arrays
+    }
+}
+
 impl Index<usize> for Arrays {
     type Output = [u32];
 
     fn index(&self, id: usize) -> &Self::Output {
-        self.arrays.get(id).unwrap().as_ref().unwrap()
+        self.get(id).unwrap()
     }
 }
 
 impl IndexMut<usize> for Arrays {
     fn index_mut(&mut self, id: usize) -> &mut Self::Output {
-        self.arrays.get_mut(id).unwrap().as_mut().unwrap()
+        self.get_mut(id).unwrap()
     }
 }
 
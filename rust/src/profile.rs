@@ -0,0 +1,370 @@
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::instruction::{disassemble, Instruction, MNEMONICS};
+
+/// Quantity weighing folded stacks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Weight {
+    /// Exact counts of executed instructions.
+    Instructions,
+    /// Samples of the wall-clock time.
+    Time,
+}
+
+#[derive(Clone, Copy, Default)]
+struct PcProfile {
+    count: u64,
+    samples: u64,
+    /// The instruction last executed at the pc.
+    code: u32,
+    /// Whether the pc has been entered by a jump, starting a basic block.
+    leader: bool,
+}
+
+/// Execution profile of the program of one generation.
+#[derive(Default)]
+struct GenerationProfile {
+    pcs: Vec<PcProfile>,
+    opcodes: [u64; MNEMONICS.len()],
+}
+
+/// Straight-line run of executed instructions entered only at its start.
+struct Block {
+    generation: usize,
+    start: usize,
+    end: usize,
+    /// Times the block has been entered.
+    entries: u64,
+    count: u64,
+    samples: u64,
+}
+
+impl GenerationProfile {
+    fn count(&self) -> u64 {
+        self.opcodes.iter().sum()
+    }
+
+    fn samples(&self) -> u64 {
+        self.pcs.iter().map(|pc| pc.samples).sum()
+    }
+
+    /// Splits executed instructions into basic blocks, ending them after
+    /// jumps and halts and before pcs entered by jumps.
+    fn blocks(&self, generation: usize) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut open = false;
+        for (pc, profile) in self.pcs.iter().enumerate() {
+            if profile.count == 0 {
+                open = false;
+                continue;
+            }
+            if !open || profile.leader {
+                blocks.push(Block {
+                    generation,
+                    start: pc,
+                    end: pc,
+                    entries: profile.count,
+                    count: 0,
+                    samples: 0,
+                });
+            }
+            let block = blocks.last_mut().unwrap();
+            block.end = pc + 1;
+            block.count += profile.count;
+            block.samples += profile.samples;
+            open = !matches!(Instruction::from_u32(profile.code).opcode(), 7 | 12);
+        }
+        blocks
+    }
+}
+
+/// Counts executed instructions per program generation and pc, and samples
+/// the pc at a regular interval of wall-clock time.
+pub struct Profiler {
+    generations: Vec<GenerationProfile>,
+    /// Set by the sampling thread when a sample is due.
+    tick: Arc<AtomicBool>,
+    interval: Duration,
+    /// Whether the next instruction is entered by a jump.
+    leader: bool,
+}
+
+impl Profiler {
+    pub fn new(interval: Duration) -> Self {
+        let tick = Arc::new(AtomicBool::new(false));
+        let sampler = Arc::downgrade(&tick);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            // Stop when the profiler is dropped.
+            let Some(tick) = sampler.upgrade() else {
+                break;
+            };
+            tick.store(true, Ordering::Relaxed);
+        });
+        Self {
+            generations: Vec::new(),
+            tick,
+            interval,
+            leader: true,
+        }
+    }
+
+    /// Records an instruction executed at `pc`.
+    pub fn record(&mut self, generation: u64, pc: usize, inst: Instruction) {
+        let generation = generation as usize;
+        if self.generations.len() <= generation {
+            self.generations
+                .resize_with(generation + 1, GenerationProfile::default);
+        }
+        let profile = &mut self.generations[generation];
+        if profile.pcs.len() <= pc {
+            profile.pcs.resize(pc + 1, PcProfile::default());
+        }
+        let pc_profile = &mut profile.pcs[pc];
+        pc_profile.count += 1;
+        pc_profile.code = inst.to_u32();
+        pc_profile.leader |= self.leader;
+        if self.tick.load(Ordering::Relaxed) {
+            self.tick.store(false, Ordering::Relaxed);
+            pc_profile.samples += 1;
+        }
+        profile.opcodes[inst.opcode() as usize] += 1;
+        self.leader = matches!(inst.opcode(), 7 | 12);
+    }
+
+    fn blocks(&self) -> Vec<Block> {
+        self.generations
+            .iter()
+            .enumerate()
+            .flat_map(|(generation, profile)| profile.blocks(generation))
+            .collect()
+    }
+
+    /// Writes text tables of the hottest `top` instructions and basic blocks,
+    /// opcode histograms and a breakdown by program generation.
+    pub fn write_report(&self, w: &mut impl Write, top: usize) -> std::io::Result<()> {
+        let count: u64 = self.generations.iter().map(|g| g.count()).sum();
+        let samples: u64 = self.generations.iter().map(|g| g.samples()).sum();
+        let blocks = self.blocks();
+        let share = |n: u64, total: u64| {
+            if total == 0 {
+                "-".to_owned()
+            } else {
+                format!("{:.2}%", n as f64 * 100.0 / total as f64)
+            }
+        };
+
+        writeln!(
+            w,
+            "{count} instructions, {samples} samples every {:?}",
+            self.interval
+        )?;
+
+        writeln!(w)?;
+        writeln!(w, "Generations")?;
+        writeln!(
+            w,
+            "{:>5} {:>14} {:>8} {:>8} {:>8}",
+            "gen", "instructions", "%insts", "%time", "blocks"
+        )?;
+        for (generation, profile) in self.generations.iter().enumerate() {
+            let block_count = blocks
+                .iter()
+                .filter(|block| block.generation == generation)
+                .count();
+            writeln!(
+                w,
+                "{generation:>5} {:>14} {:>8} {:>8} {block_count:>8}",
+                profile.count(),
+                share(profile.count(), count),
+                share(profile.samples(), samples),
+            )?;
+        }
+
+        let mut generations: Vec<usize> = (0..self.generations.len()).collect();
+        generations.sort_by_key(|&g| std::cmp::Reverse(self.generations[g].count()));
+        for &generation in generations.iter().take(top) {
+            let profile = &self.generations[generation];
+            if profile.count() == 0 {
+                continue;
+            }
+            writeln!(w)?;
+            writeln!(w, "Opcodes of generation {generation}")?;
+            writeln!(w, "{:<8} {:>14} {:>8}", "opcode", "count", "%insts")?;
+            let mut opcodes: Vec<usize> = (0..MNEMONICS.len()).collect();
+            opcodes.sort_by_key(|&op| std::cmp::Reverse(profile.opcodes[op]));
+            for op in opcodes {
+                let n = profile.opcodes[op];
+                if n == 0 {
+                    break;
+                }
+                writeln!(
+                    w,
+                    "{:<8} {n:>14} {:>8}",
+                    MNEMONICS[op],
+                    share(n, profile.count())
+                )?;
+            }
+        }
+
+        let mut hot_blocks: Vec<&Block> = blocks.iter().collect();
+        hot_blocks.sort_by_key(|block| std::cmp::Reverse(block.count));
+        writeln!(w)?;
+        writeln!(w, "Hot basic blocks")?;
+        writeln!(
+            w,
+            "{:<22} {:>12} {:>14} {:>8} {:>8}",
+            "gen:start..end", "entries", "instructions", "%insts", "%time"
+        )?;
+        for block in hot_blocks.into_iter().take(top) {
+            writeln!(
+                w,
+                "{:<22} {:>12} {:>14} {:>8} {:>8}",
+                format!("{}:{:08}..{:08}", block.generation, block.start, block.end),
+                block.entries,
+                block.count,
+                share(block.count, count),
+                share(block.samples, samples),
+            )?;
+        }
+
+        let mut hot_pcs: Vec<(usize, usize, &PcProfile)> = self
+            .generations
+            .iter()
+            .enumerate()
+            .flat_map(|(generation, profile)| {
+                profile
+                    .pcs
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, pc)| pc.count > 0)
+                    .map(move |(pc, profile)| (generation, pc, profile))
+            })
+            .collect();
+        hot_pcs.sort_by_key(|&(_, _, profile)| std::cmp::Reverse(profile.count));
+        writeln!(w)?;
+        writeln!(w, "Hot instructions")?;
+        writeln!(
+            w,
+            "{:<12} {:>14} {:>8} {:>8}  instruction",
+            "gen:pc", "count", "%insts", "%time"
+        )?;
+        for (generation, pc, profile) in hot_pcs.into_iter().take(top) {
+            writeln!(
+                w,
+                "{:<12} {:>14} {:>8} {:>8}  {}",
+                format!("{generation}:{pc:08}"),
+                profile.count,
+                share(profile.count, count),
+                share(profile.samples, samples),
+                disassemble(profile.code)
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes folded stacks of generations, basic blocks and instructions,
+    /// the input format of flame graph tools.
+    pub fn write_folded(&self, w: &mut impl Write, weight: Weight) -> std::io::Result<()> {
+        for block in self.blocks() {
+            let pcs = &self.generations[block.generation].pcs[block.start..block.end];
+            for (pc, profile) in (block.start..).zip(pcs) {
+                let n = match weight {
+                    Weight::Instructions => profile.count,
+                    Weight::Time => profile.samples,
+                };
+                if n == 0 {
+                    continue;
+                }
+                writeln!(
+                    w,
+                    "gen {};block {:08};{pc:08} {} {n}",
+                    block.generation,
+                    block.start,
+                    disassemble(profile.code)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        console::Console,
+        interpreter::{self, Hooks},
+        io::StreamIo,
+        memory::Memory,
+        program::load_program,
+    };
+
+    use super::*;
+
+    /// Profiles testdata/debug.um, which runs 13 instructions, then a loop
+    /// of 11 instructions 3 times, then halts.
+    fn profile() -> Profiler {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/debug.um");
+        let mut memory = Memory::new(load_program(&path).unwrap());
+        let io = StreamIo::new(&b""[..], std::io::sink());
+        let mut console = Console::new(Box::new(io), None, false);
+        let mut hooks = Hooks {
+            // Too long an interval to take samples.
+            profiler: Some(Profiler::new(Duration::from_secs(3600))),
+            ..Hooks::default()
+        };
+        interpreter::run(&mut memory, 0, &mut console, &mut hooks).unwrap();
+        hooks.profiler.unwrap()
+    }
+
+    #[test]
+    fn counts() {
+        let profiler = profile();
+        assert_eq!(profiler.generations.len(), 1);
+        let profile = &profiler.generations[0];
+        let counts: Vec<u64> = profile.pcs.iter().map(|pc| pc.count).collect();
+        assert_eq!(counts, [[1; 13].as_slice(), &[3; 11], &[1]].concat());
+        assert_eq!(profile.count(), 47);
+        assert_eq!(profile.samples(), 0);
+        assert_eq!(profile.opcodes[13], 18); // imm
+        assert_eq!(profile.opcodes[7], 1); // halt
+
+        // The loop at pc 13 and the halt at pc 24 are entered by jumps.
+        let blocks: Vec<(usize, usize, u64, u64)> = profiler
+            .blocks()
+            .iter()
+            .map(|block| (block.start, block.end, block.entries, block.count))
+            .collect();
+        assert_eq!(blocks, [(0, 13, 1, 13), (13, 24, 3, 33), (24, 25, 1, 1)]);
+    }
+
+    #[test]
+    fn folded_stacks() {
+        let profiler = profile();
+        let mut folded = Vec::new();
+        profiler
+            .write_folded(&mut folded, Weight::Instructions)
+            .unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        let lines: Vec<&str> = folded.lines().collect();
+        assert_eq!(lines.len(), 25);
+        assert_eq!(lines[0], "gen 0;block 00000000;00000000 imm r1, 3 1");
+        assert_eq!(lines[13], "gen 0;block 00000013;00000013 load r3, r2, r4 3");
+        assert_eq!(lines[24], "gen 0;block 00000024;00000024 halt 1");
+
+        // No samples were taken.
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded, Weight::Time).unwrap();
+        assert!(folded.is_empty());
+    }
+}
@@ -0,0 +1,23 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    path::Path,
+};
+
+use anyhow::Result;
+
+/// Reads a UM program, a sequence of big-endian platters, from `path`.
+pub fn load_program(path: &Path) -> Result<Vec<u32>> {
+    let data = std::fs::read(path)?;
+    let program: Vec<u32> = data
+        .chunks_exact(4)
+        .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+        .collect();
+    Ok(program)
+}
+
+/// Returns a hash of the platters of a program.
+pub fn hash_program(program: &[u32]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    program.hash(&mut hasher);
+    hasher.finish()
+}
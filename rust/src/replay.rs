@@ -0,0 +1,150 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{bail, ensure, Context as _, Result};
+
+// Input logs are text files with a header line followed by one line per
+// input instruction: the number of instructions executed before it, and the
+// byte delivered in hex or "eof". Lines starting with '#' are comments.
+const HEADER: &str = "umix input log 1";
+
+/// Input delivered to the program by an input instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputEvent {
+    /// Number of instructions executed before the input instruction.
+    pub insts: u64,
+    /// The byte read, or `None` at the end of input.
+    pub value: Option<u8>,
+}
+
+/// Writes input events to a log as they happen.
+pub struct InputRecorder<W: Write = BufWriter<File>> {
+    writer: W,
+}
+
+impl InputRecorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+        Self::new(BufWriter::new(file))
+    }
+}
+
+impl<W: Write> InputRecorder<W> {
+    pub fn new(mut writer: W) -> Result<Self> {
+        writeln!(writer, "{HEADER}")?;
+        Ok(Self { writer })
+    }
+
+    pub fn record(&mut self, event: InputEvent) {
+        let result = match event.value {
+            Some(value) => writeln!(self.writer, "{} {value:02x}", event.insts),
+            None => writeln!(self.writer, "{} eof", event.insts),
+        };
+        // Keep the log complete even if the run is killed while waiting for
+        // the next input.
+        result
+            .and_then(|()| self.writer.flush())
+            .expect("write error");
+    }
+}
+
+/// Loads the events of an input log in order.
+pub fn load(path: &Path) -> Result<VecDeque<InputEvent>> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    parse(&text).with_context(|| format!("loading {}", path.display()))
+}
+
+fn parse(text: &str) -> Result<VecDeque<InputEvent>> {
+    let mut lines = text.lines().enumerate();
+    ensure!(
+        lines.next().map(|(_, line)| line) == Some(HEADER),
+        "not an input log"
+    );
+
+    let mut events = VecDeque::new();
+    for (i, line) in lines {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parse = || -> Option<InputEvent> {
+            let (insts, value) = line.split_once(' ')?;
+            let insts = insts.parse().ok()?;
+            let value = match value {
+                "eof" => None,
+                value => Some(u8::from_str_radix(value, 16).ok()?),
+            };
+            Some(InputEvent { insts, value })
+        };
+        let Some(event) = parse() else {
+            bail!("line {}: invalid input event", i + 1);
+        };
+        events.push_back(event);
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_events() {
+        let events = parse("umix input log 1\n# comment\n10 61\n\n  25 0a  \n30 eof\n").unwrap();
+        assert_eq!(
+            events,
+            [
+                InputEvent {
+                    insts: 10,
+                    value: Some(b'a')
+                },
+                InputEvent {
+                    insts: 25,
+                    value: Some(b'\n')
+                },
+                InputEvent {
+                    insts: 30,
+                    value: None
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_bad_logs() {
+        assert!(parse("10 61\n").is_err());
+        let err = parse("umix input log 1\n10 61\n20 xyz\n").err().unwrap();
+        assert_eq!(err.to_string(), "line 3: invalid input event");
+        assert!(parse("umix input log 1\n10\n").is_err());
+        assert!(parse("umix input log 1\n-1 61\n").is_err());
+    }
+
+    #[test]
+    fn record_and_parse() {
+        let events = [
+            InputEvent {
+                insts: 0,
+                value: Some(0),
+            },
+            InputEvent {
+                insts: 7,
+                value: Some(0xff),
+            },
+            InputEvent {
+                insts: 9,
+                value: None,
+            },
+        ];
+        let mut recorder = InputRecorder::new(Vec::new()).unwrap();
+        for event in events {
+            recorder.record(event);
+        }
+        let text = String::from_utf8(recorder.writer).unwrap();
+        assert_eq!(parse(&text).unwrap(), events);
+    }
+}
@@ -0,0 +1,520 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::{bail, ensure, Context as _, Result};
+
+use crate::{
+    console::{BACKLOG_CAPACITY, PASTE_CAPACITY},
+    memory::{Arrays, Memory},
+};
+
+// Snapshot file layout. All values are little-endian u32s.
+//
+//   magic "UMXR", version
+//   pc, r0..r7
+//   number of slots, then for each slot its length (INACTIVE if the slot is
+//   not in use) followed by its platters
+//   number of vacant slots, then their identifiers
+//   length of the output backlog, then its bytes
+//   length of the pending paste input, then its bytes
+const MAGIC: &[u8; 4] = b"UMXR";
+const VERSION: u32 = 1;
+const INACTIVE: u32 = !0;
+
+// Snapshots saved by the C implementation (c/snapshot.c). Values are native
+// endian ints.
+//
+//   magic "UMX\x01"
+//   pc, r0..r7
+//   number of slots, then for each slot its length (-1 if the slot is not in
+//   use) followed by its platters
+//   the output backlog, BACKLOG_CAPACITY bytes in chronological order
+//   offset and size of the pending paste input, then the paste ring buffer
+const C_MAGIC: &[u8; 4] = b"UMX\x01";
+const C_INACTIVE: i32 = -1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// The native format of this crate.
+    Umix,
+    /// The format of the C implementation.
+    C,
+}
+
+/// Machine state at an instruction boundary.
+pub struct Snapshot {
+    /// The instruction to execute on resume.
+    pub pc: usize,
+    pub memory: Memory,
+    /// The most recent output, in chronological order.
+    pub backlog: Vec<u8>,
+    /// Pasted input the program has not read yet.
+    pub paste: Vec<u8>,
+}
+
+/// Saves the machine state to a file.
+pub fn save(
+    path: &Path,
+    format: Format,
+    pc: usize,
+    memory: &Memory,
+    backlog: &[u8],
+    paste: &[u8],
+) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    write_any(&mut writer, format, pc, memory, backlog, paste)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Loads a snapshot saved in either format.
+pub fn load(path: &Path) -> Result<Snapshot> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    read_any(&mut reader).with_context(|| format!("loading {}", path.display()))
+}
+
+fn write_any(
+    writer: &mut impl Write,
+    format: Format,
+    pc: usize,
+    memory: &Memory,
+    backlog: &[u8],
+    paste: &[u8],
+) -> Result<()> {
+    match format {
+        Format::Umix => write(writer, pc, memory, backlog, paste),
+        Format::C => write_c(writer, pc, memory, backlog, paste),
+    }
+}
+
+fn read_any(reader: &mut impl Read) -> Result<Snapshot> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    match &magic {
+        MAGIC => read(reader),
+        C_MAGIC => read_c(reader),
+        _ => bail!("not a snapshot"),
+    }
+}
+
+fn write(
+    writer: &mut impl Write,
+    pc: usize,
+    memory: &Memory,
+    backlog: &[u8],
+    paste: &[u8],
+) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    write_u32(writer, VERSION)?;
+    write_u32(writer, pc as u32)?;
+    for &reg in memory.regs.iter() {
+        write_u32(writer, reg)?;
+    }
+
+    let arrays = &memory.arrays;
+    write_u32(writer, arrays.num_slots() as u32)?;
+    for array in arrays.slots() {
+        match array {
+            Some(array) => {
+                write_u32(writer, array.len() as u32)?;
+                let data: Vec<u8> = array.iter().flat_map(|value| value.to_le_bytes()).collect();
+                writer.write_all(&data)?;
+            }
+            None => write_u32(writer, INACTIVE)?,
+        }
+    }
+    write_u32(writer, arrays.vacants().len() as u32)?;
+    for &id in arrays.vacants() {
+        write_u32(writer, id as u32)?;
+    }
+
+    write_u32(writer, backlog.len() as u32)?;
+    writer.write_all(backlog)?;
+    write_u32(writer, paste.len() as u32)?;
+    writer.write_all(paste)?;
+    Ok(())
+}
+
+fn read(reader: &mut impl Read) -> Result<Snapshot> {
+    let version = read_u32(reader)?;
+    ensure!(version == VERSION, "unsupported snapshot version {version}");
+
+    let pc = read_u32(reader)? as usize;
+    let mut regs = [0; 8];
+    for reg in regs.iter_mut() {
+        *reg = read_u32(reader)?;
+    }
+
+    // Counts and lengths are not trusted to allocate upfront, as a corrupt
+    // or truncated file could claim gigabytes.
+    let num_slots = read_u32(reader)? as usize;
+    let mut slots = Vec::new();
+    for id in 0..num_slots {
+        let len = read_u32(reader)?;
+        if len == INACTIVE {
+            slots.push(None);
+            continue;
+        }
+        slots.push(Some(read_platters(
+            reader,
+            id,
+            len as usize,
+            u32::from_le_bytes,
+        )?));
+    }
+    ensure!(
+        matches!(slots.first(), Some(Some(_))),
+        "array 0 is not active"
+    );
+
+    let num_vacants = read_u32(reader)? as usize;
+    let mut vacants = Vec::new();
+    let mut seen = vec![false; num_slots];
+    for _ in 0..num_vacants {
+        let id = read_u32(reader)? as usize;
+        if !matches!(slots.get(id), Some(None)) || seen[id] {
+            bail!("invalid vacant slot {id}");
+        }
+        seen[id] = true;
+        vacants.push(id);
+    }
+    ensure!(
+        vacants.len() == slots.iter().filter(|array| array.is_none()).count(),
+        "inactive slots are missing from the vacant list"
+    );
+
+    let len = read_u32(reader)? as usize;
+    ensure!(len <= BACKLOG_CAPACITY, "backlog too long");
+    let mut backlog = vec![0; len];
+    reader.read_exact(&mut backlog)?;
+
+    let len = read_u32(reader)? as usize;
+    ensure!(len <= PASTE_CAPACITY, "paste input too long");
+    let mut paste = Vec::new();
+    reader.take(len as u64).read_to_end(&mut paste)?;
+    ensure!(paste.len() == len, "paste input truncated");
+
+    Ok(Snapshot {
+        pc,
+        memory: Memory {
+            regs,
+            arrays: Arrays::from_slots(slots, vacants),
+        },
+        backlog,
+        paste,
+    })
+}
+
+fn write_c(
+    writer: &mut impl Write,
+    pc: usize,
+    memory: &Memory,
+    backlog: &[u8],
+    paste: &[u8],
+) -> Result<()> {
+    writer.write_all(C_MAGIC)?;
+    writer.write_all(&(pc as u32).to_ne_bytes())?;
+    for &reg in memory.regs.iter() {
+        writer.write_all(&reg.to_ne_bytes())?;
+    }
+
+    let arrays = &memory.arrays;
+    writer.write_all(&(arrays.num_slots() as i32).to_ne_bytes())?;
+    for array in arrays.slots() {
+        match array {
+            Some(array) => {
+                writer.write_all(&(array.len() as i32).to_ne_bytes())?;
+                let data: Vec<u8> = array.iter().flat_map(|value| value.to_ne_bytes()).collect();
+                writer.write_all(&data)?;
+            }
+            None => writer.write_all(&C_INACTIVE.to_ne_bytes())?,
+        }
+    }
+
+    // The C implementation always saves a full backlog, which starts out
+    // zero-filled.
+    let backlog = &backlog[backlog.len().saturating_sub(BACKLOG_CAPACITY)..];
+    writer.write_all(&vec![0; BACKLOG_CAPACITY - backlog.len()])?;
+    writer.write_all(backlog)?;
+
+    // The paste ring buffer starts at offset 0.
+    ensure!(paste.len() <= PASTE_CAPACITY, "paste input too long");
+    writer.write_all(&0i32.to_ne_bytes())?;
+    writer.write_all(&(paste.len() as i32).to_ne_bytes())?;
+    writer.write_all(paste)?;
+    writer.write_all(&vec![0; PASTE_CAPACITY - paste.len()])?;
+    Ok(())
+}
+
+fn read_c(reader: &mut impl Read) -> Result<Snapshot> {
+    let pc = read_i32_ne(reader)? as u32 as usize;
+    let mut regs = [0; 8];
+    for reg in regs.iter_mut() {
+        *reg = read_i32_ne(reader)? as u32;
+    }
+
+    let num_slots = read_i32_ne(reader)?;
+    ensure!(num_slots >= 0, "invalid number of arrays {num_slots}");
+    let mut slots = Vec::new();
+    let mut vacants = Vec::new();
+    for id in 0..num_slots as usize {
+        let len = read_i32_ne(reader)?;
+        if len == C_INACTIVE {
+            // The C implementation reuses inactive slots from the highest
+            // identifier down after loading a snapshot.
+            slots.push(None);
+            vacants.push(id);
+            continue;
+        }
+        ensure!(len >= 0, "invalid length {len} of array {id}");
+        slots.push(Some(read_platters(
+            reader,
+            id,
+            len as usize,
+            u32::from_ne_bytes,
+        )?));
+    }
+    ensure!(
+        matches!(slots.first(), Some(Some(_))),
+        "array 0 is not active"
+    );
+
+    let mut backlog = vec![0; BACKLOG_CAPACITY];
+    reader.read_exact(&mut backlog)?;
+    // Drop the zero fill of a backlog that has not wrapped around yet.
+    let start = backlog
+        .iter()
+        .position(|&c| c != 0)
+        .unwrap_or(backlog.len());
+    backlog.drain(..start);
+
+    let paste_offset = read_i32_ne(reader)?;
+    let paste_size = read_i32_ne(reader)?;
+    ensure!(
+        (0..PASTE_CAPACITY as i32).contains(&paste_offset),
+        "invalid paste offset {paste_offset}"
+    );
+    ensure!(
+        (0..=PASTE_CAPACITY as i32).contains(&paste_size),
+        "invalid paste size {paste_size}"
+    );
+    let mut ring = vec![0; PASTE_CAPACITY];
+    reader.read_exact(&mut ring)?;
+    // Unwrap the pending bytes of the ring buffer.
+    ring.rotate_left(paste_offset as usize);
+    ring.truncate(paste_size as usize);
+
+    Ok(Snapshot {
+        pc,
+        memory: Memory {
+            regs,
+            arrays: Arrays::from_slots(slots, vacants),
+        },
+        backlog,
+        paste: ring,
+    })
+}
+
+/// Reads the `len` platters of array `id`. The buffer grows as data arrives,
+/// so a truncated file fails without allocating the claimed length.
+fn read_platters(
+    reader: &mut impl Read,
+    id: usize,
+    len: usize,
+    from_bytes: fn([u8; 4]) -> u32,
+) -> Result<Vec<u32>> {
+    let mut data = Vec::new();
+    reader.take(len as u64 * 4).read_to_end(&mut data)?;
+    ensure!(data.len() == len * 4, "array {id} truncated");
+    Ok(data
+        .chunks_exact(4)
+        .map(|chunk| from_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
+
+fn write_u32(writer: &mut impl Write, value: u32) -> std::io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_i32_ne(reader: &mut impl Read) -> std::io::Result<i32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(i32::from_ne_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a machine with inactive slots between active arrays.
+    fn memory() -> Memory {
+        let mut memory = Memory::new(vec![1, 2, 3]);
+        memory.regs = [0, 1, 2, 3, 4, 5, 6, 0xdeadbeef];
+        let first = memory.arrays.insert(vec![4, 5]);
+        let second = memory.arrays.insert(Vec::new());
+        memory.arrays.insert(vec![6; 300]);
+        memory.arrays.remove(second);
+        memory.arrays.remove(first);
+        memory
+    }
+
+    fn assert_same_memory(actual: &Memory, expected: &Memory) {
+        assert_eq!(actual.regs, expected.regs);
+        assert_eq!(
+            actual.arrays.slots().collect::<Vec<_>>(),
+            expected.arrays.slots().collect::<Vec<_>>()
+        );
+    }
+
+    /// Saves a snapshot in `format` to memory and loads it back.
+    fn round_trip_in(
+        format: Format,
+        pc: usize,
+        memory: &Memory,
+        backlog: &[u8],
+        paste: &[u8],
+    ) -> Snapshot {
+        let mut data = Vec::new();
+        write_any(&mut data, format, pc, memory, backlog, paste).unwrap();
+        read_any(&mut &data[..]).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let memory = memory();
+        let snapshot = round_trip_in(Format::Umix, 2, &memory, b"", b"");
+
+        assert_eq!(snapshot.pc, 2);
+        assert_same_memory(&snapshot.memory, &memory);
+        // Inactive slots are reused in the same order.
+        assert_eq!(snapshot.memory.arrays.vacants(), memory.arrays.vacants());
+    }
+
+    #[test]
+    fn round_trip_console() {
+        let snapshot = round_trip_in(Format::Umix, 0, &memory(), b"backlog", b"paste");
+
+        assert_eq!(snapshot.backlog, b"backlog");
+        assert_eq!(snapshot.paste, b"paste");
+    }
+
+    #[test]
+    fn round_trip_c() {
+        let memory = memory();
+        let snapshot = round_trip_in(Format::C, 2, &memory, b"backlog", b"paste");
+
+        assert_eq!(snapshot.pc, 2);
+        assert_same_memory(&snapshot.memory, &memory);
+        // The zero fill of the backlog is dropped.
+        assert_eq!(snapshot.backlog, b"backlog");
+        assert_eq!(snapshot.paste, b"paste");
+    }
+
+    #[test]
+    fn read_c_wrapped_paste() {
+        let mut data = Vec::new();
+        write_c(&mut data, 0, &memory(), b"", b"").unwrap();
+        // Place "abcd" across the end of the ring buffer.
+        let ring = data.len() - PASTE_CAPACITY;
+        let offset = PASTE_CAPACITY as i32 - 2;
+        data[ring - 8..ring - 4].copy_from_slice(&offset.to_ne_bytes());
+        data[ring - 4..ring].copy_from_slice(&4i32.to_ne_bytes());
+        data[ring + PASTE_CAPACITY - 2..].copy_from_slice(b"ab");
+        data[ring..ring + 2].copy_from_slice(b"cd");
+
+        let snapshot = read_c(&mut &data[4..]).unwrap();
+        assert_eq!(snapshot.paste, b"abcd");
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut data = Vec::new();
+        write(&mut data, 0, &memory(), b"", b"").unwrap();
+        data[4..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let err = read(&mut &data[4..]).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!("unsupported snapshot version {}", VERSION + 1)
+        );
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let mut data = Vec::new();
+        write(&mut data, 0, &memory(), b"", b"").unwrap();
+        // Cut the file in the middle of the array of 300 platters.
+        let err = read(&mut &data[4..data.len() - 800]).err().unwrap();
+        assert_eq!(err.to_string(), "array 3 truncated");
+    }
+
+    #[test]
+    fn rejects_truncated_huge_array() {
+        let mut data = Vec::new();
+        write(&mut data, 0, &Memory::new(vec![1, 2, 3]), b"", b"").unwrap();
+        // Claim a length of array 0 far beyond the end of the file, which
+        // must not be allocated.
+        data[48..52].copy_from_slice(&0xfffffffeu32.to_le_bytes());
+        let err = read(&mut &data[4..]).err().unwrap();
+        assert_eq!(err.to_string(), "array 0 truncated");
+    }
+
+    #[test]
+    fn rejects_long_paste() {
+        let mut data = Vec::new();
+        let paste = vec![b'a'; PASTE_CAPACITY + 1];
+        write(&mut data, 0, &memory(), b"", &paste).unwrap();
+        let err = read(&mut &data[4..]).err().unwrap();
+        assert_eq!(err.to_string(), "paste input too long");
+        let err = write_c(&mut Vec::new(), 0, &memory(), b"", &paste)
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "paste input too long");
+    }
+
+    #[test]
+    fn rejects_truncated_c() {
+        let mut data = Vec::new();
+        write_c(&mut data, 0, &memory(), b"", b"").unwrap();
+        // Cut the file in the middle of the backlog, then of the array of
+        // 300 platters.
+        let backlog = data.len() - PASTE_CAPACITY - 8 - BACKLOG_CAPACITY;
+        assert!(read_c(&mut &data[4..backlog + 10]).is_err());
+        let err = read_c(&mut &data[4..backlog - 800]).err().unwrap();
+        assert_eq!(err.to_string(), "array 3 truncated");
+
+        let mut data = Vec::new();
+        write_c(&mut data, 0, &Memory::new(vec![1, 2, 3]), b"", b"").unwrap();
+        data[44..48].copy_from_slice(&i32::MAX.to_ne_bytes());
+        let err = read_c(&mut &data[4..]).err().unwrap();
+        assert_eq!(err.to_string(), "array 0 truncated");
+    }
+
+    #[test]
+    fn rejects_missing_vacant() {
+        let mut memory = Memory::new(vec![0]);
+        let id = memory.arrays.insert(vec![0]);
+        memory.arrays.remove(id);
+        let mut data = Vec::new();
+        write(&mut data, 0, &memory, b"", b"").unwrap();
+        // Clear the list of vacant slots, which follows the slots.
+        let offset = data.len() - 16;
+        assert_eq!(data[offset..offset + 8], [1, 0, 0, 0, 1, 0, 0, 0]);
+        data.splice(offset..offset + 8, [0, 0, 0, 0]);
+        let err = read(&mut &data[4..]).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "inactive slots are missing from the vacant list"
+        );
+    }
+}
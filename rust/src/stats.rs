@@ -0,0 +1,67 @@
+use crate::memory::Arrays;
+
+/// Counters of a run of the machine, printed by `run --stats` and the console
+/// `stat` command. Array operations are counted by [`Arrays`] itself.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub insts: u64,
+    pub traces_compiled: u64,
+    /// Traces compiled from hot side exits of other traces, included in
+    /// `traces_compiled`.
+    pub branch_traces_compiled: u64,
+    /// Traces compiled for a program before and reused when LoadProgram
+    /// loaded it again.
+    pub traces_reused: u64,
+    /// Calls of compiled traces from the dispatcher.
+    pub trace_entries: u64,
+    /// Jumps from compiled traces straight into others.
+    pub trace_chains: u64,
+    /// Returns from compiled traces to the dispatcher to continue in the
+    /// interpreter, on a guard failure, a store to compiled code or a jump to
+    /// a pc without a trace.
+    pub trace_exits: u64,
+}
+
+impl Stats {
+    /// Formats the counters together with those of `arrays`.
+    pub fn report(&self, arrays: &Arrays) -> String {
+        let array_stats = arrays.stats();
+        let active = arrays.slots().flatten().count();
+        format!(
+            "machine:\n\
+             \texecuted instructions:    {}\n\
+             \tprogram loads:            {}\n\
+             arrays:\n\
+             \ttotal reserved arrays:    {}\n\
+             \ttotal active arrays:      {active}\n\
+             \ttotal inactive arrays:    {}\n\
+             \ttotal allocated platters: {}\n\
+             \tpeak allocated platters:  {}\n\
+             \tallocations:              {}\n\
+             \tabandonments:             {}\n\
+             \tcopy-on-write breaks:     {}\n\
+             jit:\n\
+             \ttraces compiled:          {}\n\
+             \tbranch traces compiled:   {}\n\
+             \ttraces reused:            {}\n\
+             \ttrace entries:            {}\n\
+             \ttrace chains:             {}\n\
+             \ttrace exits:              {}\n",
+            self.insts,
+            array_stats.loads,
+            arrays.num_slots(),
+            arrays.num_slots() - active,
+            array_stats.platters,
+            array_stats.peak_platters,
+            array_stats.allocations,
+            array_stats.frees,
+            array_stats.cow_breaks,
+            self.traces_compiled,
+            self.branch_traces_compiled,
+            self.traces_reused,
+            self.trace_entries,
+            self.trace_chains,
+            self.trace_exits,
+        )
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+};
+
+use anyhow::{bail, ensure, Context as _, Result};
+
+use crate::instruction::Instruction;
+
+// Trace file layout:
+//
+//   magic "UMXT", version as a little-endian u32
+//   records, each starting with a tag byte:
+//     GENERATION_TAG, then the generation: following instructions run in the
+//     program loaded by that many LoadProgram instructions replacing array 0
+//     otherwise an executed instruction: bits 0-3 are its opcode, WRITE_FLAG
+//     is set if it writes a register and ACCESS_FLAG if it reads or writes a
+//     platter. Then the pc, the instruction as a little-endian u32, the
+//     register and its new value if written, and the array identifier and
+//     offset of the platter if accessed.
+//
+// Numbers other than the instruction and the register are unsigned LEB128.
+const MAGIC: &[u8; 4] = b"UMXT";
+const VERSION: u32 = 1;
+const GENERATION_TAG: u8 = 0xff;
+const WRITE_FLAG: u8 = 0x10;
+const ACCESS_FLAG: u8 = 0x20;
+
+/// An executed instruction in a trace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub generation: u64,
+    pub pc: usize,
+    pub code: u32,
+    /// The register written and its new value.
+    pub write: Option<(usize, u32)>,
+    /// The array identifier and offset of the platter read or written.
+    pub access: Option<(u32, u32)>,
+}
+
+/// Writes executed instructions to a trace file.
+pub struct Tracer<W: Write = BufWriter<File>> {
+    writer: W,
+    generation: u64,
+}
+
+impl Tracer {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+        Self::new(BufWriter::new(file))
+    }
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self {
+            writer,
+            generation: 0,
+        })
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) {
+        self.write_record(record).expect("write error");
+    }
+
+    fn write_record(&mut self, record: &TraceRecord) -> std::io::Result<()> {
+        if record.generation != self.generation {
+            self.writer.write_all(&[GENERATION_TAG])?;
+            write_varint(&mut self.writer, record.generation)?;
+            self.generation = record.generation;
+        }
+        let mut tag = Instruction::from_u32(record.code).opcode() as u8;
+        if record.write.is_some() {
+            tag |= WRITE_FLAG;
+        }
+        if record.access.is_some() {
+            tag |= ACCESS_FLAG;
+        }
+        self.writer.write_all(&[tag])?;
+        write_varint(&mut self.writer, record.pc as u64)?;
+        self.writer.write_all(&record.code.to_le_bytes())?;
+        if let Some((reg, value)) = record.write {
+            self.writer.write_all(&[reg as u8])?;
+            write_varint(&mut self.writer, value.into())?;
+        }
+        if let Some((id, offset)) = record.access {
+            write_varint(&mut self.writer, id.into())?;
+            write_varint(&mut self.writer, offset.into())?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the trace and returns the writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads the records of a trace file in order.
+pub struct TraceReader<R: Read = BufReader<File>> {
+    reader: R,
+    generation: u64,
+}
+
+impl TraceReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        Self::new(BufReader::new(file)).with_context(|| format!("loading {}", path.display()))
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header).context("not a trace")?;
+        ensure!(&header[..4] == MAGIC, "not a trace");
+        let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+        ensure!(version == VERSION, "unsupported trace version {version}");
+        Ok(Self {
+            reader,
+            generation: 0,
+        })
+    }
+
+    fn read_record(&mut self) -> Result<Option<TraceRecord>> {
+        let mut tag = [0];
+        loop {
+            match self.reader.read_exact(&mut tag) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => return Err(err.into()),
+            }
+            if tag[0] != GENERATION_TAG {
+                break;
+            }
+            self.generation = read_varint(&mut self.reader)?;
+        }
+        let tag = tag[0];
+        let pc = read_varint(&mut self.reader)? as usize;
+        let mut code = [0; 4];
+        self.reader.read_exact(&mut code)?;
+        let code = u32::from_le_bytes(code);
+        if Instruction::from_u32(code).opcode() as u8 != tag & 0xf {
+            bail!("corrupted trace record at pc {pc}");
+        }
+        let write = if tag & WRITE_FLAG != 0 {
+            let mut reg = [0];
+            self.reader.read_exact(&mut reg)?;
+            Some((reg[0] as usize, read_varint(&mut self.reader)? as u32))
+        } else {
+            None
+        };
+        let access = if tag & ACCESS_FLAG != 0 {
+            let id = read_varint(&mut self.reader)? as u32;
+            let offset = read_varint(&mut self.reader)? as u32;
+            Some((id, offset))
+        } else {
+            None
+        };
+        Ok(Some(TraceRecord {
+            generation: self.generation,
+            pc,
+            code,
+            write,
+            access,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+fn write_varint(writer: &mut impl Write, mut value: u64) -> std::io::Result<()> {
+    let mut buf = [0; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    writer.write_all(&buf[..len])
+}
+
+fn read_varint(reader: &mut impl Read) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("invalid number in trace")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(value: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_varint(&mut data, value).unwrap();
+        data
+    }
+
+    #[test]
+    fn varint_encoding() {
+        assert_eq!(encode(0), [0x00]);
+        assert_eq!(encode(0x7f), [0x7f]);
+        assert_eq!(encode(0x80), [0x80, 0x01]);
+        assert_eq!(encode(300), [0xac, 0x02]);
+        assert_eq!(encode(u32::MAX.into()), [0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert_eq!(encode(u64::MAX).len(), 10);
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for value in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX.into(), u64::MAX] {
+            assert_eq!(read_varint(&mut &encode(value)[..]).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn varint_invalid() {
+        // Truncated.
+        assert!(read_varint(&mut &[0x80][..]).is_err());
+        // Longer than any u64.
+        assert!(read_varint(&mut &[0xff; 11][..]).is_err());
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let records = [
+            TraceRecord {
+                generation: 0,
+                pc: 0,
+                code: 0xd2000041, // imm r1, 0x41
+                write: Some((1, 0x41)),
+                access: None,
+            },
+            TraceRecord {
+                generation: 0,
+                pc: 1,
+                code: 0x1000004a, // load r1, r1, r2
+                write: Some((1, 7)),
+                access: Some((0x41, 300)),
+            },
+            TraceRecord {
+                generation: 2,
+                pc: 1000,
+                code: 0xa0000001, // out r1
+                write: None,
+                access: None,
+            },
+        ];
+        let mut tracer = Tracer::new(Vec::new()).unwrap();
+        for record in records.iter() {
+            tracer.record(record);
+        }
+        let data = tracer.finish().unwrap();
+        let read: Vec<TraceRecord> = TraceReader::new(&data[..])
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(read, records);
+    }
+}
@@ -0,0 +1,337 @@
+use std::{collections::BTreeMap, ops::Range, str::FromStr};
+
+use crate::{
+    error::UmError,
+    instruction::Instruction,
+    interpreter::{execute_step, StepResult},
+    io::UmIo,
+    memory::Memory,
+};
+
+/// Kind of array access a watchpoint triggers on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// Location watched by a watchpoint.
+///
+/// Written as `ID[OFFSET]` or `ID[START..END]` for array platters, optionally
+/// followed by `:r`, `:w` (the default) or `:rw`, or as `rN` for a register.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Watch {
+    /// Platters `offsets` of array `id`, triggering on ArrayIndex
+    /// (reads), ArrayAmendment (writes) and Allocation (writes).
+    Array {
+        id: u32,
+        offsets: Range<u32>,
+        access: Access,
+    },
+    /// A register, triggering on every instruction writing it.
+    Register(usize),
+}
+
+impl Watch {
+    fn matches_array(&self, id: u32, offset: u32, write: bool) -> bool {
+        match self {
+            Watch::Array {
+                id: watch_id,
+                offsets,
+                access,
+            } => {
+                *watch_id == id
+                    && offsets.contains(&offset)
+                    && match access {
+                        Access::Read => !write,
+                        Access::Write => write,
+                        Access::ReadWrite => true,
+                    }
+            }
+            Watch::Register(_) => false,
+        }
+    }
+}
+
+impl FromStr for Watch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || format!("invalid watchpoint {s:?}: expected ID[OFFSET], ID[START..END] or rN");
+        if let Some(reg) = s.strip_prefix('r') {
+            return match reg.parse() {
+                Ok(reg) if reg < 8 => Ok(Watch::Register(reg)),
+                _ => Err(invalid()),
+            };
+        }
+        let (location, access) = match s.rsplit_once(':') {
+            Some((location, "r")) => (location, Access::Read),
+            Some((location, "w")) => (location, Access::Write),
+            Some((location, "rw")) => (location, Access::ReadWrite),
+            Some(_) => return Err(invalid()),
+            None => (s, Access::Write),
+        };
+        let (id, offsets) = location
+            .strip_suffix(']')
+            .and_then(|location| location.split_once('['))
+            .ok_or_else(invalid)?;
+        let id = parse_number(id).ok_or_else(invalid)?;
+        let offsets = match offsets.split_once("..") {
+            Some((start, end)) => {
+                let start = parse_number(start).ok_or_else(invalid)?;
+                let end = parse_number(end).ok_or_else(invalid)?;
+                if start >= end {
+                    return Err(invalid());
+                }
+                start..end
+            }
+            None => {
+                let offset = parse_number(offsets).ok_or_else(invalid)?;
+                offset..offset.checked_add(1).ok_or_else(invalid)?
+            }
+        };
+        Ok(Watch::Array {
+            id,
+            offsets,
+            access,
+        })
+    }
+}
+
+impl std::fmt::Display for Watch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Watch::Array {
+                id,
+                offsets,
+                access,
+            } => {
+                if offsets.len() == 1 {
+                    write!(f, "{id}[{}]", offsets.start)?;
+                } else {
+                    write!(f, "{id}[{}..{}]", offsets.start, offsets.end)?;
+                }
+                match access {
+                    Access::Read => write!(f, ":r"),
+                    Access::Write => write!(f, ":w"),
+                    Access::ReadWrite => write!(f, ":rw"),
+                }
+            }
+            Watch::Register(reg) => write!(f, "r{reg}"),
+        }
+    }
+}
+
+/// Location touched by an instruction that triggered a watchpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Array { id: u32, offset: u32 },
+    Register(usize),
+}
+
+/// Report of a triggered watchpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    /// Number of the watchpoint.
+    pub watch: usize,
+    /// The instruction that triggered the watchpoint.
+    pub pc: usize,
+    pub target: Target,
+    pub write: bool,
+    /// The value before the instruction, or `None` for a newly allocated
+    /// array.
+    pub old: Option<u32>,
+    pub new: u32,
+}
+
+impl WatchHit {
+    /// Describes the access without the watchpoint number.
+    pub fn describe(&self) -> String {
+        let kind = if self.write { "write" } else { "read" };
+        let target = match self.target {
+            Target::Array { id, offset } => format!("{id}[{offset}]"),
+            Target::Register(reg) => format!("r{reg}"),
+        };
+        let values = match self.old {
+            Some(old) if self.write => format!("0x{old:08x} -> 0x{:08x}", self.new),
+            Some(_) => format!("0x{:08x}", self.new),
+            None => format!("allocated -> 0x{:08x}", self.new),
+        };
+        format!("{kind} {target} at {:08}: {values}", self.pc)
+    }
+}
+
+impl std::fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "watchpoint {}: {}", self.watch, self.describe())
+    }
+}
+
+/// Set of numbered watchpoints checked around single instructions.
+#[derive(Debug, Default)]
+pub struct Watchpoints {
+    watches: BTreeMap<usize, Watch>,
+    next: usize,
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a watchpoint and returns its number.
+    pub fn insert(&mut self, watch: Watch) -> usize {
+        self.next += 1;
+        self.watches.insert(self.next, watch);
+        self.next
+    }
+
+    pub fn remove(&mut self, n: usize) -> bool {
+        self.watches.remove(&n).is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Watch)> {
+        self.watches.iter().map(|(&n, watch)| (n, watch))
+    }
+
+    /// Executes an instruction like [`execute_step`], appending the
+    /// watchpoints it triggers to `hits`.
+    pub fn execute_step(
+        &self,
+        pc: usize,
+        inst: Instruction,
+        memory: &mut Memory,
+        io: &mut dyn UmIo,
+        hits: &mut Vec<WatchHit>,
+    ) -> Result<StepResult, UmError> {
+        let regs = memory.regs;
+        // Platter accessed by ArrayIndex or ArrayAmendment, and its value
+        // before the instruction.
+        let cell = match inst.opcode() {
+            1 => Some((regs[inst.b()], regs[inst.c()], false)),
+            2 => Some((regs[inst.a()], regs[inst.b()], true)),
+            _ => None,
+        }
+        .and_then(|(id, offset, write)| {
+            let old = *memory.arrays.get(id as usize)?.get(offset as usize)?;
+            Some((id, offset, write, old))
+        });
+
+        let result = execute_step(pc, inst, memory, io)?;
+
+        let mut hit = |watch, target, write, old, new| {
+            hits.push(WatchHit {
+                watch,
+                pc,
+                target,
+                write,
+                old,
+                new,
+            })
+        };
+        for (&n, watch) in self.watches.iter() {
+            if let Some((id, offset, write, old)) = cell {
+                if watch.matches_array(id, offset, write) {
+                    let new = memory.arrays[id as usize][offset as usize];
+                    hit(n, Target::Array { id, offset }, write, Some(old), new);
+                }
+            }
+            match *watch {
+                Watch::Array {
+                    id, ref offsets, ..
+                } if inst.opcode() == 8
+                    && memory.regs[inst.b()] == id
+                    && watch.matches_array(id, offsets.start, true)
+                    && (offsets.start as usize) < memory.arrays[id as usize].len() =>
+                {
+                    // Report the first watched platter of a new array.
+                    let target = Target::Array {
+                        id,
+                        offset: offsets.start,
+                    };
+                    hit(n, target, true, None, 0);
+                }
+                Watch::Register(reg) if inst.written_register(&regs) == Some(reg) => {
+                    hit(
+                        n,
+                        Target::Register(reg),
+                        true,
+                        Some(regs[reg]),
+                        memory.regs[reg],
+                    );
+                }
+                _ => {}
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Parses a decimal number, or a hexadecimal one prefixed with `0x`.
+pub fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn array(id: u32, offsets: Range<u32>, access: Access) -> Watch {
+        Watch::Array {
+            id,
+            offsets,
+            access,
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("0[5]".parse(), Ok(array(0, 5..6, Access::Write)));
+        assert_eq!("3[0x10]:r".parse(), Ok(array(3, 16..17, Access::Read)));
+        assert_eq!(
+            "0x2a[4..8]:rw".parse(),
+            Ok(array(42, 4..8, Access::ReadWrite))
+        );
+        assert_eq!("1[0..2]:w".parse(), Ok(array(1, 0..2, Access::Write)));
+        assert_eq!("r0".parse(), Ok(Watch::Register(0)));
+        assert_eq!("r7".parse(), Ok(Watch::Register(7)));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for s in [
+            "",
+            "r8",
+            "rx",
+            "0",
+            "0[",
+            "0[]",
+            "[1]",
+            "0[1]:x",
+            "0[1]:",
+            "0[2..2]",
+            "0[3..1]",
+            "0[4294967295]",
+            "0x[1]",
+        ] {
+            assert!(s.parse::<Watch>().is_err(), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for s in ["0[5]:w", "3[16]:r", "42[4..8]:rw", "r7"] {
+            let watch: Watch = s.parse().unwrap();
+            assert_eq!(watch.to_string(), s);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+#!/usr/bin/env python3
+"""Assembles the test programs.
+
+Usage: asm.py SOURCE.uma OUTPUT.um
+
+A source has one instruction per line, written with the mnemonics of
+`umix dump`:
+
+    cmove rA, rB, rC     rA = rB if rC != 0
+    load rA, rB, rC      rA = rB[rC]
+    store rA, rB, rC     rA[rB] = rC
+    add, mul, div, nand  rA = rB op rC
+    halt
+    alloc rB, rC         rB = new array of rC platters
+    free rC
+    out rC
+    in rC
+    jmp rB, rC           load array rB into array 0 and continue at rC
+    imm rA, VALUE
+    word VALUE           a raw platter
+
+`name:` defines a label at the next platter. Values are Python expressions
+over integers, character literals and labels. `#` starts a comment.
+"""
+
+import re
+import struct
+import sys
+
+OPCODES = {
+    'cmove': 0, 'load': 1, 'store': 2, 'add': 3, 'mul': 4, 'div': 5,
+    'nand': 6, 'halt': 7, 'alloc': 8, 'free': 9, 'out': 10, 'in': 11,
+    'jmp': 12, 'imm': 13,
+}
+# Register fields used by each mnemonic besides the standard a, b, c.
+FIELDS = {'halt': '', 'alloc': 'bc', 'free': 'c', 'out': 'c', 'in': 'c', 'jmp': 'bc'}
+
+
+def parse(path):
+    labels = {}
+    lines = []
+    with open(path) as f:
+        for lineno, line in enumerate(f, 1):
+            line = line.split('#', 1)[0].strip()
+            while (m := re.match(r'(\w+):\s*', line)):
+                labels[m.group(1)] = len(lines)
+                line = line[m.end():]
+            if line:
+                lines.append((lineno, line))
+    return labels, lines
+
+
+def value(expr, labels):
+    v = eval(expr, {}, dict(labels))
+    if isinstance(v, str):
+        v = ord(v)
+    return v
+
+
+def register(operand):
+    m = re.fullmatch(r'r([0-7])', operand)
+    if not m:
+        raise ValueError(f'bad register {operand!r}')
+    return int(m.group(1))
+
+
+def encode(line, labels):
+    mnemonic, _, rest = line.partition(' ')
+    operands = [o.strip() for o in rest.split(',')] if rest.strip() else []
+    if mnemonic == 'word':
+        (v,) = operands
+        return value(v, labels) & 0xffffffff
+    opcode = OPCODES[mnemonic]
+    if mnemonic == 'imm':
+        a, v = operands
+        v = value(v, labels)
+        if not 0 <= v < 1 << 25:
+            raise ValueError(f'immediate {v} out of range')
+        return opcode << 28 | register(a) << 25 | v
+    fields = FIELDS.get(mnemonic, 'abc')
+    if len(operands) != len(fields):
+        raise ValueError(f'{mnemonic} takes {len(fields)} registers')
+    code = opcode << 28
+    for field, operand in zip(fields, operands):
+        code |= register(operand) << {'a': 6, 'b': 3, 'c': 0}[field]
+    return code
+
+
+def main():
+    source, output = sys.argv[1:]
+    labels, lines = parse(source)
+    words = []
+    for lineno, line in lines:
+        try:
+            words.append(encode(line, labels))
+        except Exception as e:
+            sys.exit(f'{source}:{lineno}: {e}')
+    with open(output, 'wb') as f:
+        f.write(b''.join(struct.pack('>I', w) for w in words))
+
+
+if __name__ == '__main__':
+    main()
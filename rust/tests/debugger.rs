@@ -0,0 +1,117 @@
+//! Runs scripted sessions of `umix debug` over the programs in testdata.
+
+use std::{
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+const UMIX: &str = env!("CARGO_BIN_EXE_umix");
+
+/// Runs the debugger on a program in testdata with `script` as its input, and
+/// returns what it prints.
+fn debug(program: &str, script: &str) -> String {
+    let program = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata")
+        .join(program);
+    let mut child = Command::new(UMIX)
+        .arg("debug")
+        .arg(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(script.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "umix debug failed");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn breakpoints() {
+    // The program prints "ok\n" a byte per iteration of the loop at pc 13,
+    // between the lines of the debugger. An empty line repeats `continue`.
+    let output = debug(
+        "debug.um",
+        "break 13\nbreak 0x18\ncontinue\ncontinue\n\ndelete 1\ninfo\ncontinue\nregs\n",
+    );
+    assert_eq!(
+        output,
+        "\
+=> 0:00000000: imm r1, 3
+(umdb) breakpoint 1 at 00000013
+(umdb) breakpoint 2 at 00000024
+(umdb) breakpoint 1
+=> 0:00000013: load r3, r2, r4
+(umdb) obreakpoint 1
+=> 0:00000013: load r3, r2, r4
+(umdb) kbreakpoint 1
+=> 0:00000013: load r3, r2, r4
+(umdb) (umdb) breakpoint 2 at 00000024
+(umdb) 
+breakpoint 2
+=> 0:00000024: halt
+(umdb) r0  0x00000000  0
+r1  0x0000000d  13
+r2  0x00000001  1
+r3  0x0000000a  10
+r4  0x00000003  3
+r5  0x00000001  1
+r6  0x00000000  0
+r7  0x00000018  24
+pc  00000024  generation 0
+executed 46 instructions, 46 in the history
+(umdb) 
+"
+    );
+}
+
+#[test]
+fn breakpoints_in_generations() {
+    // reload.um loads a copy of itself 7 times, and runs a loop at pc 9 in
+    // each generation.
+    let output = debug(
+        "reload.um",
+        "break 1:9\ncatch load\ncontinue\ncontinue\ninfo\ncatch load\nbreak 3:9\n\
+         delete 1\ncontinue\nregs\nbreak x:9\nbreak 1:\ncatch\ndelete 1\ndelete 2\ncontinue\nquit\n",
+    );
+    let (session, end) = output.split_once("(umdb) ok\n").unwrap();
+    assert_eq!(
+        session,
+        "\
+=> 0:00000000: nand r2 r0, r0
+(umdb) breakpoint 1 at 1:00000009
+(umdb) catching LoadProgram on
+(umdb) loaded program from array 1 (generation 1)
+=> 1:00000000: nand r2 r0, r0
+(umdb) breakpoint 1
+=> 1:00000009: add r4, r4, r2
+(umdb) breakpoint 1 at 1:00000009
+catching LoadProgram
+(umdb) catching LoadProgram off
+(umdb) breakpoint 2 at 3:00000009
+(umdb) (umdb) breakpoint 2
+=> 3:00000009: add r4, r4, r2
+(umdb) r0  0x00000000  0
+r1  0x00000001  1
+r2  0xffffffff  4294967295
+r3  0x00000bb8  3000
+r4  0x00000bb8  3000
+r5  0x00000003  3
+r6  0x00000033  51
+r7  0x00000004  4
+pc  00000009  generation 3
+executed 55437 instructions, 55437 in the history
+(umdb) invalid breakpoint: x:9
+(umdb) invalid breakpoint: 1:
+(umdb) usage: catch load
+(umdb) no such breakpoint
+(umdb) "
+    );
+    assert!(end.starts_with("program halted after "), "{end}");
+}
@@ -0,0 +1,90 @@
+//! Runs the programs in testdata in every execution mode, and checks that
+//! they print the same output and execute the same number of instructions.
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+const UMIX: &str = env!("CARGO_BIN_EXE_umix");
+
+/// Output and number of executed instructions of a run.
+#[derive(Debug, PartialEq, Eq)]
+struct RunResult {
+    stdout: String,
+    insts: u64,
+}
+
+fn run(command: &mut Command) -> RunResult {
+    let output = command
+        .arg("--stats")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{command:?} failed: {stderr}");
+    let insts = stderr
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("executed instructions:"))
+        .unwrap_or_else(|| panic!("no statistics from {command:?}: {stderr}"))
+        .trim()
+        .parse()
+        .unwrap();
+    RunResult {
+        stdout: String::from_utf8(output.stdout).unwrap(),
+        insts,
+    }
+}
+
+fn programs() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+    let mut programs: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "um"))
+        .collect();
+    programs.sort();
+    assert!(!programs.is_empty());
+    programs
+}
+
+#[test]
+fn modes_agree() {
+    let dir = std::env::temp_dir().join(format!("umix-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    for program in programs() {
+        let name = program.file_stem().unwrap().to_str().unwrap();
+        let expected = run(Command::new(UMIX)
+            .args(["run", "--mode", "interpreter"])
+            .arg(&program));
+        assert_eq!(expected.stdout, "ok\n", "{name} in the interpreter");
+
+        let backends: &[&str] = if cfg!(feature = "llvm") {
+            &["cranelift", "llvm"]
+        } else {
+            &["cranelift"]
+        };
+        for backend in backends {
+            let result = run(Command::new(UMIX)
+                .args(["run", "--mode", "jit", "--backend", backend])
+                .arg(&program));
+            assert_eq!(result, expected, "{name} in the {backend} JIT");
+        }
+
+        let exe = dir.join(name);
+        let status = Command::new(UMIX)
+            .arg("compile")
+            .arg("-o")
+            .arg(&exe)
+            .arg(&program)
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "compiling {name}");
+        let result = run(&mut Command::new(&exe));
+        assert_eq!(result, expected, "{name} compiled ahead of time");
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}